@@ -205,24 +205,39 @@ fn read_program_header(
     let program_header_offset = elf_header.program_header_offset as usize;
     let program_header_entry_size = elf_header.program_header_entry_size as usize;
     let program_header_entry_count = elf_header.program_header_entry_count as usize;
+    if program_header_entry_count > 0 && program_header_entry_size < size_of::<ProgramHeaderEntry>()
+    {
+        return Err(ElfValidationError::Header {
+            field: "program_header_entry_size",
+            expected: format!(">= {}", size_of::<ProgramHeaderEntry>()),
+            actual: format!("{}", program_header_entry_size),
+        });
+    }
+    let program_header_end = program_header_entry_size
+        .checked_mul(program_header_entry_count)
+        .and_then(|size| size.checked_add(program_header_offset));
+    if program_header_end.map_or(true, |end| end > bytes.len()) {
+        return Err(ElfValidationError::Header {
+            field: "program_header_offset",
+            expected: format!(
+                "<= {} for {} entries",
+                bytes.len(),
+                program_header_entry_count
+            ),
+            actual: format!("{}", program_header_offset),
+        });
+    }
     for i in 0..program_header_entry_count {
         let entry_offset = program_header_offset + i * program_header_entry_size;
-        if entry_offset + program_header_entry_size > bytes.len() {
-            return Err(ElfValidationError::ProgramHeaderEntry {
-                field: "size",
-                expected: format!("<= {}", bytes.len() - entry_offset),
-                actual: format!("{}", program_header_entry_size),
-                index: i,
-            });
-        }
         let entry = unsafe { &*(bytes[entry_offset..].as_ptr() as *const ProgramHeaderEntry) };
         if entry.program_type != 1 {
             continue;
         }
-        if entry.offset as usize + entry.file_size as usize > bytes.len() {
+        let end = (entry.offset as usize).checked_add(entry.file_size as usize);
+        if end.map_or(true, |end| end > bytes.len()) {
             return Err(ElfValidationError::ProgramHeaderEntry {
                 field: "file_size",
-                expected: format!("<= {}", bytes.len() - entry.offset as usize),
+                expected: format!("<= {}", bytes.len().saturating_sub(entry.offset as usize)),
                 actual: format!("{}", entry.file_size),
                 index: i,
             });
@@ -370,6 +385,11 @@ pub struct ElfBinary {
     pub sections: Vec<Section>,
 
     pub entrypoint: usize,
+
+    // Programs can ask for their program headers (through the auxiliary vector), so we have to know where they are.
+    pub program_header_offset: usize,
+    pub program_header_entry_size: usize,
+    pub program_header_entry_count: usize,
 }
 
 pub fn load_elf(bytes: &[u8]) -> Result<ElfBinary, ElfValidationError> {
@@ -388,5 +408,8 @@ pub fn load_elf(bytes: &[u8]) -> Result<ElfBinary, ElfValidationError> {
         loadable_segments,
         sections,
         entrypoint: header.entrypoint as usize,
+        program_header_offset: header.program_header_offset as usize,
+        program_header_entry_size: header.program_header_entry_size as usize,
+        program_header_entry_count: header.program_header_entry_count as usize,
     })
 }
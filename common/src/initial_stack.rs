@@ -0,0 +1,46 @@
+//! The layout of the stack which the kernel gives a program when it starts.
+//!
+//! This follows the System V ABI, so the stack pointer points to (in increasing addresses):
+//! - `argc`
+//! - `argc` pointers to the arguments, followed by a null pointer
+//! - pointers to the environment variables (`NAME=value`), followed by a null pointer
+//! - the auxiliary vector: pairs of (type, value) from [`AuxiliaryVectorType`], ending with [`AuxiliaryVectorType::Null`]
+//!
+//! Every entry is 64 bits. The strings (which are null-terminated UTF-8) and anything else which the auxiliary vector points to are stored above this.
+//!
+//! The stack pointer is 16-byte aligned, and there is an unmapped guard region below the stack, so overflowing it kills the program rather than silently overwriting memory.
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuxiliaryVectorType {
+    /// Marks the end of the auxiliary vector.
+    Null = 0,
+    /// The address of the program headers.
+    ProgramHeaders = 3,
+    /// The size of each program header.
+    ProgramHeaderSize = 4,
+    /// The number of program headers.
+    ProgramHeaderCount = 5,
+    PageSize = 6,
+    /// The program's entrypoint.
+    Entry = 9,
+    /// The address of 16 random bytes.
+    Random = 25,
+}
+
+impl TryFrom<u64> for AuxiliaryVectorType {
+    type Error = &'static str;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(AuxiliaryVectorType::Null),
+            3 => Ok(AuxiliaryVectorType::ProgramHeaders),
+            4 => Ok(AuxiliaryVectorType::ProgramHeaderSize),
+            5 => Ok(AuxiliaryVectorType::ProgramHeaderCount),
+            6 => Ok(AuxiliaryVectorType::PageSize),
+            9 => Ok(AuxiliaryVectorType::Entry),
+            25 => Ok(AuxiliaryVectorType::Random),
+            _ => Err("Invalid auxiliary vector type"),
+        }
+    }
+}
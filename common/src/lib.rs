@@ -1,8 +1,12 @@
 #![no_std]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
 pub mod beryllium;
+#[cfg(feature = "alloc")]
 pub mod elf;
 pub mod font;
 pub mod framebuffer;
+pub mod initial_stack;
+pub mod syscall;
@@ -0,0 +1,226 @@
+//! The system call interface between the kernel and user programs.
+//!
+//! # ABI
+//! Every system call has a number (from [`SyscallNumber`]) and up to six arguments, all of which are 64 bits.
+//! It returns a 64-bit value and an error code, which is 0 on success and one of [`SyscallError`] otherwise.
+//!
+//! On x86_64, `syscall` (or the slower `int 0x80`) is used with the number in `rax` and the arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`.
+//! The value is returned in `rax` and the error code in `rdx`.
+//! `syscall` also overwrites `rcx` and `r11`.
+//!
+//! On aarch64, `svc 0` is used with the number in `x8` and the arguments in `x0` to `x5`.
+//! The value is returned in `x0` and the error code in `x1`.
+//!
+//! All other registers are preserved.
+//!
+//! # Time
+//! Deadlines are times on the monotonic clock (see `CLOCK_MONOTONIC`) and timeouts are lengths of time, both in nanoseconds.
+//! Either can be `NO_TIMEOUT` to wait forever.
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallNumber {
+    /// Print a UTF-8 string (pointer, length) to the kernel console.
+    DebugPrint = 0,
+    /// Start the program at the given path (pointer, length) in the initial ramdisk, returning a handle to the new process.
+    ///
+    /// The third and fourth arguments are a pointer to an array of handles, and how many there are (at most `MAX_MESSAGE_HANDLES`).
+    /// These are moved to the new process, where they are numbered from 1 in the same order.
+    Spawn = 1,
+    /// Stop the current process with the given exit status. This never returns.
+    Exit = 2,
+    /// Wait for the process with the given handle to exit, returning its exit status: (handle, deadline).
+    ///
+    /// Fails with `TimedOut` if the deadline passes first.
+    Wait = 3,
+    /// Create a pair of connected channel endpoints, writing their handles to the given pointer (to two `u64`s).
+    CreateChannel = 4,
+    /// Send a message on a channel: (handle, data pointer, data length, handles pointer, handle count).
+    ///
+    /// The handles (which need `RIGHT_TRANSFER`) are moved out of the current process, keeping their rights.
+    /// This fails with `InvalidHandle` if one of them is the channel itself.
+    /// Fails with `WouldBlock` if the other end already has `MAX_QUEUED_MESSAGES` waiting.
+    Send = 5,
+    /// Receive a message from a channel: (handle, data pointer, handles pointer, capacities, flags, deadline).
+    ///
+    /// The capacities have the size of the data buffer in the low 32 bits and the number of handles which fit in the high 32 bits.
+    /// Returns the length of the data in the low 32 bits and the number of handles in the high 32 bits.
+    /// If the message doesn't fit, this fails with `BufferTooSmall` and the message stays in the channel.
+    /// Blocks until there is a message, unless `RECEIVE_NON_BLOCKING` is in the flags, and fails with `TimedOut` if the deadline passes first.
+    Receive = 6,
+    /// Make another handle to the same object: (handle, rights). The rights must be a subset of the original handle's.
+    Duplicate = 7,
+    /// Remove a handle from the current process. The object is destroyed once nothing refers to it.
+    Close = 8,
+    /// Create a memory object of the given size (rounded up to `MEMORY_OBJECT_ALIGNMENT`), returning a handle to it.
+    CreateMemoryObject = 9,
+    /// Map a memory object into the current process: (handle, address, flags from `MAP_*`).
+    ///
+    /// The whole object is mapped, starting at the address (which must be a multiple of `MEMORY_OBJECT_ALIGNMENT`).
+    /// The handle needs `RIGHT_READ`, and `RIGHT_WRITE` as well if the mapping is writable.
+    MapMemoryObject = 10,
+    /// Unmap the memory object which was mapped at the given address.
+    UnmapMemoryObject = 11,
+    /// Block until `FutexWake` is called on an address: (address of a `u32`, expected value, timeout in nanoseconds).
+    ///
+    /// Fails with `WouldBlock` straight away if the value at the address isn't the expected value, or `TimedOut` if the timeout (which can be `NO_TIMEOUT`) passes first.
+    /// This can also return early for no reason, so the caller should check the value again.
+    /// The address is the same for every process which has the memory mapped, even at different virtual addresses.
+    FutexWait = 12,
+    /// Wake threads which are blocked in `FutexWait` on an address: (address, maximum number of threads), returning how many were woken.
+    FutexWake = 13,
+    /// Read one of the clocks (`CLOCK_MONOTONIC` or `CLOCK_REALTIME`), returning the time in nanoseconds.
+    ///
+    /// Fails with `Unavailable` for `CLOCK_REALTIME` if there isn't a real time clock.
+    ClockGetTime = 14,
+    /// Block until the monotonic clock reaches the deadline.
+    SleepUntil = 15,
+    /// Block until the timeout has passed.
+    SleepFor = 16,
+}
+
+pub const SYSCALL_COUNT: usize = 17;
+
+/// Pass this as a timeout or deadline to wait forever.
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+/// The time since the system started, which never goes backwards.
+pub const CLOCK_MONOTONIC: u64 = 0;
+/// The real (UTC) time since the Unix epoch (1970-01-01 00:00:00 UTC).
+///
+/// Unlike the monotonic clock, this isn't guaranteed to never go backwards.
+pub const CLOCK_REALTIME: u64 = 1;
+
+/// Receiving messages from a channel, waiting for a process and mapping a memory object.
+pub const RIGHT_READ: u64 = 1 << 0;
+/// Sending messages on a channel and mapping a memory object as writable.
+pub const RIGHT_WRITE: u64 = 1 << 1;
+/// Making more handles to the object with `Duplicate`.
+pub const RIGHT_DUPLICATE: u64 = 1 << 2;
+/// Giving the handle to another process (in a message, or when spawning it).
+pub const RIGHT_TRANSFER: u64 = 1 << 3;
+/// New objects start with a handle which has all of these.
+pub const ALL_RIGHTS: u64 = RIGHT_READ | RIGHT_WRITE | RIGHT_DUPLICATE | RIGHT_TRANSFER;
+
+/// The largest amount of data which can be in a single message.
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+/// The most handles which can be sent with a single message.
+pub const MAX_MESSAGE_HANDLES: usize = 16;
+/// How many messages can be waiting to be received from one end of a channel.
+pub const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// Memory objects are made of blocks of this size, and have to be mapped at addresses which are a multiple of it.
+pub const MEMORY_OBJECT_ALIGNMENT: usize = 65536;
+/// The largest memory object which can be created.
+pub const MAX_MEMORY_OBJECT_SIZE: usize = 256 * 1024 * 1024;
+
+/// Flag for `MapMemoryObject` to allow writing to the memory.
+pub const MAP_WRITABLE: u64 = 1 << 0;
+/// Flag for `MapMemoryObject` to allow running code from the memory.
+pub const MAP_EXECUTABLE: u64 = 1 << 1;
+
+/// Flag for `Receive` to fail with `WouldBlock` instead of waiting for a message.
+pub const RECEIVE_NON_BLOCKING: u64 = 1 << 0;
+
+/// The exit status of a process which was killed by the kernel (for example because it accessed invalid memory).
+pub const KILLED_EXIT_STATUS: u64 = u64::MAX;
+
+impl TryFrom<u64> for SyscallNumber {
+    type Error = SyscallError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SyscallNumber::DebugPrint),
+            1 => Ok(SyscallNumber::Spawn),
+            2 => Ok(SyscallNumber::Exit),
+            3 => Ok(SyscallNumber::Wait),
+            4 => Ok(SyscallNumber::CreateChannel),
+            5 => Ok(SyscallNumber::Send),
+            6 => Ok(SyscallNumber::Receive),
+            7 => Ok(SyscallNumber::Duplicate),
+            8 => Ok(SyscallNumber::Close),
+            9 => Ok(SyscallNumber::CreateMemoryObject),
+            10 => Ok(SyscallNumber::MapMemoryObject),
+            11 => Ok(SyscallNumber::UnmapMemoryObject),
+            12 => Ok(SyscallNumber::FutexWait),
+            13 => Ok(SyscallNumber::FutexWake),
+            14 => Ok(SyscallNumber::ClockGetTime),
+            15 => Ok(SyscallNumber::SleepUntil),
+            16 => Ok(SyscallNumber::SleepFor),
+            _ => Err(SyscallError::InvalidSyscall),
+        }
+    }
+}
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// There is no system call with that number.
+    InvalidSyscall = 1,
+    /// One of the arguments doesn't make sense for this system call.
+    InvalidArgument = 2,
+    /// A pointer argument refers to memory which the program isn't allowed to access.
+    InvalidAddress = 3,
+    /// The file doesn't exist.
+    NotFound = 4,
+    /// The file isn't a valid ELF executable.
+    InvalidExecutable = 5,
+    /// The handle doesn't exist, or refers to the wrong kind of object.
+    InvalidHandle = 6,
+    /// The operation would have to wait, but the caller asked it not to.
+    WouldBlock = 7,
+    /// The other end of the channel has been closed.
+    PeerClosed = 8,
+    /// The buffer isn't big enough for the result.
+    BufferTooSmall = 9,
+    /// The handle doesn't have the rights which are needed.
+    AccessDenied = 10,
+    /// There isn't enough free memory.
+    OutOfMemory = 11,
+    /// The timeout passed before the operation could finish.
+    TimedOut = 12,
+    /// The hardware which is needed isn't there.
+    Unavailable = 13,
+}
+
+impl TryFrom<u64> for SyscallError {
+    type Error = &'static str;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(SyscallError::InvalidSyscall),
+            2 => Ok(SyscallError::InvalidArgument),
+            3 => Ok(SyscallError::InvalidAddress),
+            4 => Ok(SyscallError::NotFound),
+            5 => Ok(SyscallError::InvalidExecutable),
+            6 => Ok(SyscallError::InvalidHandle),
+            7 => Ok(SyscallError::WouldBlock),
+            8 => Ok(SyscallError::PeerClosed),
+            9 => Ok(SyscallError::BufferTooSmall),
+            10 => Ok(SyscallError::AccessDenied),
+            11 => Ok(SyscallError::OutOfMemory),
+            12 => Ok(SyscallError::TimedOut),
+            13 => Ok(SyscallError::Unavailable),
+            _ => Err("Invalid system call error code"),
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// Combine the value and error code from a system call into a result.
+pub fn decode_result(value: u64, error_code: u64) -> SyscallResult {
+    if error_code == 0 {
+        Ok(value)
+    } else {
+        Err(SyscallError::try_from(error_code).unwrap_or(SyscallError::InvalidSyscall))
+    }
+}
+
+/// Split a result into the value and error code which are returned to the program.
+pub fn encode_result(result: SyscallResult) -> (u64, u64) {
+    match result {
+        Ok(value) => (value, 0),
+        Err(error) => (0, error as u64),
+    }
+}
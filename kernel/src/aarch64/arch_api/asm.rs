@@ -5,3 +5,44 @@ pub fn memory_barrier() {
         asm!("dmb sy");
     }
 }
+
+/// # Safety
+/// Code which relies on interrupts staying enabled (such as anything waiting for the timer) will stop working until they are enabled again.
+pub unsafe fn disable_interrupts() {
+    asm!("msr daifset, #15", options(nomem, nostack));
+}
+
+/// Disable interrupts, returning whether they were enabled before.
+pub fn save_and_disable_interrupts() -> bool {
+    let daif: u64;
+    // This isn't `nomem`, so that memory accesses can't be moved to before interrupts are disabled.
+    unsafe { asm!("mrs {}, daif", "msr daifset, #15", out(reg) daif, options(nostack)) };
+    // The I bit masks IRQs.
+    daif & (1 << 7) == 0
+}
+
+/// Enable interrupts again if they were enabled before `save_and_disable_interrupts`.
+///
+/// # Safety
+/// The same as enabling interrupts, if they were enabled.
+pub unsafe fn restore_interrupts(enabled: bool) {
+    if enabled {
+        asm!("msr daifclr, #15", options(nostack));
+    }
+}
+
+/// Pause the CPU until the next interrupt arrives.
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("wfi", options(nomem, nostack));
+    }
+}
+
+/// Read a counter which increases quickly and steadily (but not at any particular rate).
+pub fn read_cycle_counter() -> u64 {
+    let counter: u64;
+    unsafe {
+        asm!("mrs {}, cntvct_el0", out(reg) counter, options(nomem, nostack));
+    }
+    counter
+}
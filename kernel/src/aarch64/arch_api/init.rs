@@ -4,6 +4,7 @@ use crate::arch::exceptions::load_exceptions;
 use crate::arch_api::stack::Stack;
 use crate::heap::{map_physical_memory, PhysicalAddressHandle};
 use crate::paging::{MemoryType, PagePermissions};
+use crate::per_cpu;
 use crate::physical_memory_manager;
 use common::beryllium::{
     BootRequestTagType, FrameBufferTag, MemoryMapEntry, MemoryMapEntryType, MemoryMapTag,
@@ -55,6 +56,7 @@ pub static mut MEMORY_MAP_TAG: MemoryMapTag = MemoryMapTag {
 };
 
 pub fn arch_init() {
+    per_cpu::initialize_boot_cpu();
     load_exceptions();
 
     let memory_map = unsafe {
@@ -1,6 +1,9 @@
 use alloc::boxed::Box;
 
-use crate::arch::{asm::enable_interrupts, gicv2::Gicv2};
+use crate::{
+    arch::{asm::enable_interrupts, gicv2::Gicv2, gicv3::Gicv3},
+    irq::{InterruptSource, Irq},
+};
 
 use super::acpi::AcpiInfo;
 
@@ -91,6 +94,26 @@ pub fn initialize(acpi_info: &AcpiInfo) {
                 cpu_interface_address as usize,
             )));
         }
+    } else if gic_distributor.gic_version == 3 || gic_distributor.gic_version == 4 {
+        // GICv4 only adds virtualization features, so it works the same way as GICv3 otherwise.
+        assert!(
+            !acpi_info
+                .madt
+                .generic_interrupt_controller_redistributor_entries
+                .is_empty(),
+            "No GICR entries found in MADT"
+        );
+
+        // SAFETY: The provided addresses are from ACPI, so they are correct.
+        // Also, there are no other GIC drivers running at this point, so there will be no conflicts.
+        unsafe {
+            GIC = Some(Box::new(Gicv3::new(
+                gic_distributor.base_address as usize,
+                &acpi_info
+                    .madt
+                    .generic_interrupt_controller_redistributor_entries,
+            )));
+        }
     } else {
         panic!("GICv{} not supported yet", gic_distributor.gic_version);
     }
@@ -141,3 +164,25 @@ pub(in crate::arch) fn enable_interrupts_for_this_cpu() {
     // SAFETY: The GIC is designed to work across threads.
     unsafe { GIC.as_mut().unwrap().enable_interrupts_for_this_cpu() }
 }
+
+/// The interrupt ID is the global system interrupt, so there is nothing to allocate, just the trigger mode to set.
+pub fn allocate(source: InterruptSource) -> Irq {
+    let interrupt_number = source.global_system_interrupt;
+    assert!(
+        interrupt_is_usable(interrupt_number),
+        "GIC interrupt {} can't be used",
+        interrupt_number
+    );
+    // The GIC only takes active high interrupts, so anything else has to be inverted before it gets there.
+    assert!(!source.active_low, "GIC interrupts can't be active low");
+    configure_interrupt(interrupt_number, !source.level_triggered, Priority::Normal);
+    Irq::new(interrupt_number, interrupt_number)
+}
+
+pub fn enable(irq: Irq) {
+    enable_interrupt(irq.number());
+}
+
+pub fn disable(irq: Irq) {
+    disable_interrupt(irq.number());
+}
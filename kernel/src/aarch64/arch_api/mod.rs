@@ -4,6 +4,10 @@ pub mod init;
 pub mod initial_ramdisk;
 pub mod irq;
 pub mod paging;
+pub mod per_cpu;
+pub mod rtc;
+pub mod smp;
 pub mod stack;
+pub mod syscall;
+pub mod thread;
 pub mod timer;
-pub mod user_mode;
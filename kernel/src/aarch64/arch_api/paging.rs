@@ -9,6 +9,7 @@ use crate::{
     lazy_init::lazy_static,
     paging::{MemoryType, PagePermissions},
     physical_memory_manager,
+    spinlock::{InterruptSafeSpinLock, LockLevel},
 };
 
 /// It was a lot simpler to use 4k pages, although we may consider using 16k or 64k pages in the future.
@@ -131,37 +132,36 @@ fn deconstruct_virtual_address(address: usize) -> PageTableIndices {
 }
 
 lazy_static! {
-    static ref PAGE_TABLE_ALLOCATION_POOL: &'static mut BuddyAllocator<128, { physical_memory_manager::LOG2_BLOCK_SIZE }, 12> = {
+    static ref PAGE_TABLE_ALLOCATION_POOL: InterruptSafeSpinLock<
+        &'static mut BuddyAllocator<128, { physical_memory_manager::LOG2_BLOCK_SIZE }, 12>,
+    > = {
         static mut ACTUAL_ALLOCATOR: BuddyAllocator<128, 16, 12> = BuddyAllocator::unusable();
-        unsafe { ACTUAL_ALLOCATOR.all_unused() }
+        InterruptSafeSpinLock::new(LockLevel::PageTables, unsafe {
+            ACTUAL_ALLOCATOR.all_unused()
+        })
     };
 }
 
 fn allocate_page_table() -> usize {
-    unsafe {
-        if let Some(allocated_page) = PAGE_TABLE_ALLOCATION_POOL.allocate(4096) {
-            allocated_page
-        } else {
-            PAGE_TABLE_ALLOCATION_POOL.add_entry(
-                physical_memory_manager::BLOCK_SIZE,
-                physical_memory_manager::allocate_block_address()
-                    .expect("Failed to get physical memory for page tables"),
-            );
-            PAGE_TABLE_ALLOCATION_POOL
-                .allocate(4096)
-                .expect("Adding new entry to page table allocation pool didn't change anything")
-        }
+    let mut pool = PAGE_TABLE_ALLOCATION_POOL.lock();
+    if let Some(allocated_page) = pool.allocate(4096) {
+        allocated_page
+    } else {
+        pool.add_entry(
+            physical_memory_manager::BLOCK_SIZE,
+            physical_memory_manager::allocate_block_address()
+                .expect("Failed to get physical memory for page tables"),
+        );
+        pool.allocate(4096)
+            .expect("Adding new entry to page table allocation pool didn't change anything")
     }
 }
 fn free_page_table(address: usize) {
-    unsafe {
-        PAGE_TABLE_ALLOCATION_POOL.free(4096, address);
-        // If this merged into a 64 kb block, return it to the physical memory manager (PMM) so it can be used by someone else.
-        if let Some(free_block) =
-            PAGE_TABLE_ALLOCATION_POOL.allocate(physical_memory_manager::BLOCK_SIZE)
-        {
-            physical_memory_manager::mark_as_free(free_block);
-        }
+    let mut pool = PAGE_TABLE_ALLOCATION_POOL.lock();
+    pool.free(4096, address);
+    // If this merged into a 64 kb block, return it to the physical memory manager (PMM) so it can be used by someone else.
+    if let Some(free_block) = pool.allocate(physical_memory_manager::BLOCK_SIZE) {
+        physical_memory_manager::mark_as_free(free_block);
     }
 }
 
@@ -370,8 +370,14 @@ pub fn get_physical_address(virtual_address: usize) -> usize {
 }
 
 pub(in crate::arch) fn initialize_lower_half_table() {
-    // We need to set the TTBR0_EL1 register to a newly allocated page table.
-    // We also need to put the recursive mapping in it, so we need access first.
+    unsafe {
+        activate_user_page_table(create_user_page_table());
+    }
+}
+
+/// Create a new (empty) lower half page table, returning its physical address.
+pub fn create_user_page_table() -> usize {
+    // We need to put the recursive mapping in it, so we need access first.
     let page_table_address = allocate_page_table();
     unsafe {
         let recursive_mapping_entry_flags = PageTableFlags::VALID
@@ -386,14 +392,84 @@ pub(in crate::arch) fn initialize_lower_half_table() {
             MemoryType::Normal,
             PagePermissions::KERNEL_READ_WRITE,
         );
+        page_table_handle.fill(0);
         let final_entry: &mut [u8; 8] = (&mut page_table_handle[PAGE_SIZE - 8..])
             .try_into()
             .unwrap();
         *final_entry = recursive_mapping_entry.to_ne_bytes();
     }
-    unsafe {
-        asm::write_ttbr0(page_table_address as u64);
+    page_table_address
+}
+
+/// # Safety
+/// The page table must have come from `create_user_page_table`, and must not have been destroyed.
+pub unsafe fn activate_user_page_table(page_table_address: usize) {
+    asm::write_ttbr0(page_table_address as u64);
+    asm::isb();
+    // We don't use ASIDs, so anything cached from the old table has to go.
+    asm!("tlbi vmalle1", options(nomem, nostack));
+    asm::dsb_ish();
+    asm::isb();
+}
+
+pub fn get_active_user_page_table() -> usize {
+    asm::read_ttbr0() as usize & PHYSICAL_PAGE_MASK as usize
+}
+
+/// Free a page table from `create_user_page_table`, along with all of the lower level tables under it.
+///
+/// This does not free the pages which were mapped with it.
+///
+/// # Safety
+/// The page table must not be the active one, and must never be used again.
+pub unsafe fn destroy_user_page_table(page_table_address: usize) {
+    let previous_page_table = get_active_user_page_table();
+    assert_ne!(
+        previous_page_table, page_table_address,
+        "Destroying the active page table"
+    );
+    // We need the page table to be active to reach the lower levels through the recursive mapping.
+    activate_user_page_table(page_table_address);
+    for level_0_index in 0..LOWER_RECURSIVE_MAPPING_INDEX {
+        let (level_0_flags, level_1_table) = read_page_table_entry(
+            false,
+            LOWER_RECURSIVE_MAPPING_INDEX,
+            LOWER_RECURSIVE_MAPPING_INDEX,
+            LOWER_RECURSIVE_MAPPING_INDEX,
+            level_0_index,
+        );
+        if !level_0_flags.contains(PageTableFlags::VALID) {
+            continue;
+        }
+        for level_1_index in 0..512 {
+            let (level_1_flags, level_2_table) = read_page_table_entry(
+                false,
+                LOWER_RECURSIVE_MAPPING_INDEX,
+                LOWER_RECURSIVE_MAPPING_INDEX,
+                level_0_index,
+                level_1_index,
+            );
+            if !level_1_flags.contains(PageTableFlags::VALID) {
+                continue;
+            }
+            for level_2_index in 0..512 {
+                let (level_2_flags, level_3_table) = read_page_table_entry(
+                    false,
+                    LOWER_RECURSIVE_MAPPING_INDEX,
+                    level_0_index,
+                    level_1_index,
+                    level_2_index,
+                );
+                if level_2_flags.contains(PageTableFlags::VALID) {
+                    free_page_table(level_3_table as usize);
+                }
+            }
+            free_page_table(level_2_table as usize);
+        }
+        free_page_table(level_1_table as usize);
     }
+    activate_user_page_table(previous_page_table);
+    free_page_table(page_table_address);
 }
 
 pub fn is_valid_user_address(address: usize) -> bool {
@@ -0,0 +1,26 @@
+use crate::arch::registers::{get_tpidr_el1, set_tpidr_el1};
+
+/// The parts of each CPU's data which only aarch64 needs (which is nothing, so far).
+pub struct ArchPerCpu {}
+
+impl ArchPerCpu {
+    pub const fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for ArchPerCpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Safety
+/// The address has to point to this CPU's data.
+pub unsafe fn set_per_cpu_address(address: usize) {
+    set_tpidr_el1(address as u64);
+}
+
+pub fn get_per_cpu_address() -> usize {
+    get_tpidr_el1() as usize
+}
@@ -0,0 +1,47 @@
+//! The PL031 real time clock.
+
+use core::time::Duration;
+
+use crate::{mmio::MmioMemoryHandle, paging::PagePermissions};
+
+use super::acpi::AcpiInfo;
+
+/// The PL031 is only described by the DSDT, which we can't read (since it is AML), so we use the address which QEMU's `virt` machine puts it at.
+const PL031_ADDRESS: usize = 0x0901_0000;
+const PL031_MMIO_SIZE: usize = 0x1000;
+
+/// The number of seconds since the Unix epoch.
+const PL031_DATA_OFFSET: usize = 0x000;
+const PL031_PERIPHERAL_ID0_OFFSET: usize = 0xFE0;
+const PL031_PERIPHERAL_ID1_OFFSET: usize = 0xFE4;
+
+const PL031_PART_NUMBER: u32 = 0x031;
+
+/// Read the time (since the Unix epoch) from the RTC, if there is one.
+pub fn read_time(_acpi_info: &AcpiInfo) -> Option<Duration> {
+    // SAFETY: Nothing else uses the RTC.
+    let mmio_handle = unsafe {
+        MmioMemoryHandle::new(
+            PL031_ADDRESS,
+            PL031_MMIO_SIZE,
+            PagePermissions::KERNEL_READ_WRITE,
+        )
+    };
+    unsafe {
+        // Make sure that it really is a PL031 before trusting what it says.
+        let part_number = (mmio_handle
+            .at_offset::<u32>(PL031_PERIPHERAL_ID0_OFFSET)
+            .read()
+            & 0xff)
+            | (mmio_handle
+                .at_offset::<u32>(PL031_PERIPHERAL_ID1_OFFSET)
+                .read()
+                & 0xf)
+                << 8;
+        if part_number != PL031_PART_NUMBER {
+            return None;
+        }
+        let seconds = mmio_handle.at_offset::<u32>(PL031_DATA_OFFSET).read();
+        Some(Duration::from_secs(seconds as u64))
+    }
+}
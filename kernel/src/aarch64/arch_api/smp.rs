@@ -0,0 +1,241 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::global_asm,
+    mem::{offset_of, size_of},
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::{
+        asm::{clean_data_cache, read_ttbr0},
+        exceptions::load_exceptions,
+        psci::{self, Conduit},
+        registers::{get_cpacr, get_mair, get_mpidr, get_sctlr, get_tcr, get_ttbr1},
+    },
+    arch_api::{asm::wait_for_interrupt, irq, stack::Stack, timer},
+    clock::monotonic_time,
+    memory::align_address_down,
+    paging::{get_physical_address, map_page, unmap_page, MemoryType, PagePermissions, PAGE_SIZE},
+    per_cpu::{self, PerCpu},
+    println,
+};
+
+use super::acpi::AcpiInfo;
+
+/// The affinity fields of the MPIDR, which are what PSCI and the MADT use to identify a CPU.
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+/// The GICC flag for a CPU which is ready to use.
+/// CPUs which are only online capable have to be hot-added before they can be started, so they are left alone.
+const GICC_ENABLED: u32 = 1 << 0;
+
+/// How long to wait for a CPU to say it has started before giving up on it.
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Everything a secondary CPU needs to get from having its MMU off to running the kernel like the first CPU.
+/// It is read with the MMU (and so the cache) off, so it has to be cleaned from the cache before the CPU starts.
+/// The alignment keeps it within one page, since the pages of the heap aren't next to each other in physical memory.
+#[repr(C, align(128))]
+struct SecondaryStartData {
+    mair: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    sctlr: u64,
+    cpacr: u64,
+    stack_top: u64,
+    entry: u64,
+    processor: u64,
+}
+
+// PSCI starts the CPU here with its MMU off, so until paging is on this only uses the physical address of the start data in x0.
+// Turning the MMU on carries on at the same (physical) address, so this code has to be identity mapped while the CPUs start.
+global_asm!(
+    ".globl secondary_cpu_entry",
+    ".globl secondary_cpu_entry_end",
+    ".balign 4",
+    "secondary_cpu_entry:",
+    "    ldr x1, [x0, #{mair}]",
+    "    ldr x2, [x0, #{tcr}]",
+    "    ldr x3, [x0, #{ttbr0}]",
+    "    ldr x4, [x0, #{ttbr1}]",
+    "    ldr x5, [x0, #{sctlr}]",
+    "    ldr x6, [x0, #{cpacr}]",
+    "    ldr x7, [x0, #{stack_top}]",
+    "    ldr x8, [x0, #{entry}]",
+    "    ldr x9, [x0, #{processor}]",
+    "    msr mair_el1, x1",
+    "    msr tcr_el1, x2",
+    "    msr ttbr0_el1, x3",
+    "    msr ttbr1_el1, x4",
+    "    msr cpacr_el1, x6",
+    "    isb",
+    "    tlbi vmalle1",
+    "    dsb nsh",
+    "    isb",
+    "    msr sctlr_el1, x5",
+    "    isb",
+    "    mov sp, x7",
+    "    mov x0, x9",
+    "    mov x29, #0",
+    "    mov x30, #0",
+    "    br x8",
+    "secondary_cpu_entry_end:",
+    mair = const offset_of!(SecondaryStartData, mair),
+    tcr = const offset_of!(SecondaryStartData, tcr),
+    ttbr0 = const offset_of!(SecondaryStartData, ttbr0),
+    ttbr1 = const offset_of!(SecondaryStartData, ttbr1),
+    sctlr = const offset_of!(SecondaryStartData, sctlr),
+    cpacr = const offset_of!(SecondaryStartData, cpacr),
+    stack_top = const offset_of!(SecondaryStartData, stack_top),
+    entry = const offset_of!(SecondaryStartData, entry),
+    processor = const offset_of!(SecondaryStartData, processor),
+);
+
+extern "C" {
+    static secondary_cpu_entry: u8;
+    static secondary_cpu_entry_end: u8;
+}
+
+/// A CPU other than the one which booted the kernel.
+struct SecondaryProcessor {
+    mp_id_register: u64,
+    stack: Box<Stack>,
+    per_cpu: &'static PerCpu,
+    /// Set by the CPU once it has finished starting up.
+    started: AtomicBool,
+}
+
+// SAFETY: Only touched by `start_application_processors`, while nothing else is running.
+/// The CPUs which have been started, which stay around for as long as the kernel runs.
+static mut SECONDARY_PROCESSORS: Vec<&'static SecondaryProcessor> = Vec::new();
+
+/// The number of CPUs which are running, including the one which booted the kernel.
+pub fn cpu_count() -> usize {
+    // SAFETY: See above.
+    unsafe { (*addr_of!(SECONDARY_PROCESSORS)).len() + 1 }
+}
+
+/// The physical address of a kernel virtual address (which doesn't have to be page aligned).
+fn physical_address_of(virtual_address: usize) -> usize {
+    align_address_down(get_physical_address(virtual_address), PAGE_SIZE)
+        + virtual_address % PAGE_SIZE
+}
+
+/// Start every CPU other than this one (the secondary cores) with PSCI, leaving them idle with interrupts masked.
+///
+/// This has to be called after the GIC and the clock are initialized.
+pub fn start_application_processors(acpi_info: &AcpiInfo) {
+    let Some(conduit) = Conduit::from_fadt(&acpi_info.fadt) else {
+        println!("No PSCI, so only the boot CPU will run");
+        return;
+    };
+    let own_mp_id_register = get_mpidr() & MPIDR_AFFINITY_MASK;
+
+    let entry_start = unsafe { addr_of!(secondary_cpu_entry) as usize };
+    let entry_end = unsafe { addr_of!(secondary_cpu_entry_end) as usize };
+    let entry_physical_address = physical_address_of(entry_start);
+    // The CPU reads the code with its cache off.
+    clean_data_cache(entry_start, entry_end - entry_start);
+    let entry_pages = (align_address_down(entry_start, PAGE_SIZE)..entry_end).step_by(PAGE_SIZE);
+    for page in entry_pages.clone() {
+        let physical_page = align_address_down(get_physical_address(page), PAGE_SIZE);
+        map_page(
+            physical_page,
+            physical_page,
+            MemoryType::Normal,
+            PagePermissions::KERNEL_READ_EXECUTE,
+        );
+    }
+
+    let mut any_timed_out = false;
+    // CPUs which fail to start still use up an ID, since they might start later on.
+    let mut next_cpu_id = 1;
+    for cpu_interface in &acpi_info
+        .madt
+        .generic_interrupt_controller_cpu_interface_entries
+    {
+        let mp_id_register = cpu_interface.mp_id_register & MPIDR_AFFINITY_MASK;
+        if mp_id_register == own_mp_id_register || cpu_interface.flags & GICC_ENABLED == 0 {
+            continue;
+        }
+
+        let mut stack: Box<Stack> = unsafe { Box::new_zeroed().assume_init() };
+        let stack_top = stack.as_mut_ptr() as u64 + size_of::<Stack>() as u64;
+        let processor: &'static SecondaryProcessor = Box::leak(Box::new(SecondaryProcessor {
+            mp_id_register,
+            stack,
+            per_cpu: per_cpu::allocate(next_cpu_id),
+            started: AtomicBool::new(false),
+        }));
+        next_cpu_id += 1;
+        // The CPU only needs this until it has turned its MMU on, but it isn't worth keeping track of when that is.
+        let start_data = Box::leak(Box::new(SecondaryStartData {
+            mair: get_mair(),
+            tcr: get_tcr(),
+            ttbr0: read_ttbr0(),
+            ttbr1: get_ttbr1(),
+            sctlr: get_sctlr(),
+            cpacr: get_cpacr(),
+            stack_top,
+            entry: secondary_cpu_main as usize as u64,
+            processor: processor as *const SecondaryProcessor as u64,
+        }));
+        let start_data_address = start_data as *const SecondaryStartData as usize;
+        clean_data_cache(start_data_address, size_of::<SecondaryStartData>());
+
+        // SAFETY: The entry code is identity mapped, and the start data is set up for this CPU.
+        if let Err(error) = unsafe {
+            psci::cpu_on(
+                conduit,
+                mp_id_register,
+                entry_physical_address,
+                physical_address_of(start_data_address) as u64,
+            )
+        } {
+            println!(
+                "Failed to start CPU with MPIDR {:#x}: {:?}",
+                mp_id_register, error
+            );
+            continue;
+        }
+
+        let deadline = monotonic_time() + START_TIMEOUT;
+        while !processor.started.load(Ordering::Acquire) && monotonic_time() < deadline {
+            core::hint::spin_loop();
+        }
+        if !processor.started.load(Ordering::Acquire) {
+            // It has its own start data, so it can still carry on starting later without getting in anything's way.
+            println!("CPU with MPIDR {:#x} didn't start in time", mp_id_register);
+            any_timed_out = true;
+            continue;
+        }
+        println!("Started CPU with MPIDR {:#x}", mp_id_register);
+        // SAFETY: See `SECONDARY_PROCESSORS`.
+        unsafe { (*core::ptr::addr_of_mut!(SECONDARY_PROCESSORS)).push(processor) };
+    }
+
+    // A CPU which didn't start in time could still need the entry code later on.
+    if !any_timed_out {
+        for page in entry_pages {
+            unmap_page(align_address_down(get_physical_address(page), PAGE_SIZE));
+        }
+    }
+    println!("{} CPUs running", cpu_count());
+}
+
+extern "C" fn secondary_cpu_main(processor: &'static SecondaryProcessor) -> ! {
+    // SAFETY: The data was made just for this CPU, and is never freed.
+    unsafe { per_cpu::install(processor.per_cpu) };
+    load_exceptions();
+    irq::enable_interrupts_for_this_cpu();
+    timer::initialize_this_cpu();
+    processor.started.store(true, Ordering::Release);
+
+    // Interrupts stay masked until the scheduler can run threads on more than one CPU, so this waits forever.
+    loop {
+        wait_for_interrupt();
+    }
+}
@@ -0,0 +1,23 @@
+use common::syscall::{encode_result, SyscallResult};
+
+use super::thread::SavedRegisters;
+
+// See common::syscall for a description of the ABI.
+impl SavedRegisters {
+    pub fn syscall_number(&self) -> u64 {
+        self.x8
+    }
+
+    pub fn syscall_arguments(&self) -> [u64; 6] {
+        [self.x0, self.x1, self.x2, self.x3, self.x4, self.x5]
+    }
+
+    pub fn set_syscall_result(&mut self, result: SyscallResult) {
+        (self.x0, self.x1) = encode_result(result);
+    }
+
+    /// Go back to the `svc` instruction, so the system call happens again when the thread is resumed.
+    pub fn restart_syscall(&mut self) {
+        self.elr -= 4;
+    }
+}
@@ -0,0 +1,80 @@
+use core::{arch::asm, mem::size_of};
+
+use crate::per_cpu::this_cpu;
+
+pub use crate::arch::exceptions::SavedRegisters;
+
+/// EL1 using SP_EL1, with all exceptions unmasked.
+const KERNEL_MODE_SPSR: u64 = 0b0101;
+/// EL0, with all exceptions unmasked.
+const USER_MODE_SPSR: u64 = 0b0000;
+
+/// Write the given registers to the top of a kernel stack, returning a pointer to where they were written.
+///
+/// Returning from an exception pops the registers off the stack, so `SP_EL1` ends up at `stack_top` once the thread is running.
+///
+/// # Safety
+/// `stack_top` must be the (16-byte aligned) top of a kernel stack with room for the registers.
+unsafe fn push_registers(stack_top: usize, registers: SavedRegisters) -> *mut SavedRegisters {
+    let registers_pointer = (stack_top - size_of::<SavedRegisters>()) as *mut SavedRegisters;
+    registers_pointer.write(registers);
+    registers_pointer
+}
+
+/// Set up the initial registers for a thread which runs `entrypoint` in kernel mode.
+///
+/// # Safety
+/// `stack_top` must be the (16-byte aligned) top of a kernel stack, which is then owned by the new thread.
+pub unsafe fn push_initial_kernel_registers(
+    stack_top: usize,
+    entrypoint: extern "C" fn() -> !,
+) -> *mut SavedRegisters {
+    push_registers(
+        stack_top,
+        SavedRegisters {
+            elr: entrypoint as usize as u64,
+            spsr: KERNEL_MODE_SPSR,
+            ..Default::default()
+        },
+    )
+}
+
+/// Set up the initial registers for a thread which starts at `entrypoint` in user mode.
+///
+/// # Safety
+/// `stack_top` must be the (16-byte aligned) top of a kernel stack, which is then owned by the new thread.
+pub unsafe fn push_initial_user_registers(
+    stack_top: usize,
+    entrypoint: usize,
+    stack_pointer: usize,
+) -> *mut SavedRegisters {
+    push_registers(
+        stack_top,
+        SavedRegisters {
+            sp: stack_pointer as u64,
+            elr: entrypoint as u64,
+            spsr: USER_MODE_SPSR,
+            ..Default::default()
+        },
+    )
+}
+
+/// Tell the CPU which kernel stack to use for interrupts which arrive while running the current thread.
+///
+/// This only keeps track of it in the CPU's data on aarch64, since `SP_EL1` is left at the top of the kernel stack when we return to user mode.
+pub fn set_kernel_stack(stack_top: usize) {
+    this_cpu().set_kernel_stack_top(stack_top);
+}
+
+/// Restore the given registers, continuing wherever they were saved.
+///
+/// # Safety
+/// The registers must have been produced by an exception handler or one of the functions above, and must not be in use by anything else.
+pub unsafe fn resume(registers: *mut SavedRegisters) -> ! {
+    asm!(
+        "mov sp, {}",
+        "b restore_registers_and_eret",
+        in(reg) registers,
+        options(noreturn)
+    );
+}
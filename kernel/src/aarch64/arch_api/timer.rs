@@ -1,4 +1,7 @@
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
 
 use crate::arch::{
     gtdt::TimerFlags,
@@ -11,24 +14,68 @@ use super::{
 };
 
 static TIMER_INTERRUPT: AtomicU32 = AtomicU32::new(0);
+static TIMER_INTERRUPT_EDGE_TRIGGERED: AtomicBool = AtomicBool::new(false);
+/// The counter value when the timer was initialized, which counts as the start of the clock.
+static CLOCK_START: AtomicU64 = AtomicU64::new(0);
+/// 0 until the timer is initialized.
+static CLOCK_FREQUENCY: AtomicU64 = AtomicU64::new(0);
 
 pub fn initialize(acpi_info: &AcpiInfo) {
     let timer_frequency = get_cntfrq();
-    set_cntv_ctl(0x1); // Enable the timer, unmask the interrupt
-    set_cntv_cval(get_cntvct() + timer_frequency); // Set the timer compare value to go off in 1 second
-    configure_interrupt(
-        acpi_info.gtdt.timer_interrupt,
+    CLOCK_START.store(get_cntvct(), Ordering::SeqCst);
+    CLOCK_FREQUENCY.store(timer_frequency, Ordering::SeqCst);
+    TIMER_INTERRUPT.store(acpi_info.gtdt.timer_interrupt, Ordering::SeqCst);
+    TIMER_INTERRUPT_EDGE_TRIGGERED.store(
         acpi_info
             .gtdt
             .timer_flags
             .contains(TimerFlags::EDGE_TRIGGERED),
-        Priority::High,
+        Ordering::SeqCst,
     );
-    enable_interrupt(acpi_info.gtdt.timer_interrupt);
+    initialize_this_cpu();
+}
 
-    TIMER_INTERRUPT.store(acpi_info.gtdt.timer_interrupt, Ordering::SeqCst);
+/// Set up the timer of the current CPU, which the other CPUs do once the first one has initialized the clock.
+/// The timer interrupt is a private peripheral interrupt, so each CPU has to configure and enable its own.
+pub(in crate::arch) fn initialize_this_cpu() {
+    set_cntv_ctl(0x0); // Disabled until something sets a deadline
+    let timer_interrupt = get_timer_interrupt();
+    configure_interrupt(
+        timer_interrupt,
+        TIMER_INTERRUPT_EDGE_TRIGGERED.load(Ordering::SeqCst),
+        Priority::High,
+    );
+    enable_interrupt(timer_interrupt);
 }
 
 pub(in crate::arch) fn get_timer_interrupt() -> u32 {
     TIMER_INTERRUPT.load(Ordering::SeqCst)
 }
+
+/// How many ticks of the clock have passed since it was initialized.
+pub fn read_clock() -> u64 {
+    get_cntvct().wrapping_sub(CLOCK_START.load(Ordering::Relaxed))
+}
+
+/// In ticks per second, or 0 if the clock hasn't been initialized yet.
+pub fn clock_frequency() -> u64 {
+    CLOCK_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Make the timer interrupt happen when the monotonic clock reaches `deadline`, or never if it's `None`.
+///
+/// This replaces any deadline which was set before.
+pub fn set_deadline(deadline: Option<Duration>) {
+    match deadline {
+        Some(deadline) => {
+            let ticks = deadline.as_nanos() * clock_frequency() as u128 / 1_000_000_000;
+            let compare_value = CLOCK_START
+                .load(Ordering::Relaxed)
+                .saturating_add(ticks.min(u64::MAX as u128) as u64);
+            set_cntv_cval(compare_value);
+            set_cntv_ctl(0x1); // Enable the timer, unmask the interrupt
+        }
+        // The interrupt stays pending while the timer is enabled and the deadline has passed, so it has to be turned off.
+        None => set_cntv_ctl(0x0),
+    }
+}
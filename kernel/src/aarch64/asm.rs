@@ -15,6 +15,13 @@ pub unsafe fn write_ttbr0(ttbr0: u64) {
     unsafe { asm!("msr ttbr0_el1, {}", in(reg) ttbr0, options(nomem, nostack)) }
 }
 
+#[inline(always)]
+pub fn read_ttbr0() -> u64 {
+    let ttbr0: u64;
+    unsafe { asm!("mrs {}, ttbr0_el1", out(reg) ttbr0, options(nomem, nostack)) }
+    ttbr0
+}
+
 #[inline(always)]
 pub fn yield_instruction() {
     unsafe { asm!("yield", options(nomem, nostack)) }
@@ -29,3 +36,17 @@ pub unsafe fn eret(elr: u64, spsr: u64) -> ! {
         asm!("msr elr_el1, {}", "msr spsr_el1, {}", "eret", in(reg) elr, in(reg) spsr, options(nomem, nostack, noreturn));
     }
 }
+
+/// Write any cached data in the range back to main memory, so that something which doesn't use the cache (like a CPU with its MMU turned off) can see it.
+pub fn clean_data_cache(start: usize, size: usize) {
+    let cache_type: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) cache_type, options(nomem, nostack)) };
+    // The smallest data cache line size, as the log2 of the number of words.
+    let line_size = 4usize << ((cache_type >> 16) & 0xf);
+    let mut address = start & !(line_size - 1);
+    while address < start + size {
+        unsafe { asm!("dc cvac, {}", in(reg) address, options(nostack)) };
+        address += line_size;
+    }
+    unsafe { asm!("dsb sy", options(nostack)) };
+}
@@ -1,16 +1,18 @@
 #[cfg_attr(test, allow(unused_imports))]
 use core::{
     arch::{asm, global_asm},
-    fmt::Debug,
+    fmt::{self, Debug},
 };
 
+use common::syscall::KILLED_EXIT_STATUS;
+
 use crate::{
-    arch::registers::{get_cntfrq, get_cntvct, get_esr, set_cntv_cval},
+    arch::registers::{get_esr, get_far},
     arch_api::{
         irq::{acknowledge_interrupt, end_of_interrupt},
         timer,
     },
-    print,
+    irq, println, process, scheduler, syscall, timer_queue, user_memory,
 };
 
 // The vector table itself is defined in assembly language, since it requires low-level manipulation of registers and system instructions.
@@ -70,46 +72,57 @@ pub extern "C" fn invalid_vector(vector: *const &str) {
 }
 
 /// The registers saved by the assembly code, which are passed to the handlers.
-#[derive(Debug)]
+#[repr(C)]
+#[derive(Debug, Default)]
 pub struct SavedRegisters {
-    x0: u64,
-    x1: u64,
-    x2: u64,
-    x3: u64,
-    x4: u64,
-    x5: u64,
-    x6: u64,
-    x7: u64,
-    x8: u64,
-    x9: u64,
-    x10: u64,
-    x11: u64,
-    x12: u64,
-    x13: u64,
-    x14: u64,
-    x15: u64,
-    x16: u64,
-    x17: u64,
-    x18: u64,
-    x19: u64,
-    x20: u64,
-    x21: u64,
-    x22: u64,
-    x23: u64,
-    x24: u64,
-    x25: u64,
-    x26: u64,
-    x27: u64,
-    x28: u64,
-    x29: u64,
-    x30: u64,
-    sp: u64,
-    elr: u64, // Exception Link Register, giving the address of the interrupted instruction.
-    spsr: u64,
+    pub(in crate::arch) x0: u64,
+    pub(in crate::arch) x1: u64,
+    pub(in crate::arch) x2: u64,
+    pub(in crate::arch) x3: u64,
+    pub(in crate::arch) x4: u64,
+    pub(in crate::arch) x5: u64,
+    pub(in crate::arch) x6: u64,
+    pub(in crate::arch) x7: u64,
+    pub(in crate::arch) x8: u64,
+    pub(in crate::arch) x9: u64,
+    pub(in crate::arch) x10: u64,
+    pub(in crate::arch) x11: u64,
+    pub(in crate::arch) x12: u64,
+    pub(in crate::arch) x13: u64,
+    pub(in crate::arch) x14: u64,
+    pub(in crate::arch) x15: u64,
+    pub(in crate::arch) x16: u64,
+    pub(in crate::arch) x17: u64,
+    pub(in crate::arch) x18: u64,
+    pub(in crate::arch) x19: u64,
+    pub(in crate::arch) x20: u64,
+    pub(in crate::arch) x21: u64,
+    pub(in crate::arch) x22: u64,
+    pub(in crate::arch) x23: u64,
+    pub(in crate::arch) x24: u64,
+    pub(in crate::arch) x25: u64,
+    pub(in crate::arch) x26: u64,
+    pub(in crate::arch) x27: u64,
+    pub(in crate::arch) x28: u64,
+    pub(in crate::arch) x29: u64,
+    pub(in crate::arch) x30: u64,
+    pub(in crate::arch) sp: u64,
+    pub(in crate::arch) elr: u64, // Exception Link Register, giving the address of the interrupted instruction.
+    pub(in crate::arch) spsr: u64,
 }
 
 #[no_mangle]
-pub extern "C" fn synchronous_vector(registers: &SavedRegisters) {
+pub extern "C" fn synchronous_vector(registers: *mut SavedRegisters) -> *mut SavedRegisters {
+    let esr_value = get_esr();
+    let esr_class = (esr_value >> 26) & 0b111111;
+    // System calls use user memory, which might not have been touched yet.
+    if esr_class == ESR_CLASS_DATA_ABORT_SAME_LEVEL
+        && is_translation_fault(esr_value)
+        && user_memory::handle_page_fault(get_far() as usize)
+    {
+        return registers;
+    }
+    let registers = unsafe { &*registers };
     panic!(
         "Synchronous exception at {:p}: {:x}\n{:x?}",
         registers.elr as *const (),
@@ -118,58 +131,138 @@ pub extern "C" fn synchronous_vector(registers: &SavedRegisters) {
     );
 }
 #[no_mangle]
-pub extern "C" fn irq_vector(registers: &SavedRegisters) {
+pub extern "C" fn irq_vector(registers: *mut SavedRegisters) -> *mut SavedRegisters {
     let Some(irq_info) = acknowledge_interrupt() else {
-        return;
+        return registers;
     };
     let interrupt_number = irq_info.interrupt_number;
     if interrupt_number == timer::get_timer_interrupt() {
-        // Test code for the timer. Remove when we know it works.
-        print!(".");
-        // Set the timer to go off again in 1 second.
-        set_cntv_cval(get_cntfrq() + get_cntvct());
+        timer_queue::handle_timer_interrupt();
+        end_of_interrupt(irq_info);
+        scheduler::preempt(registers)
+    } else if irq::handle(interrupt_number) {
+        end_of_interrupt(irq_info);
+        registers
     } else {
-        panic!("IRQ {}\n{:x?}", irq_info.interrupt_number, registers);
+        panic!("IRQ {}\n{:x?}", irq_info.interrupt_number, unsafe {
+            &*registers
+        });
     }
-    end_of_interrupt(irq_info);
 }
 #[no_mangle]
-pub extern "C" fn fiq_vector(registers: &SavedRegisters) {
+pub extern "C" fn fiq_vector(registers: &SavedRegisters) -> *mut SavedRegisters {
     panic!("FIQ exception\n{:x?}", registers);
 }
 #[no_mangle]
-pub extern "C" fn serror_vector(registers: &SavedRegisters) {
+pub extern "C" fn serror_vector(registers: &SavedRegisters) -> *mut SavedRegisters {
     panic!("SError exception\n{:x?}", registers);
 }
 
+const ESR_CLASS_UNKNOWN: u64 = 0b000000;
+const ESR_CLASS_FLOATING_POINT: u64 = 0b000111;
+const ESR_CLASS_ILLEGAL_EXECUTION_STATE: u64 = 0b001110;
 const ESR_CLASS_SVC: u64 = 0b010101;
+const ESR_CLASS_INSTRUCTION_ABORT: u64 = 0b100000;
+const ESR_CLASS_PC_ALIGNMENT: u64 = 0b100010;
+const ESR_CLASS_DATA_ABORT: u64 = 0b100100;
+const ESR_CLASS_DATA_ABORT_SAME_LEVEL: u64 = 0b100101;
+const ESR_CLASS_SP_ALIGNMENT: u64 = 0b100110;
+const ESR_CLASS_BRK: u64 = 0b111100;
+
+const ESR_DATA_ABORT_WRITE: u64 = 1 << 6;
+
+/// Describe the fault status code from the ESR of an instruction or data abort.
+fn describe_abort(esr_value: u64) -> &'static str {
+    match (esr_value & 0b111111) >> 2 {
+        0b0000 => "address size fault",
+        0b0001 => "translation fault",
+        0b0010 => "access flag fault",
+        0b0011 => "permission fault",
+        _ => "other abort",
+    }
+}
 
+fn is_translation_fault(esr_value: u64) -> bool {
+    (esr_value & 0b111111) >> 2 == 0b0001
+}
+
+fn kill_user_program(registers: &SavedRegisters, reason: fmt::Arguments) -> *mut SavedRegisters {
+    println!(
+        "Killed user program: {} at {:p}",
+        reason, registers.elr as *const ()
+    );
+    process::exit_current_process(KILLED_EXIT_STATUS)
+}
+
+// Exceptions from lower exception levels always come from user mode, so we don't have to check the SPSR to find out where they came from.
 #[no_mangle]
-pub extern "C" fn synchronous_vector_user(registers: &SavedRegisters) {
+pub extern "C" fn synchronous_vector_user(registers: *mut SavedRegisters) -> *mut SavedRegisters {
     let esr_value = get_esr();
     let esr_class = (esr_value >> 26) & 0b111111;
     if esr_class == ESR_CLASS_SVC {
         // It was a system call instruction.
-        crate::println!("System call from user mode");
-    } else {
-        panic!(
-            "synchronous exception in user code at {:p}: {:x}\n{:x?}",
-            registers.elr as *const (),
-            get_esr(),
-            registers
-        );
+        return syscall::handle_syscall(registers);
+    }
+    if (esr_class == ESR_CLASS_INSTRUCTION_ABORT || esr_class == ESR_CLASS_DATA_ABORT)
+        && is_translation_fault(esr_value)
+        && user_memory::handle_page_fault(get_far() as usize)
+    {
+        return registers;
+    }
+    let registers = unsafe { &*registers };
+    match esr_class {
+        ESR_CLASS_INSTRUCTION_ABORT => kill_user_program(
+            registers,
+            format_args!(
+                "instruction abort executing {:p} ({})",
+                get_far() as *const (),
+                describe_abort(esr_value)
+            ),
+        ),
+        ESR_CLASS_DATA_ABORT => kill_user_program(
+            registers,
+            format_args!(
+                "data abort {} {:p} ({})",
+                if esr_value & ESR_DATA_ABORT_WRITE != 0 {
+                    "writing"
+                } else {
+                    "reading"
+                },
+                get_far() as *const (),
+                describe_abort(esr_value)
+            ),
+        ),
+        ESR_CLASS_UNKNOWN => kill_user_program(registers, format_args!("undefined instruction")),
+        ESR_CLASS_FLOATING_POINT => {
+            kill_user_program(registers, format_args!("floating point access"))
+        }
+        ESR_CLASS_ILLEGAL_EXECUTION_STATE => {
+            kill_user_program(registers, format_args!("illegal execution state"))
+        }
+        ESR_CLASS_PC_ALIGNMENT => kill_user_program(
+            registers,
+            format_args!("misaligned program counter {:p}", get_far() as *const ()),
+        ),
+        ESR_CLASS_SP_ALIGNMENT => {
+            kill_user_program(registers, format_args!("misaligned stack pointer"))
+        }
+        ESR_CLASS_BRK => kill_user_program(registers, format_args!("breakpoint")),
+        _ => kill_user_program(
+            registers,
+            format_args!("synchronous exception (ESR {:x})", esr_value),
+        ),
     }
 }
 
 #[no_mangle]
-pub extern "C" fn irq_vector_user(registers: &SavedRegisters) {
-    irq_vector(registers);
+pub extern "C" fn irq_vector_user(registers: *mut SavedRegisters) -> *mut SavedRegisters {
+    irq_vector(registers)
 }
 #[no_mangle]
-pub extern "C" fn fiq_vector_user(registers: &SavedRegisters) {
+pub extern "C" fn fiq_vector_user(registers: &SavedRegisters) -> *mut SavedRegisters {
     panic!("FIQ exception in user code\n{:x?}", registers);
 }
 #[no_mangle]
-pub extern "C" fn serror_vector_user(registers: &SavedRegisters) {
+pub extern "C" fn serror_vector_user(registers: &SavedRegisters) -> *mut SavedRegisters {
     panic!("SError exception in user code\n{:x?}", registers);
 }
@@ -1,4 +1,5 @@
 // Sets up the data structure for the exception handlers to interpret.
+// x30 has to be saved by the caller, since calling this function overwrites it.
 save_registers:
 stp x0, x1, [sp, #0x00]
 stp x2, x3, [sp, #0x10]
@@ -16,12 +17,14 @@ stp x24, x25, [sp, #0xc0]
 stp x26, x27, [sp, #0xd0]
 stp x28, x29, [sp, #0xe0]
 mrs x0, sp_el0
-stp x30, x0, [sp, #0xf0]
+str x0, [sp, #0xf8]
 mrs x0, elr_el1
 mrs x1, spsr_el1
 stp x0, x1, [sp, #0x100]
 ret
 
+// The handlers return a pointer to the registers which should be restored, which is how the scheduler switches between threads.
+.globl restore_registers_and_eret
 restore_registers_and_eret:
 ldp x0, x1, [sp, #0x100]
 msr spsr_el1, x1
@@ -67,53 +70,69 @@ b invalid_vector
 // The next four are system exceptions with kernel stack, which is what we use.
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl synchronous_vector
+mov sp, x0
 b restore_registers_and_eret
 
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl irq_vector
+mov sp, x0
 b restore_registers_and_eret
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl fiq_vector
+mov sp, x0
 b restore_registers_and_eret
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl serror_vector
+mov sp, x0
 b restore_registers_and_eret
 // The next lot are the user mode vectors in aarch64 mode.
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl synchronous_vector_user
+mov sp, x0
 b restore_registers_and_eret
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl irq_vector_user
+mov sp, x0
 b restore_registers_and_eret
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl fiq_vector_user
+mov sp, x0
 b restore_registers_and_eret
 .p2align 7
 sub sp, sp, #0x110
+str x30, [sp, #0xf0]
 bl save_registers
 mov x0, sp // Passing the registers as the first argument.
 bl serror_vector_user
+mov sp, x0
 b restore_registers_and_eret
 // The last lot are for aarch32, which we don't support.
 .p2align 7
@@ -0,0 +1,383 @@
+use alloc::vec::Vec;
+
+use crate::{
+    acpi::madt::GenericInterruptControllerRedistributorInfo,
+    arch::{
+        asm::isb,
+        registers::{
+            get_icc_iar1_el1, get_icc_sre_el1, get_mpidr, set_icc_eoir1_el1, set_icc_igrpen1_el1,
+            set_icc_pmr_el1, set_icc_sre_el1,
+        },
+    },
+    arch_api::irq::{GenericInterruptController, InterruptInfo, Priority},
+    mmio::MmioMemoryHandle,
+    paging::PagePermissions,
+    println,
+};
+
+/// Each CPU has its own redistributor, which handles its private interrupts and wakes it up.
+struct Redistributor {
+    /// The affinity (Aff3.Aff2.Aff1.Aff0) of the CPU it belongs to.
+    affinity: u32,
+    registers: MmioMemoryHandle,
+}
+
+pub struct Gicv3 {
+    distributor_registers: MmioMemoryHandle,
+    redistributors: Vec<Redistributor>,
+
+    interrupt_line_count: u32,
+}
+
+const DISTRIBUTOR_RANGE_LENGTH: usize = 0x10000;
+/// A redistributor has a frame with its control registers, followed by one with the registers for the private interrupts.
+const REDISTRIBUTOR_RANGE_LENGTH: usize = 0x20000;
+/// GICv4 redistributors have another two frames after that, for virtual interrupts.
+const REDISTRIBUTOR_WITH_VIRTUAL_INTERRUPTS_LENGTH: usize = 0x40000;
+const PRIVATE_INTERRUPT_FRAME_OFFSET: usize = 0x10000;
+
+const DISTRIBUTOR_CONTROL_OFFSET: usize = 0x0000;
+const DISTRIBUTOR_TYPE_OFFSET: usize = 0x0004;
+const DISTRIBUTOR_GROUP_OFFSET: usize = 0x0080;
+const DISTRIBUTOR_SET_ENABLE_OFFSET: usize = 0x0100;
+const DISTRIBUTOR_CLEAR_ENABLE_OFFSET: usize = 0x0180;
+const DISTRIBUTOR_PRIORITY_OFFSET: usize = 0x0400;
+const DISTRIBUTOR_INTERRUPT_CONFIGURATION_OFFSET: usize = 0x0C00;
+/// One 64-bit register for each interrupt, saying which CPU it goes to.
+const DISTRIBUTOR_ROUTING_OFFSET: usize = 0x6000;
+
+/// Enables both group 1 bits, since which one is which depends on whether the GIC has a secure mode.
+const DISTRIBUTOR_CONTROL_ENABLE_GROUP_1: u32 = 0b11;
+const DISTRIBUTOR_CONTROL_AFFINITY_ROUTING: u32 = 1 << 4;
+const DISTRIBUTOR_CONTROL_REGISTER_WRITE_PENDING: u32 = 1 << 31;
+
+const REDISTRIBUTOR_CONTROL_OFFSET: usize = 0x0000;
+const REDISTRIBUTOR_TYPE_OFFSET: usize = 0x0008;
+const REDISTRIBUTOR_WAKE_OFFSET: usize = 0x0014;
+const REDISTRIBUTOR_GROUP_OFFSET: usize = PRIVATE_INTERRUPT_FRAME_OFFSET + 0x0080;
+const REDISTRIBUTOR_SET_ENABLE_OFFSET: usize = PRIVATE_INTERRUPT_FRAME_OFFSET + 0x0100;
+const REDISTRIBUTOR_CLEAR_ENABLE_OFFSET: usize = PRIVATE_INTERRUPT_FRAME_OFFSET + 0x0180;
+const REDISTRIBUTOR_PRIORITY_OFFSET: usize = PRIVATE_INTERRUPT_FRAME_OFFSET + 0x0400;
+const REDISTRIBUTOR_INTERRUPT_CONFIGURATION_OFFSET: usize = PRIVATE_INTERRUPT_FRAME_OFFSET + 0x0C00;
+
+const REDISTRIBUTOR_CONTROL_REGISTER_WRITE_PENDING: u32 = 1 << 3;
+const REDISTRIBUTOR_TYPE_VIRTUAL_INTERRUPTS: u64 = 1 << 1;
+const REDISTRIBUTOR_TYPE_LAST: u64 = 1 << 4;
+const REDISTRIBUTOR_WAKE_PROCESSOR_SLEEP: u32 = 1 << 1;
+const REDISTRIBUTOR_WAKE_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+const ICC_SRE_ENABLE: u64 = 1 << 0;
+
+// Interrupts 0-15 are sent by software, 16-31 are private to each CPU (and set up in its redistributor), and the rest are shared between the CPUs.
+const PRIVATE_INTERRUPT_START: u32 = 16;
+const SHARED_INTERRUPT_START: u32 = 32;
+/// Interrupts 1020-1023 are special, and acknowledging one of them means that there wasn't an interrupt.
+const SPECIAL_INTERRUPT_START: u32 = 1020;
+
+const LOW_PRIORITY: u8 = 0xd0;
+const NORMAL_PRIORITY: u8 = 0xc0;
+const HIGH_PRIORITY: u8 = 0xb0;
+
+/// Put the affinity fields of the MPIDR next to each other, the way the redistributors report them.
+fn affinity(mpidr: u64) -> u32 {
+    ((mpidr >> 8) & 0xff00_0000) as u32 | (mpidr & 0xff_ffff) as u32
+}
+
+/// The routing register has the affinity fields where the MPIDR has them.
+fn routing_register_value(affinity: u32) -> u64 {
+    ((affinity as u64 & 0xff00_0000) << 8) | (affinity as u64 & 0xff_ffff)
+}
+
+/// # Safety
+/// The registers must be a GIC distributor or redistributor, with the configuration register at `configuration_offset`.
+unsafe fn set_edge_triggered(
+    registers: &mut MmioMemoryHandle,
+    configuration_offset: usize,
+    interrupt_number: u32,
+    edge_triggered: bool,
+) {
+    // There are 2 bits for each interrupt, and the upper one is set if it is edge triggered.
+    let register_offset = configuration_offset + (interrupt_number / 16) as usize * 4;
+    let shift = (interrupt_number % 16) * 2 + 1;
+    let value = registers.at_offset::<u32>(register_offset).read();
+    registers
+        .at_offset::<u32>(register_offset)
+        .write(value & !(1 << shift) | (edge_triggered as u32) << shift);
+}
+
+fn priority_value(priority: Priority) -> u8 {
+    match priority {
+        Priority::Low => LOW_PRIORITY,
+        Priority::Normal => NORMAL_PRIORITY,
+        Priority::High => HIGH_PRIORITY,
+    }
+}
+
+impl Gicv3 {
+    /// # Safety
+    /// There must be no other active drivers, and the provided addresses must point to valid GICs.
+    pub unsafe fn new(
+        distributor_address: usize,
+        redistributor_ranges: &[GenericInterruptControllerRedistributorInfo],
+    ) -> Self {
+        let distributor_registers = MmioMemoryHandle::new(
+            distributor_address,
+            DISTRIBUTOR_RANGE_LENGTH,
+            PagePermissions::KERNEL_READ_WRITE,
+        );
+
+        // As with GICv2, the distributor is disabled while it is being set up.
+        distributor_registers
+            .at_offset::<u32>(DISTRIBUTOR_CONTROL_OFFSET)
+            .write(0x0);
+        wait_for_distributor_writes(&distributor_registers);
+
+        // Bits 4:0 of the type register give the number of interrupt lines, as 32(n+1).
+        let type_register = distributor_registers
+            .at_offset::<u32>(DISTRIBUTOR_TYPE_OFFSET)
+            .read();
+        let interrupt_line_count =
+            (32 * ((type_register & 0b11111) + 1)).min(SPECIAL_INTERRUPT_START);
+
+        // The kernel uses group 1 for everything, and the shared interrupts start off disabled.
+        for register_index in (SHARED_INTERRUPT_START / 32)..(interrupt_line_count / 32) {
+            let register_offset = register_index as usize * 4;
+            distributor_registers
+                .at_offset::<u32>(DISTRIBUTOR_GROUP_OFFSET + register_offset)
+                .write(0xFFFFFFFF);
+            distributor_registers
+                .at_offset::<u32>(DISTRIBUTOR_CLEAR_ENABLE_OFFSET + register_offset)
+                .write(0xFFFFFFFF);
+        }
+        wait_for_distributor_writes(&distributor_registers);
+
+        // With affinity routing, the shared interrupts are sent to CPUs by their affinity rather than by a bitmask of CPU interfaces.
+        distributor_registers
+            .at_offset::<u32>(DISTRIBUTOR_CONTROL_OFFSET)
+            .write(DISTRIBUTOR_CONTROL_AFFINITY_ROUTING | DISTRIBUTOR_CONTROL_ENABLE_GROUP_1);
+        wait_for_distributor_writes(&distributor_registers);
+
+        // The redistributors are packed together in each range, with the last one marked in its type register.
+        let mut redistributors = Vec::new();
+        for redistributor_range in redistributor_ranges {
+            let mut offset = 0;
+            while offset + REDISTRIBUTOR_RANGE_LENGTH
+                <= redistributor_range.discovery_range_length as usize
+            {
+                let registers = MmioMemoryHandle::new(
+                    redistributor_range.discovery_range_base_address as usize + offset,
+                    REDISTRIBUTOR_RANGE_LENGTH,
+                    PagePermissions::KERNEL_READ_WRITE,
+                );
+                let type_register = registers.at_offset::<u64>(REDISTRIBUTOR_TYPE_OFFSET).read();
+                redistributors.push(Redistributor {
+                    affinity: (type_register >> 32) as u32,
+                    registers,
+                });
+                if type_register & REDISTRIBUTOR_TYPE_LAST != 0 {
+                    break;
+                }
+                offset += if type_register & REDISTRIBUTOR_TYPE_VIRTUAL_INTERRUPTS != 0 {
+                    REDISTRIBUTOR_WITH_VIRTUAL_INTERRUPTS_LENGTH
+                } else {
+                    REDISTRIBUTOR_RANGE_LENGTH
+                };
+            }
+        }
+
+        println!(
+            "GICv3 with {} interrupt lines and {} redistributors",
+            interrupt_line_count,
+            redistributors.len()
+        );
+
+        Self {
+            distributor_registers,
+            redistributors,
+
+            interrupt_line_count,
+        }
+    }
+
+    fn this_cpu_redistributor(&mut self) -> &mut Redistributor {
+        let affinity = affinity(get_mpidr());
+        self.redistributors
+            .iter_mut()
+            .find(|redistributor| redistributor.affinity == affinity)
+            .unwrap_or_else(|| panic!("No GIC redistributor for affinity {:x}", affinity))
+    }
+}
+
+/// Writes to some distributor registers (like disabling interrupts) take effect some time later, and this waits until they have.
+///
+/// # Safety
+/// The registers must be a GIC distributor.
+unsafe fn wait_for_distributor_writes(distributor_registers: &MmioMemoryHandle) {
+    while distributor_registers
+        .at_offset::<u32>(DISTRIBUTOR_CONTROL_OFFSET)
+        .read()
+        & DISTRIBUTOR_CONTROL_REGISTER_WRITE_PENDING
+        != 0
+    {
+        core::hint::spin_loop();
+    }
+}
+
+/// # Safety
+/// The registers must be a GIC redistributor.
+unsafe fn wait_for_redistributor_writes(redistributor_registers: &MmioMemoryHandle) {
+    while redistributor_registers
+        .at_offset::<u32>(REDISTRIBUTOR_CONTROL_OFFSET)
+        .read()
+        & REDISTRIBUTOR_CONTROL_REGISTER_WRITE_PENDING
+        != 0
+    {
+        core::hint::spin_loop();
+    }
+}
+
+impl GenericInterruptController for Gicv3 {
+    fn acknowledge_interrupt(&mut self) -> Option<InterruptInfo> {
+        let acknowledge_register_value = get_icc_iar1_el1() as u32;
+        let interrupt_number = acknowledge_register_value & 0xFFFFFF;
+        if (SPECIAL_INTERRUPT_START..1024).contains(&interrupt_number) {
+            None
+        } else {
+            Some(InterruptInfo {
+                acknowledge_register_value,
+                interrupt_number,
+            })
+        }
+    }
+
+    fn end_of_interrupt(&mut self, interrupt_info: InterruptInfo) {
+        set_icc_eoir1_el1(interrupt_info.acknowledge_register_value as u64);
+    }
+
+    fn enable_interrupt(&mut self, interrupt_number: u32) {
+        assert!(
+            self.interrupt_is_usable(interrupt_number),
+            "attempted to enable an interrupt that is not usable"
+        );
+        // SAFETY: we were created with addresses, which were required to be valid.
+        unsafe {
+            if interrupt_number < SHARED_INTERRUPT_START {
+                self.this_cpu_redistributor()
+                    .registers
+                    .at_offset::<u32>(REDISTRIBUTOR_SET_ENABLE_OFFSET)
+                    .write(1 << interrupt_number);
+            } else {
+                self.distributor_registers
+                    .at_offset::<u32>(
+                        DISTRIBUTOR_SET_ENABLE_OFFSET + ((interrupt_number / 32) as usize * 4),
+                    )
+                    .write(1 << (interrupt_number % 32));
+            }
+        }
+    }
+
+    fn disable_interrupt(&mut self, interrupt_number: u32) {
+        assert!(
+            self.interrupt_is_usable(interrupt_number),
+            "attempted to disable an interrupt that is not usable"
+        );
+        // SAFETY: we were created with addresses, which were required to be valid.
+        unsafe {
+            if interrupt_number < SHARED_INTERRUPT_START {
+                let registers = &mut self.this_cpu_redistributor().registers;
+                registers
+                    .at_offset::<u32>(REDISTRIBUTOR_CLEAR_ENABLE_OFFSET)
+                    .write(1 << interrupt_number);
+                wait_for_redistributor_writes(registers);
+            } else {
+                self.distributor_registers
+                    .at_offset::<u32>(
+                        DISTRIBUTOR_CLEAR_ENABLE_OFFSET + ((interrupt_number / 32) as usize * 4),
+                    )
+                    .write(1 << (interrupt_number % 32));
+                wait_for_distributor_writes(&self.distributor_registers);
+            }
+        }
+    }
+
+    fn configure_interrupt(
+        &mut self,
+        interrupt_number: u32,
+        edge_triggered: bool,
+        priority: Priority,
+    ) {
+        assert!(
+            self.interrupt_is_usable(interrupt_number),
+            "attempted to configure an interrupt that is not usable"
+        );
+        // SAFETY: we were created with addresses, which were required to be valid.
+        unsafe {
+            if interrupt_number < SHARED_INTERRUPT_START {
+                let registers = &mut self.this_cpu_redistributor().registers;
+                registers
+                    .at_offset::<u8>(REDISTRIBUTOR_PRIORITY_OFFSET + interrupt_number as usize)
+                    .write(priority_value(priority));
+                set_edge_triggered(
+                    registers,
+                    REDISTRIBUTOR_INTERRUPT_CONFIGURATION_OFFSET,
+                    interrupt_number,
+                    edge_triggered,
+                );
+            } else {
+                let affinity = affinity(get_mpidr());
+                let registers = &mut self.distributor_registers;
+                registers
+                    .at_offset::<u8>(DISTRIBUTOR_PRIORITY_OFFSET + interrupt_number as usize)
+                    .write(priority_value(priority));
+                set_edge_triggered(
+                    registers,
+                    DISTRIBUTOR_INTERRUPT_CONFIGURATION_OFFSET,
+                    interrupt_number,
+                    edge_triggered,
+                );
+                // Shared interrupts go to the CPU which configured them.
+                registers
+                    .at_offset::<u64>(DISTRIBUTOR_ROUTING_OFFSET + interrupt_number as usize * 8)
+                    .write(routing_register_value(affinity));
+            }
+        }
+    }
+
+    fn interrupt_is_usable(&self, interrupt_number: u32) -> bool {
+        (PRIVATE_INTERRUPT_START..self.interrupt_line_count).contains(&interrupt_number)
+    }
+
+    fn enable_interrupts_for_this_cpu(&mut self) {
+        let registers = &mut self.this_cpu_redistributor().registers;
+        // SAFETY: we were created with addresses, which were required to be valid.
+        unsafe {
+            // The redistributor has to be woken up before it passes on any interrupts.
+            let wake_register = registers.at_offset::<u32>(REDISTRIBUTOR_WAKE_OFFSET).read();
+            registers
+                .at_offset::<u32>(REDISTRIBUTOR_WAKE_OFFSET)
+                .write(wake_register & !REDISTRIBUTOR_WAKE_PROCESSOR_SLEEP);
+            while registers.at_offset::<u32>(REDISTRIBUTOR_WAKE_OFFSET).read()
+                & REDISTRIBUTOR_WAKE_CHILDREN_ASLEEP
+                != 0
+            {
+                core::hint::spin_loop();
+            }
+
+            registers
+                .at_offset::<u32>(REDISTRIBUTOR_GROUP_OFFSET)
+                .write(0xFFFFFFFF);
+            registers
+                .at_offset::<u32>(REDISTRIBUTOR_CLEAR_ENABLE_OFFSET)
+                .write(0xFFFFFFFF);
+            wait_for_redistributor_writes(registers);
+        }
+
+        // The CPU interface is used through system registers instead of memory, once they are enabled.
+        set_icc_sre_el1(get_icc_sre_el1() | ICC_SRE_ENABLE);
+        isb();
+        // As with GICv2, this allows all low-priority and above interrupts.
+        set_icc_pmr_el1(LOW_PRIORITY as u64 + 0x10);
+        set_icc_igrpen1_el1(1);
+        isb();
+    }
+}
@@ -2,6 +2,8 @@ pub mod arch_api;
 mod asm;
 mod exceptions;
 mod gicv2;
+mod gicv3;
+mod psci;
 mod registers;
 
 #[path = "acpi/gtdt.rs"]
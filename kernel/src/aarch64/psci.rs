@@ -0,0 +1,123 @@
+//! The Power State Coordination Interface, which firmware (or a hypervisor) provides for turning CPUs on and off.
+
+use core::arch::asm;
+
+use crate::acpi::fadt::{ArmBootFlags, FadtInfo};
+
+const PSCI_CPU_ON: u32 = 0xC400_0003;
+
+/// How calls get to the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    /// Secure monitor call, for firmware at EL3.
+    Smc,
+    /// Hypervisor call, for firmware (or a hypervisor) at EL2.
+    Hvc,
+}
+
+impl Conduit {
+    /// Which conduit the FADT says to use, or `None` if there is no PSCI.
+    pub fn from_fadt(fadt: &FadtInfo) -> Option<Self> {
+        if !fadt.arm_boot_flags.contains(ArmBootFlags::SUPPORTS_PSCI) {
+            None
+        } else if fadt.arm_boot_flags.contains(ArmBootFlags::MUST_USE_HVC) {
+            Some(Self::Hvc)
+        } else {
+            Some(Self::Smc)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i64),
+}
+
+impl PsciError {
+    fn from_return_value(value: i64) -> Result<(), Self> {
+        match value {
+            0 => Ok(()),
+            -1 => Err(Self::NotSupported),
+            -2 => Err(Self::InvalidParameters),
+            -3 => Err(Self::Denied),
+            -4 => Err(Self::AlreadyOn),
+            -5 => Err(Self::OnPending),
+            -6 => Err(Self::InternalFailure),
+            -7 => Err(Self::NotPresent),
+            -8 => Err(Self::Disabled),
+            -9 => Err(Self::InvalidAddress),
+            value => Err(Self::Unknown(value)),
+        }
+    }
+}
+
+/// # Safety
+/// The function and its arguments can do just about anything to the system (like turning it off).
+unsafe fn call(
+    conduit: Conduit,
+    function: u32,
+    argument1: u64,
+    argument2: u64,
+    argument3: u64,
+) -> i64 {
+    let result: i64;
+    // Older versions of the calling convention let the firmware overwrite everything up to x17.
+    // The instructions are written out as numbers since the assembler only accepts SMC when targeting EL3.
+    match conduit {
+        Conduit::Smc => asm!(
+            // smc #0
+            ".inst 0xd4000003",
+            inout("x0") function as u64 => result,
+            inout("x1") argument1 => _,
+            inout("x2") argument2 => _,
+            inout("x3") argument3 => _,
+            out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+            out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+            out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+            out("x16") _, out("x17") _,
+            options(nostack),
+        ),
+        Conduit::Hvc => asm!(
+            // hvc #0
+            ".inst 0xd4000002",
+            inout("x0") function as u64 => result,
+            inout("x1") argument1 => _,
+            inout("x2") argument2 => _,
+            inout("x3") argument3 => _,
+            out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+            out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+            out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+            out("x16") _, out("x17") _,
+            options(nostack),
+        ),
+    }
+    result
+}
+
+/// Turn on the CPU with the given affinity (from its MPIDR), which starts at the physical address `entry_point` at the current exception level, with its MMU off and `context` in x0.
+///
+/// # Safety
+/// There must be code at `entry_point` which can run with the MMU off, and the CPU must not already be in use.
+pub unsafe fn cpu_on(
+    conduit: Conduit,
+    target: u64,
+    entry_point: usize,
+    context: u64,
+) -> Result<(), PsciError> {
+    PsciError::from_return_value(call(
+        conduit,
+        PSCI_CPU_ON,
+        target,
+        entry_point as u64,
+        context,
+    ))
+}
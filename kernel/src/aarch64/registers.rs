@@ -8,6 +8,15 @@ pub fn get_esr() -> u64 {
     esr
 }
 
+/// Get the Fault Address Register, which holds the address that caused an abort.
+pub fn get_far() -> u64 {
+    let mut far: u64;
+    unsafe {
+        asm!("mrs {}, far_el1", out(reg) far, options(nomem, nostack));
+    }
+    far
+}
+
 pub fn get_cntfrq() -> u64 {
     let mut cntfrq: u64;
     unsafe {
@@ -35,3 +44,114 @@ pub fn set_cntv_cval(cntv_cval: u64) {
         asm!("msr cntv_cval_el0, {}", in(reg) cntv_cval, options(nomem, nostack));
     }
 }
+
+/// The multiprocessor affinity register, which identifies the current CPU.
+pub fn get_mpidr() -> u64 {
+    let mut mpidr: u64;
+    unsafe {
+        asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack));
+    }
+    mpidr
+}
+
+pub fn get_mair() -> u64 {
+    let mut mair: u64;
+    unsafe {
+        asm!("mrs {}, mair_el1", out(reg) mair, options(nomem, nostack));
+    }
+    mair
+}
+
+pub fn get_tcr() -> u64 {
+    let mut tcr: u64;
+    unsafe {
+        asm!("mrs {}, tcr_el1", out(reg) tcr, options(nomem, nostack));
+    }
+    tcr
+}
+
+pub fn get_ttbr1() -> u64 {
+    let mut ttbr1: u64;
+    unsafe {
+        asm!("mrs {}, ttbr1_el1", out(reg) ttbr1, options(nomem, nostack));
+    }
+    ttbr1
+}
+
+pub fn get_sctlr() -> u64 {
+    let mut sctlr: u64;
+    unsafe {
+        asm!("mrs {}, sctlr_el1", out(reg) sctlr, options(nomem, nostack));
+    }
+    sctlr
+}
+
+/// The architectural feature access control register, which (among other things) controls access to the floating point registers.
+pub fn get_cpacr() -> u64 {
+    let mut cpacr: u64;
+    unsafe {
+        asm!("mrs {}, cpacr_el1", out(reg) cpacr, options(nomem, nostack));
+    }
+    cpacr
+}
+
+/// The kernel's thread ID register, which (since user mode can't read it) holds the address of the current CPU's data.
+pub fn get_tpidr_el1() -> u64 {
+    let mut tpidr: u64;
+    unsafe {
+        asm!("mrs {}, tpidr_el1", out(reg) tpidr, options(nomem, nostack));
+    }
+    tpidr
+}
+
+/// # Safety
+/// Whatever is using the current value (see `get_tpidr_el1`) has to be done with it.
+pub unsafe fn set_tpidr_el1(tpidr: u64) {
+    asm!("msr tpidr_el1, {}", in(reg) tpidr, options(nomem, nostack));
+}
+
+// The GICv3 CPU interface registers are written with their encodings, since the assembler only knows their names with the GICv3 feature enabled.
+
+/// The system register enable register, which switches the GIC CPU interface over to the ICC registers.
+pub fn get_icc_sre_el1() -> u64 {
+    let mut icc_sre: u64;
+    unsafe {
+        asm!("mrs {}, s3_0_c12_c12_5", out(reg) icc_sre, options(nomem, nostack));
+    }
+    icc_sre
+}
+
+pub fn set_icc_sre_el1(icc_sre: u64) {
+    unsafe {
+        asm!("msr s3_0_c12_c12_5, {}", in(reg) icc_sre, options(nomem, nostack));
+    }
+}
+
+/// The priority mask register, which only lets through interrupts with a higher priority (a lower number) than it.
+pub fn set_icc_pmr_el1(icc_pmr: u64) {
+    unsafe {
+        asm!("msr s3_0_c4_c6_0, {}", in(reg) icc_pmr, options(nomem, nostack));
+    }
+}
+
+/// Enables group 1 interrupts, which are the ones the kernel uses.
+pub fn set_icc_igrpen1_el1(icc_igrpen1: u64) {
+    unsafe {
+        asm!("msr s3_0_c12_c12_7, {}", in(reg) icc_igrpen1, options(nomem, nostack));
+    }
+}
+
+/// Reading the interrupt acknowledge register acknowledges the highest priority pending interrupt, and gives its interrupt ID.
+pub fn get_icc_iar1_el1() -> u64 {
+    let mut icc_iar1: u64;
+    unsafe {
+        asm!("mrs {}, s3_0_c12_c12_0", out(reg) icc_iar1, options(nomem, nostack));
+    }
+    icc_iar1
+}
+
+pub fn set_icc_eoir1_el1(icc_eoir1: u64) {
+    unsafe {
+        asm!("msr s3_0_c12_c12_1, {}", in(reg) icc_eoir1, options(nomem, nostack));
+    }
+}
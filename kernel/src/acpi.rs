@@ -116,7 +116,7 @@ impl AcpiTableHandle {
 #[cfg(target_arch = "aarch64")]
 const REQUIRED_TABLES: &[&[u8; 4]] = &[b"APIC", b"GTDT", b"FACP"];
 #[cfg(target_arch = "x86_64")]
-const REQUIRED_TABLES: &[&[u8; 4]] = &[b"APIC", b"HPET"];
+const REQUIRED_TABLES: &[&[u8; 4]] = &[b"APIC", b"HPET", b"FACP"];
 
 #[derive(Debug)]
 pub enum AcpiTableSearchError {
@@ -14,7 +14,10 @@ use super::AcpiTableHandle;
 memory_struct! {
 struct FadtTableBody<'lifetime> {
     // TODO: fill out the rest of this structure when we need it (which may well be never, since we are only dealing with early initialization code).
-    unknown: ReservedMemory<73>,
+    unknown: ReservedMemory<70>,
+    _day_alarm: u8,
+    _month_alarm: u8,
+    century: u8,
     x86_boot_flags: u16,
     reserved: u8,
     fixed_flags: u32,
@@ -73,6 +76,8 @@ pub struct FadtInfo {
     pub x86_boot_flags: X86BootFlags,
     pub fixed_flags: FixedFlags,
     pub arm_boot_flags: ArmBootFlags,
+    /// The index of the CMOS register with the century in it, if there is one.
+    pub century_register: Option<u8>,
 }
 
 impl FadtInfo {
@@ -85,6 +90,10 @@ impl FadtInfo {
             x86_boot_flags: X86BootFlags::from_bits_retain(fadt_table_body.x86_boot_flags()),
             fixed_flags: FixedFlags::from_bits_retain(fadt_table_body.fixed_flags()),
             arm_boot_flags: ArmBootFlags::from_bits_retain(fadt_table_body.arm_boot_flags()),
+            century_register: match fadt_table_body.century() {
+                0 => None,
+                register => Some(register),
+            },
         }
     }
 }
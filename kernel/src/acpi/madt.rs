@@ -37,6 +37,8 @@ const MADT_ENTRY_TYPE_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
 const MADT_ENTRY_TYPE_NON_MASKABLE_INTERRUPT_SOURCE: u8 = 3;
 const MADT_ENTRY_TYPE_LOCAL_APIC_NMI: u8 = 4;
 const MADT_ENTRY_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_ENTRY_TYPE_LOCAL_X2APIC: u8 = 9;
+const MADT_ENTRY_TYPE_LOCAL_X2APIC_NMI: u8 = 0xa;
 
 const MADT_TYPE_GENERIC_INTERRUPT_CONTROLLER_CPU_INTERFACE: u8 = 0xb;
 const MADT_TYPE_GENERIC_INTERRUPT_CONTROLLER_DISTRIBUTOR: u8 = 0xc;
@@ -44,6 +46,8 @@ const MADT_TYPE_GENERIC_INTERRUPT_CONTROLLER_MSI_FRAME: u8 = 0xd;
 const MADT_TYPE_GENERIC_INTERRUPT_CONTROLLER_REDISTRIBUTOR: u8 = 0xe;
 const MADT_TYPE_GENERIC_INTERRUPT_CONTROLLER_TRANSLATION_SERVICE: u8 = 0xf;
 
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
 memory_struct! {
     struct LocalApicEntry<'lifetime> {
         madt_header: MadtEntryHeader<'lifetime>,
@@ -90,6 +94,26 @@ memory_struct! {
     }
 }
 
+memory_struct! {
+    struct LocalX2ApicEntry<'lifetime> {
+        madt_header: MadtEntryHeader<'lifetime>,
+        reserved: u16,
+        x2apic_id: u32,
+        flags: u32,
+        acpi_processor_uid: u32,
+    }
+}
+
+memory_struct! {
+    struct LocalX2ApicNmiEntry<'lifetime> {
+        madt_header: MadtEntryHeader<'lifetime>,
+        flags: u16,
+        acpi_processor_uid: u32,
+        local_x2apic_lint: u8,
+        reserved: ReservedMemory<3>,
+    }
+}
+
 memory_struct! {
     struct LocalApicAddressOverrideEntry<'lifetime> {
         madt_header: MadtEntryHeader<'lifetime>,
@@ -208,8 +232,20 @@ pub struct InterruptSourceOverrideInfo {
     pub flags: GeneralAPICInterruptFlags,
 }
 
+#[derive(Debug)]
+pub struct LocalApicInfo {
+    pub apic_id: u32,
+    /// How the NMI entries refer to the processor.
+    pub acpi_processor_uid: u32,
+}
+
+/// The processor UID in an NMI entry which means that it applies to every processor.
+pub const ALL_PROCESSORS: u32 = u32::MAX;
+
 #[derive(Debug)]
 pub struct LocalApicNmiInfo {
+    /// Either the UID of the processor it applies to, or `ALL_PROCESSORS`.
+    pub acpi_processor_uid: u32,
     pub flags: GeneralAPICInterruptFlags,
     pub local_apic_lint: u8,
 }
@@ -220,6 +256,7 @@ pub struct GenericInterruptControllerCpuInterfaceInfo {
     pub base_address: u64,
     pub mp_id_register: u64,
     pub efficiency_class: u8,
+    pub flags: u32,
 }
 
 #[derive(Debug)]
@@ -239,7 +276,8 @@ pub struct MadtInfo {
     pub local_interrupt_controller_address: u64,
     pub flags: u32,
 
-    pub local_apic_ids: Vec<u8>,
+    /// Both xAPIC and x2APIC entries (since CPUs with IDs above 254 only have x2APIC entries), for the CPUs which are enabled.
+    pub local_apic_entries: Vec<LocalApicInfo>,
     pub io_apic_entries: Vec<IoApicInfo>,
     pub interrupt_source_override_entries: Vec<InterruptSourceOverrideInfo>,
     pub local_apic_nmi_entries: Vec<LocalApicNmiInfo>,
@@ -276,7 +314,13 @@ impl MadtInfo {
                 MADT_ENTRY_TYPE_LOCAL_APIC => {
                     let entry = LocalApicEntry::from_bytes(Endianness::Little, value_memory)
                         .expect("Invalid MADT entry");
-                    result.local_apic_ids.push(entry.apic_id());
+                    // CPUs which are only online capable have to be hot-added before they can be started, and the rest are just placeholders.
+                    if entry.flags() & LOCAL_APIC_ENABLED != 0 {
+                        result.local_apic_entries.push(LocalApicInfo {
+                            apic_id: entry.apic_id() as u32,
+                            acpi_processor_uid: entry.acpi_id() as u32,
+                        });
+                    }
                 }
                 MADT_ENTRY_TYPE_IO_APIC => {
                     let entry = IoApicEntry::from_bytes(Endianness::Little, value_memory)
@@ -306,10 +350,34 @@ impl MadtInfo {
                     let entry = LocalApicNmiEntry::from_bytes(Endianness::Little, value_memory)
                         .expect("Invalid MADT entry");
                     result.local_apic_nmi_entries.push(LocalApicNmiInfo {
+                        // The 8-bit processor IDs use 0xff for all of them.
+                        acpi_processor_uid: match entry.acpi_processor_id() {
+                            0xff => ALL_PROCESSORS,
+                            acpi_processor_id => acpi_processor_id as u32,
+                        },
                         flags: GeneralAPICInterruptFlags::from_bits_retain(entry.flags()),
                         local_apic_lint: entry.local_apic_lint(),
                     });
                 }
+                MADT_ENTRY_TYPE_LOCAL_X2APIC => {
+                    let entry = LocalX2ApicEntry::from_bytes(Endianness::Little, value_memory)
+                        .expect("Invalid MADT entry");
+                    if entry.flags() & LOCAL_APIC_ENABLED != 0 {
+                        result.local_apic_entries.push(LocalApicInfo {
+                            apic_id: entry.x2apic_id(),
+                            acpi_processor_uid: entry.acpi_processor_uid(),
+                        });
+                    }
+                }
+                MADT_ENTRY_TYPE_LOCAL_X2APIC_NMI => {
+                    let entry = LocalX2ApicNmiEntry::from_bytes(Endianness::Little, value_memory)
+                        .expect("Invalid MADT entry");
+                    result.local_apic_nmi_entries.push(LocalApicNmiInfo {
+                        acpi_processor_uid: entry.acpi_processor_uid(),
+                        flags: GeneralAPICInterruptFlags::from_bits_retain(entry.flags()),
+                        local_apic_lint: entry.local_x2apic_lint(),
+                    });
+                }
                 MADT_ENTRY_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE => {
                     let entry =
                         LocalApicAddressOverrideEntry::from_bytes(Endianness::Little, value_memory)
@@ -330,6 +398,7 @@ impl MadtInfo {
                                 base_address: entry.base_address(),
                                 mp_id_register: entry.multiprocessing_id(),
                                 efficiency_class: entry.processor_efficiency(),
+                                flags: entry.flags(),
                             });
                     } else {
                         let entry = GenericInterruptControllerCpuInterfaceEntry76::from_bytes(
@@ -344,6 +413,7 @@ impl MadtInfo {
                                 base_address: entry.base_address(),
                                 mp_id_register: entry.multiprocessing_id(),
                                 efficiency_class: 0,
+                                flags: entry.flags(),
                             });
                     }
                 }
@@ -0,0 +1,122 @@
+//! Channels are pairs of connected endpoints, which programs use to send each other messages (and handles).
+
+use alloc::{
+    collections::VecDeque,
+    rc::{Rc, Weak},
+    vec::Vec,
+};
+use core::{cell::RefCell, ptr};
+
+use common::syscall::{SyscallError, MAX_QUEUED_MESSAGES};
+
+use crate::{
+    handle::Handle,
+    scheduler::{self, Waiter},
+};
+
+pub struct Message {
+    pub data: Vec<u8>,
+    pub handles: Vec<Handle>,
+}
+
+/// One end of a channel. Messages sent from the other end are queued here until they are received.
+pub struct ChannelEndpoint {
+    messages: VecDeque<Message>,
+    /// This doesn't keep the other end alive, so that it can tell when it has been closed.
+    peer: Weak<RefCell<ChannelEndpoint>>,
+    /// Threads which are blocked until a message arrives (or the other end is closed).
+    waiting_threads: Vec<Waiter>,
+}
+
+impl ChannelEndpoint {
+    fn new(peer: Weak<RefCell<ChannelEndpoint>>) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            peer,
+            waiting_threads: Vec::new(),
+        }
+    }
+
+    fn wake_waiting_threads(&mut self) {
+        for waiter in self.waiting_threads.drain(..) {
+            scheduler::wake_thread(waiter);
+        }
+    }
+
+    /// Check whether `send` would succeed, so that the handles in a message don't have to be taken until we know.
+    pub fn check_can_send(&self) -> Result<(), SyscallError> {
+        let peer = self.peer.upgrade().ok_or(SyscallError::PeerClosed)?;
+        if peer.borrow().messages.len() >= MAX_QUEUED_MESSAGES {
+            return Err(SyscallError::WouldBlock);
+        }
+        Ok(())
+    }
+
+    /// Put a message in the queue at the other end.
+    pub fn send(&self, message: Message) -> Result<(), SyscallError> {
+        self.check_can_send()?;
+        let peer = self.peer.upgrade().unwrap();
+        let mut peer = peer.borrow_mut();
+        peer.messages.push_back(message);
+        peer.wake_waiting_threads();
+        Ok(())
+    }
+
+    /// The message which will be received next, if there is one.
+    ///
+    /// Fails with `PeerClosed` if there aren't any messages, and never will be.
+    pub fn next_message(&self) -> Result<Option<&Message>, SyscallError> {
+        match self.messages.front() {
+            Some(message) => Ok(Some(message)),
+            None if self.peer.strong_count() == 0 => Err(SyscallError::PeerClosed),
+            None => Ok(None),
+        }
+    }
+
+    pub fn take_next_message(&mut self) -> Option<Message> {
+        self.messages.pop_front()
+    }
+
+    /// Whether `other` is the other end of this channel.
+    pub fn is_peer(&self, other: &Rc<RefCell<ChannelEndpoint>>) -> bool {
+        ptr::eq(self.peer.as_ptr(), Rc::as_ptr(other))
+    }
+
+    /// Wake `waiter` up when a message arrives.
+    pub fn add_waiting_thread(&mut self, waiter: Waiter) {
+        self.waiting_threads
+            .retain(|&waiter| scheduler::is_still_waiting(waiter));
+        self.waiting_threads.push(waiter);
+    }
+}
+
+impl Drop for ChannelEndpoint {
+    fn drop(&mut self) {
+        // Anything waiting at the other end has to find out that there won't be any more messages.
+        if let Some(peer) = self.peer.upgrade() {
+            peer.borrow_mut().wake_waiting_threads();
+        }
+    }
+}
+
+pub fn create_channel() -> (Rc<RefCell<ChannelEndpoint>>, Rc<RefCell<ChannelEndpoint>>) {
+    let first = Rc::new(RefCell::new(ChannelEndpoint::new(Weak::new())));
+    let second = Rc::new(RefCell::new(ChannelEndpoint::new(Rc::downgrade(&first))));
+    first.borrow_mut().peer = Rc::downgrade(&second);
+    (first, second)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn is_peer_test() {
+        let (first, second) = create_channel();
+        let (third, _fourth) = create_channel();
+        assert!(first.borrow().is_peer(&second));
+        assert!(second.borrow().is_peer(&first));
+        assert!(!first.borrow().is_peer(&first));
+        assert!(!first.borrow().is_peer(&third));
+    }
+}
@@ -0,0 +1,47 @@
+//! The monotonic clock, which counts the time since the kernel started and never goes backwards.
+
+use core::time::Duration;
+
+use crate::arch_api::timer::{clock_frequency, read_clock};
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+fn ticks_to_nanoseconds(ticks: u64, frequency: u64) -> u64 {
+    // Multiplying first keeps the precision, and u128 is big enough that it can't overflow.
+    (ticks as u128 * NANOSECONDS_PER_SECOND / frequency as u128) as u64
+}
+
+/// The time since the clock was started (during boot), or zero if it hasn't been yet.
+pub fn monotonic_time() -> Duration {
+    let frequency = clock_frequency();
+    if frequency == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos(ticks_to_nanoseconds(read_clock(), frequency))
+}
+
+/// Spin until `duration` has passed, for when there is nothing else to be doing (like while starting the other CPUs).
+pub fn busy_wait(duration: Duration) {
+    let end = monotonic_time() + duration;
+    while monotonic_time() < end {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ticks_to_nanoseconds_test() {
+        assert_eq!(ticks_to_nanoseconds(0, 1_000_000), 0);
+        assert_eq!(ticks_to_nanoseconds(3, 1_000_000), 3_000);
+        assert_eq!(ticks_to_nanoseconds(1, 3), 333_333_333);
+        // A day of a 3GHz TSC would overflow a u64 if it was multiplied by a billion.
+        let day = 24 * 60 * 60;
+        assert_eq!(
+            ticks_to_nanoseconds(3_000_000_000 * day, 3_000_000_000),
+            day * 1_000_000_000
+        );
+    }
+}
@@ -1,10 +1,13 @@
-use core::fmt::Write;
+use core::fmt::{Arguments, Write};
 
 use alloc::boxed::Box;
 use common::font::get_character_dimensions;
 use common::framebuffer::get_screen_dimensions;
 
-use crate::{font_renderer, lazy_init::lazy_static};
+use crate::{
+    font_renderer,
+    spinlock::{InterruptSafeSpinLock, LockLevel},
+};
 
 pub fn get_console_dimensions() -> (usize, usize) {
     let screen_dimensions = get_screen_dimensions();
@@ -15,25 +18,42 @@ pub fn get_console_dimensions() -> (usize, usize) {
     )
 }
 
-lazy_static! {
-    static ref CONSOLE_BACKBUFFER: Box<[char]> = {
-        let console_dimensions = get_console_dimensions();
-        unsafe { Box::new_zeroed_slice(console_dimensions.0 * console_dimensions.1).assume_init() }
-    };
+/// Everything on the screen, along with where the next character goes.
+struct Console {
+    x: usize,
+    y: usize,
+    /// Made the first time something is printed, since the heap doesn't exist when the console does.
+    backbuffer: Option<Box<[char]>>,
 }
 
-static mut X: usize = 0;
-static mut Y: usize = 0;
+/// Printing takes this once for each `print!`, so that what different CPUs print doesn't get mixed together.
+static CONSOLE: InterruptSafeSpinLock<Console> = InterruptSafeSpinLock::new(
+    LockLevel::Console,
+    Console {
+        x: 0,
+        y: 0,
+        backbuffer: None,
+    },
+);
+
+impl Console {
+    fn backbuffer(&mut self) -> &mut [char] {
+        self.backbuffer.get_or_insert_with(|| {
+            let console_dimensions = get_console_dimensions();
+            unsafe {
+                Box::new_zeroed_slice(console_dimensions.0 * console_dimensions.1).assume_init()
+            }
+        })
+    }
 
-fn possibly_scroll() {
-    unsafe {
+    fn possibly_scroll(&mut self) {
         let console_dimensions = get_console_dimensions();
-        if Y >= console_dimensions.1 {
+        if self.y >= console_dimensions.1 {
+            let backbuffer = self.backbuffer();
             for row in 1..console_dimensions.1 {
-                let source = &CONSOLE_BACKBUFFER
-                    [row * console_dimensions.0..(row + 1) * console_dimensions.0];
-                let destination = &mut CONSOLE_BACKBUFFER
-                    [(row - 1) * console_dimensions.0..row * console_dimensions.0];
+                let (destination, source) = backbuffer
+                    [(row - 1) * console_dimensions.0..(row + 1) * console_dimensions.0]
+                    .split_at_mut(console_dimensions.0);
                 let source_line_length = source
                     .iter()
                     .take_while(|&&character| character != '\n')
@@ -66,66 +86,77 @@ fn possibly_scroll() {
             }
             // Now clear out the last row.
             for x in 0..console_dimensions.0 {
-                CONSOLE_BACKBUFFER[(console_dimensions.1 - 1) * console_dimensions.0 + x] = ' ';
+                backbuffer[(console_dimensions.1 - 1) * console_dimensions.0 + x] = ' ';
                 font_renderer::draw_character(
                     ' ',
                     x * get_character_dimensions().0,
                     (console_dimensions.1 - 1) * get_character_dimensions().1,
                 );
             }
-            Y -= 1;
-            X = 0;
+            self.y -= 1;
+            self.x = 0;
         }
     }
-}
 
-pub fn write_character(character: char) {
-    let x = unsafe { X };
-    let y = unsafe { Y };
-    unsafe {
-        CONSOLE_BACKBUFFER[x + y * get_console_dimensions().0] = character;
-    }
-    if character == '\n' {
-        unsafe {
-            X = 0;
-            Y += 1;
-            possibly_scroll();
-        }
-    } else {
-        font_renderer::draw_character(
-            character,
-            x * get_character_dimensions().0,
-            y * get_character_dimensions().1,
-        );
-        unsafe {
-            X += 1;
-            if X >= get_console_dimensions().0 {
-                X = 0;
-                Y += 1;
-                possibly_scroll();
+    fn write_character(&mut self, character: char) {
+        let (x, y) = (self.x, self.y);
+        self.backbuffer()[x + y * get_console_dimensions().0] = character;
+        if character == '\n' {
+            self.x = 0;
+            self.y += 1;
+            self.possibly_scroll();
+        } else {
+            font_renderer::draw_character(
+                character,
+                x * get_character_dimensions().0,
+                y * get_character_dimensions().1,
+            );
+            self.x += 1;
+            if self.x >= get_console_dimensions().0 {
+                self.x = 0;
+                self.y += 1;
+                self.possibly_scroll();
             }
         }
     }
 }
 
-pub fn write_string(string: &str) {
-    for character in string.chars() {
-        write_character(character);
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for character in s.chars() {
+            self.write_character(character);
+        }
+        Ok(())
     }
 }
 
-pub struct ConsoleWriter;
+pub fn write_fmt(arguments: Arguments) {
+    CONSOLE.lock().write_fmt(arguments).unwrap();
+}
 
-impl Write for ConsoleWriter {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        write_string(s);
-        Ok(())
+/// Print only if nothing else is printing, returning whether it did.
+/// This is for code which can interrupt anything (like an NMI handler), where waiting could mean waiting for itself.
+pub fn try_write_fmt(arguments: Arguments) -> bool {
+    match CONSOLE.try_lock() {
+        Some(mut console) => {
+            console.write_fmt(arguments).unwrap();
+            true
+        }
+        None => false,
     }
 }
 
+/// Let the panic handler print, even if the panic happened while something was printing.
+///
+/// # Safety
+/// Nothing which was printing can carry on afterwards.
+pub unsafe fn force_unlock() {
+    CONSOLE.force_unlock();
+}
+
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => (::core::fmt::Write::write_fmt(&mut $crate::console::ConsoleWriter, format_args!($($arg)*)).unwrap());
+    ($($arg:tt)*) => ($crate::console::write_fmt(format_args!($($arg)*)));
 }
 
 pub use print;
@@ -1,14 +1,14 @@
 use core::slice;
 
-use common::elf::{ElfBinary, LoadableSegment};
-
-use crate::{
-    paging::{change_block_permissions, MemoryType, PagePermissions},
-    user_memory::allocate_user_memory_at,
+use common::{
+    elf::{ElfBinary, LoadableSegment},
+    syscall::SyscallError,
 };
 
+use crate::{paging::PagePermissions, user_memory::AddressSpace};
+
 /// # Safety
-/// If the virtual address of the segment hasn't been mapped, this will do something weird (probably page fault, but who knows).
+/// If the virtual address of the segment hasn't been reserved in the active address space, this will do something weird (probably page fault, but who knows).
 unsafe fn copy_elf_section(loadable_segment: &LoadableSegment, file: &[u8]) {
     // TODO: There must be a cleaner way than this.
     let bytes = unsafe {
@@ -21,22 +21,49 @@ unsafe fn copy_elf_section(loadable_segment: &LoadableSegment, file: &[u8]) {
         &file[loadable_segment.file_offset
             ..loadable_segment.file_offset + loadable_segment.size_in_file],
     );
-    bytes[loadable_segment.size_in_file..].fill(0);
+    // The rest (like the BSS) is already zero, since user memory is zeroed when it is first used.
+}
+
+/// Check that a segment is all in the file, and can go where it asks to (which has to be block aligned, and not overlap anything already there).
+fn is_valid_segment(
+    address_space: &AddressSpace,
+    loadable_segment: &LoadableSegment,
+    file: &[u8],
+) -> bool {
+    loadable_segment.size_in_file <= loadable_segment.size_in_memory
+        && loadable_segment
+            .file_offset
+            .checked_add(loadable_segment.size_in_file)
+            .is_some_and(|end| end <= file.len())
+        && address_space.can_reserve(
+            loadable_segment.virtual_address,
+            loadable_segment.size_in_memory,
+        )
 }
 
-pub fn map_sections(elf: &ElfBinary, file: &[u8]) {
+/// Load the segments of the ELF file into the given address space.
+///
+/// Fails with `InvalidExecutable` if any of them aren't valid, in which case some of the segments might have been loaded already.
+pub fn map_sections(
+    address_space: &mut AddressSpace,
+    elf: &ElfBinary,
+    file: &[u8],
+) -> Result<(), SyscallError> {
     for loadable_segment in &elf.loadable_segments {
-        allocate_user_memory_at(
+        if !is_valid_segment(address_space, loadable_segment, file) {
+            return Err(SyscallError::InvalidExecutable);
+        }
+        address_space.reserve_memory_at(
             loadable_segment.virtual_address,
             loadable_segment.size_in_memory,
             PagePermissions::KERNEL_READ_WRITE, // Allows us to write the contents first.
         );
-        unsafe { copy_elf_section(loadable_segment, file) };
+        address_space.with_active(|_| unsafe { copy_elf_section(loadable_segment, file) });
         // Now set the permissions
-        change_block_permissions(
+        address_space.change_permissions(
             loadable_segment.virtual_address,
-            MemoryType::Normal,
             PagePermissions::new(true, loadable_segment.writable, loadable_segment.executable),
         );
     }
+    Ok(())
 }
@@ -34,10 +34,10 @@ pub fn draw_character(character: char, x: usize, y: usize) {
         return;
     }
     let mut character_cache_offset = get_character_cache_offset(character as usize);
-    if character_cache_offset >= unsafe { CHARACTER_CACHE.len() } {
+    if character_cache_offset >= CHARACTER_CACHE.len() {
         character_cache_offset = get_character_cache_offset(0);
     }
-    let character_cache = unsafe { &CHARACTER_CACHE[character_cache_offset..] };
+    let character_cache = &CHARACTER_CACHE[character_cache_offset..];
     let bytes_per_pixel = get_bytes_per_pixel();
     for row in 0..character_height {
         let row_pixel_cache = &character_cache[row * character_width * bytes_per_pixel
@@ -0,0 +1,47 @@
+//! Futexes let user programs block until another thread changes a value in memory, so they can build locks without spinning.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+
+use crate::scheduler::{self, Waiter};
+
+// SAFETY: Only used while handling system calls, with interrupts disabled, and only the boot CPU runs user threads.
+/// The threads waiting on each futex, keyed by physical address so that shared memory works.
+static mut WAITING_THREADS: BTreeMap<usize, VecDeque<Waiter>> = BTreeMap::new();
+
+/// Add a thread to the queue for the futex at `physical_address`.
+///
+/// The caller has to block it.
+pub fn add_waiting_thread(physical_address: usize, waiter: Waiter) {
+    unsafe {
+        let queue = WAITING_THREADS.entry(physical_address).or_default();
+        // Threads which timed out would otherwise stay here until the futex is woken.
+        queue.retain(|&waiter| scheduler::is_still_waiting(waiter));
+        queue.push_back(waiter);
+    }
+}
+
+/// Wake up to `count` threads waiting on the futex at `physical_address`, returning how many were woken.
+pub fn wake(physical_address: usize, count: usize) -> usize {
+    unsafe {
+        let Some(queue) = WAITING_THREADS.get_mut(&physical_address) else {
+            return 0;
+        };
+        let mut woken = Vec::new();
+        while woken.len() < count {
+            let Some(waiter) = queue.pop_front() else {
+                break;
+            };
+            // Threads which timed out are still in the queue, but waking them does nothing.
+            if scheduler::wake_thread_with_result(waiter, Ok(0)) {
+                woken.push(waiter);
+            }
+        }
+        if queue.is_empty() {
+            WAITING_THREADS.remove(&physical_address);
+        }
+        woken.len()
+    }
+}
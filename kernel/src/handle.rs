@@ -0,0 +1,182 @@
+//! Handles are how user programs refer to kernel objects.
+//!
+//! Each process has its own table, so a handle is just a small number which means nothing to any other process.
+//! Programs can only get handles from the kernel (by creating objects or receiving them), so they can't forge access to objects which they weren't given.
+
+use alloc::{collections::BTreeMap, rc::Rc, vec::Vec};
+use bitflags::bitflags;
+use core::cell::RefCell;
+
+use common::syscall::{
+    SyscallError, ALL_RIGHTS, RIGHT_DUPLICATE, RIGHT_READ, RIGHT_TRANSFER, RIGHT_WRITE,
+};
+
+use crate::{channel::ChannelEndpoint, memory_object::MemoryObject, process::Process};
+
+/// Something in the kernel which a process can refer to with a handle.
+///
+/// Objects are reference counted, and are destroyed when the last handle (or other reference) goes away.
+#[derive(Clone)]
+pub enum KernelObject {
+    Process(Rc<RefCell<Process>>),
+    Channel(Rc<RefCell<ChannelEndpoint>>),
+    MemoryObject(Rc<MemoryObject>),
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Rights: u64 {
+        const READ = RIGHT_READ;
+        const WRITE = RIGHT_WRITE;
+        const DUPLICATE = RIGHT_DUPLICATE;
+        const TRANSFER = RIGHT_TRANSFER;
+        const ALL = ALL_RIGHTS;
+    }
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    pub object: KernelObject,
+    pub rights: Rights,
+}
+
+pub struct HandleTable {
+    handles: BTreeMap<u64, Handle>,
+    next_handle: u64,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self {
+            handles: BTreeMap::new(),
+            // 0 is never a valid handle, so that it can be used to mean "nothing".
+            next_handle: 1,
+        }
+    }
+
+    pub fn add(&mut self, handle: Handle) -> u64 {
+        let value = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(value, handle);
+        value
+    }
+
+    /// Get the object behind a handle, as long as the handle has all of `required_rights`.
+    pub fn get(&self, handle: u64, required_rights: Rights) -> Result<&KernelObject, SyscallError> {
+        let handle = self
+            .handles
+            .get(&handle)
+            .ok_or(SyscallError::InvalidHandle)?;
+        if !handle.rights.contains(required_rights) {
+            return Err(SyscallError::AccessDenied);
+        }
+        Ok(&handle.object)
+    }
+
+    /// Make a new handle to the same object, with (at most) the same rights.
+    pub fn duplicate(&mut self, handle: u64, rights: Rights) -> Result<u64, SyscallError> {
+        let existing = self
+            .handles
+            .get(&handle)
+            .ok_or(SyscallError::InvalidHandle)?;
+        if !existing.rights.contains(Rights::DUPLICATE) || !existing.rights.contains(rights) {
+            return Err(SyscallError::AccessDenied);
+        }
+        let object = existing.object.clone();
+        Ok(self.add(Handle { object, rights }))
+    }
+
+    pub fn remove(&mut self, handle: u64) -> Result<Handle, SyscallError> {
+        self.handles
+            .remove(&handle)
+            .ok_or(SyscallError::InvalidHandle)
+    }
+
+    /// Check that all of the handles can be given to another process, and that none of them are repeated.
+    pub fn validate_transfer(&self, handles: &[u64]) -> Result<(), SyscallError> {
+        for (index, handle) in handles.iter().enumerate() {
+            if handles[..index].contains(handle) {
+                return Err(SyscallError::InvalidHandle);
+            }
+            self.get(*handle, Rights::TRANSFER)?;
+        }
+        Ok(())
+    }
+
+    /// Take the handles out of the table (to give them to another process).
+    ///
+    /// If any of them can't be transferred, none of them are removed.
+    pub fn remove_for_transfer(&mut self, handles: &[u64]) -> Result<Vec<Handle>, SyscallError> {
+        self.validate_transfer(handles)?;
+        Ok(handles
+            .iter()
+            .map(|handle| self.handles.remove(handle).unwrap())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::create_channel;
+
+    fn channel_handle(rights: Rights) -> Handle {
+        Handle {
+            object: KernelObject::Channel(create_channel().0),
+            rights,
+        }
+    }
+
+    #[test]
+    fn rights_test() {
+        let mut table = HandleTable::new();
+        let read_only = table.add(channel_handle(Rights::READ));
+        assert!(table.get(read_only, Rights::READ).is_ok());
+        assert_eq!(
+            table.get(read_only, Rights::WRITE).err(),
+            Some(SyscallError::AccessDenied)
+        );
+        assert_eq!(
+            table.get(read_only + 1, Rights::empty()).err(),
+            Some(SyscallError::InvalidHandle)
+        );
+        assert_eq!(
+            table.duplicate(read_only, Rights::READ).err(),
+            Some(SyscallError::AccessDenied)
+        );
+
+        let full = table.add(channel_handle(Rights::ALL));
+        let duplicate = table.duplicate(full, Rights::READ).unwrap();
+        assert!(table.get(duplicate, Rights::READ).is_ok());
+        assert_eq!(
+            table.duplicate(duplicate, Rights::READ).err(),
+            Some(SyscallError::AccessDenied)
+        );
+        assert!(table.remove(full).is_ok());
+        assert!(table.get(duplicate, Rights::READ).is_ok());
+        assert_eq!(table.remove(full).err(), Some(SyscallError::InvalidHandle));
+    }
+
+    #[test]
+    fn transfer_test() {
+        let mut table = HandleTable::new();
+        let transferable = table.add(channel_handle(Rights::ALL));
+        let stuck = table.add(channel_handle(Rights::ALL - Rights::TRANSFER));
+        assert_eq!(
+            table.remove_for_transfer(&[transferable, stuck]).err(),
+            Some(SyscallError::AccessDenied)
+        );
+        assert_eq!(
+            table
+                .remove_for_transfer(&[transferable, transferable])
+                .err(),
+            Some(SyscallError::InvalidHandle)
+        );
+        // Nothing should have been removed by the failed transfers.
+        assert!(table.get(transferable, Rights::empty()).is_ok());
+        let transferred = table.remove_for_transfer(&[transferable]).unwrap();
+        assert_eq!(transferred.len(), 1);
+        assert_eq!(transferred[0].rights, Rights::ALL);
+        assert!(table.get(transferable, Rights::empty()).is_err());
+    }
+}
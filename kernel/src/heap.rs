@@ -2,7 +2,7 @@ use core::{
     alloc::GlobalAlloc,
     mem::size_of,
     ops::{Deref, DerefMut},
-    ptr::null_mut,
+    ptr::{addr_of_mut, null_mut},
 };
 
 use alloc::boxed::Box;
@@ -14,6 +14,7 @@ use crate::{
     memory::align_address_up,
     paging::{get_physical_address, map_block, unmap_block, MemoryType, PagePermissions},
     physical_memory_manager::{self, mark_as_free, BLOCK_SIZE, LOG2_BLOCK_SIZE},
+    spinlock::{InterruptSafeSpinLock, LockLevel},
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -36,15 +37,15 @@ const_assert!(
 );
 
 lazy_static! {
-    static ref HEAP_VIRTUAL_MEMORY_ALLOCATOR: &mut BuddyAllocator<256, LOG2_HEAP_SIZE, LOG2_BLOCK_SIZE> = {
+    static ref HEAP_VIRTUAL_MEMORY_ALLOCATOR: InterruptSafeSpinLock<&'static mut BuddyAllocator<256, LOG2_HEAP_SIZE, LOG2_BLOCK_SIZE>> = {
         static mut REAL_ALLOCATOR: BuddyAllocator<256, LOG2_HEAP_SIZE, LOG2_BLOCK_SIZE> =
             BuddyAllocator::unusable();
-        unsafe {
-            REAL_ALLOCATOR
-                .all_unused()
-                .add_entry(HEAP_SIZE, VIRTUAL_HEAP_START);
-            &mut REAL_ALLOCATOR
-        }
+        // SAFETY: This only runs once, and after that the allocator is only used through the lock.
+        let allocator = unsafe { &mut *addr_of_mut!(REAL_ALLOCATOR) };
+        allocator
+            .all_unused()
+            .add_entry(HEAP_SIZE, VIRTUAL_HEAP_START);
+        InterruptSafeSpinLock::new(LockLevel::HeapVirtualMemory, allocator)
     };
 }
 
@@ -75,14 +76,14 @@ struct SlabAllocator {
     // The empty ones are removed immediately and so are the full ones, so we just need to keep track of the partials.
 }
 
-// TODO: Add locking to the allocator so that this is actually safe.
-unsafe impl Sync for SlabAllocator {}
+// SAFETY: The entry lists are only ever touched through the lock around the allocator.
+unsafe impl Send for SlabAllocator {}
 
 struct HeapAllocator;
 
 #[cfg(not(test))]
 #[global_allocator]
-static mut GLOBAL_ALLOCATOR: HeapAllocator = HeapAllocator {};
+static GLOBAL_ALLOCATOR: HeapAllocator = HeapAllocator {};
 
 impl SlabAllocator {
     const fn new() -> Self {
@@ -92,34 +93,32 @@ impl SlabAllocator {
     }
 
     fn allocate_entry_list<const SIZE: usize>() -> *mut SlabEntry<SIZE> {
-        unsafe {
-            // Rust doesn't let us use any kind of allocator api or anything, so this is the best I can think of.
-            // It is a bit of repetition, but it's not too bad.
-            let virtual_address = HEAP_VIRTUAL_MEMORY_ALLOCATOR.allocate(BLOCK_SIZE);
-            if let Some(virtual_address) = virtual_address {
-                let physical_address = physical_memory_manager::allocate_block_address();
-                if let Some(physical_address) = physical_address {
-                    map_block(
-                        virtual_address,
-                        physical_address,
-                        MemoryType::Normal,
-                        PagePermissions::KERNEL_READ_WRITE,
-                    );
-                    return virtual_address as *mut SlabEntry<SIZE>;
-                }
+        // Rust doesn't let us use any kind of allocator api or anything, so this is the best I can think of.
+        // It is a bit of repetition, but it's not too bad.
+        let virtual_address = HEAP_VIRTUAL_MEMORY_ALLOCATOR.lock().allocate(BLOCK_SIZE);
+        if let Some(virtual_address) = virtual_address {
+            let physical_address = physical_memory_manager::allocate_block_address();
+            if let Some(physical_address) = physical_address {
+                map_block(
+                    virtual_address,
+                    physical_address,
+                    MemoryType::Normal,
+                    PagePermissions::KERNEL_READ_WRITE,
+                );
+                return virtual_address as *mut SlabEntry<SIZE>;
             }
-            panic!("Out of memory allocating slab entry block");
         }
+        panic!("Out of memory allocating slab entry block");
     }
 
     fn free_entry_list<const SIZE: usize>(entry_list: *mut SlabEntry<SIZE>) {
-        unsafe {
-            let virtual_address = entry_list as usize;
-            let physical_address = get_physical_address(virtual_address);
-            unmap_block(virtual_address);
-            mark_as_free(physical_address);
-            HEAP_VIRTUAL_MEMORY_ALLOCATOR.free(BLOCK_SIZE, virtual_address);
-        }
+        let virtual_address = entry_list as usize;
+        let physical_address = get_physical_address(virtual_address);
+        unmap_block(virtual_address);
+        mark_as_free(physical_address);
+        HEAP_VIRTUAL_MEMORY_ALLOCATOR
+            .lock()
+            .free(BLOCK_SIZE, virtual_address);
     }
 
     /// This function is to initialize the head entry of the list and assumes that there were no entries before (so it is only really useful for creating an entry when the list is empty).
@@ -224,7 +223,9 @@ impl SlabAllocator {
     }
 }
 
-static mut SLAB_ALLOCATOR: SlabAllocator = SlabAllocator::new();
+/// The slab allocator takes the locks for the virtual and physical memory (and the page tables) while it is locked itself, whenever it needs a new entry list.
+static SLAB_ALLOCATOR: InterruptSafeSpinLock<SlabAllocator> =
+    InterruptSafeSpinLock::new(LockLevel::Heap, SlabAllocator::new());
 
 unsafe impl GlobalAlloc for HeapAllocator {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
@@ -234,7 +235,7 @@ unsafe impl GlobalAlloc for HeapAllocator {
         // All of our algorithms align objects to their size, so this should be no problem.
         assert!(layout.align() <= size);
         if size >= BLOCK_SIZE {
-            let address = HEAP_VIRTUAL_MEMORY_ALLOCATOR.allocate(size);
+            let address = HEAP_VIRTUAL_MEMORY_ALLOCATOR.lock().allocate(size);
             if let Some(address) = address {
                 for virtual_block_address in (address..(address + size)).step_by(BLOCK_SIZE) {
                     let physical_block_address = physical_memory_manager::allocate_block_address();
@@ -258,19 +259,19 @@ unsafe impl GlobalAlloc for HeapAllocator {
             let size = usize::max(size, MIN_SLAB_ENTRY_SIZE);
             // This is a little annoying, but I don't think there is a better approach and it isn't really that bad.
             match size {
-                8 => SLAB_ALLOCATOR.allocate::<8>(),
-                16 => SLAB_ALLOCATOR.allocate::<16>(),
-                32 => SLAB_ALLOCATOR.allocate::<32>(),
-                64 => SLAB_ALLOCATOR.allocate::<64>(),
-                128 => SLAB_ALLOCATOR.allocate::<128>(),
-                256 => SLAB_ALLOCATOR.allocate::<256>(),
-                512 => SLAB_ALLOCATOR.allocate::<512>(),
-                1024 => SLAB_ALLOCATOR.allocate::<1024>(),
-                2048 => SLAB_ALLOCATOR.allocate::<2048>(),
-                4096 => SLAB_ALLOCATOR.allocate::<4096>(),
-                8192 => SLAB_ALLOCATOR.allocate::<8192>(),
-                16384 => SLAB_ALLOCATOR.allocate::<16384>(),
-                32768 => SLAB_ALLOCATOR.allocate::<32768>(),
+                8 => SLAB_ALLOCATOR.lock().allocate::<8>(),
+                16 => SLAB_ALLOCATOR.lock().allocate::<16>(),
+                32 => SLAB_ALLOCATOR.lock().allocate::<32>(),
+                64 => SLAB_ALLOCATOR.lock().allocate::<64>(),
+                128 => SLAB_ALLOCATOR.lock().allocate::<128>(),
+                256 => SLAB_ALLOCATOR.lock().allocate::<256>(),
+                512 => SLAB_ALLOCATOR.lock().allocate::<512>(),
+                1024 => SLAB_ALLOCATOR.lock().allocate::<1024>(),
+                2048 => SLAB_ALLOCATOR.lock().allocate::<2048>(),
+                4096 => SLAB_ALLOCATOR.lock().allocate::<4096>(),
+                8192 => SLAB_ALLOCATOR.lock().allocate::<8192>(),
+                16384 => SLAB_ALLOCATOR.lock().allocate::<16384>(),
+                32768 => SLAB_ALLOCATOR.lock().allocate::<32768>(),
                 _ => panic!("Invalid slab allocator size: {}", size),
             }
         }
@@ -285,24 +286,24 @@ unsafe impl GlobalAlloc for HeapAllocator {
                 mark_as_free(get_physical_address(block_address));
                 unmap_block(block_address);
             }
-            HEAP_VIRTUAL_MEMORY_ALLOCATOR.free(size, address);
+            HEAP_VIRTUAL_MEMORY_ALLOCATOR.lock().free(size, address);
         } else {
             let size = usize::max(size, MIN_SLAB_ENTRY_SIZE);
             // Again, we have to match on the size.
             match size {
-                8 => SLAB_ALLOCATOR.free::<8>(ptr),
-                16 => SLAB_ALLOCATOR.free::<16>(ptr),
-                32 => SLAB_ALLOCATOR.free::<32>(ptr),
-                64 => SLAB_ALLOCATOR.free::<64>(ptr),
-                128 => SLAB_ALLOCATOR.free::<128>(ptr),
-                256 => SLAB_ALLOCATOR.free::<256>(ptr),
-                512 => SLAB_ALLOCATOR.free::<512>(ptr),
-                1024 => SLAB_ALLOCATOR.free::<1024>(ptr),
-                2048 => SLAB_ALLOCATOR.free::<2048>(ptr),
-                4096 => SLAB_ALLOCATOR.free::<4096>(ptr),
-                8192 => SLAB_ALLOCATOR.free::<8192>(ptr),
-                16384 => SLAB_ALLOCATOR.free::<16384>(ptr),
-                32768 => SLAB_ALLOCATOR.free::<32768>(ptr),
+                8 => SLAB_ALLOCATOR.lock().free::<8>(ptr),
+                16 => SLAB_ALLOCATOR.lock().free::<16>(ptr),
+                32 => SLAB_ALLOCATOR.lock().free::<32>(ptr),
+                64 => SLAB_ALLOCATOR.lock().free::<64>(ptr),
+                128 => SLAB_ALLOCATOR.lock().free::<128>(ptr),
+                256 => SLAB_ALLOCATOR.lock().free::<256>(ptr),
+                512 => SLAB_ALLOCATOR.lock().free::<512>(ptr),
+                1024 => SLAB_ALLOCATOR.lock().free::<1024>(ptr),
+                2048 => SLAB_ALLOCATOR.lock().free::<2048>(ptr),
+                4096 => SLAB_ALLOCATOR.lock().free::<4096>(ptr),
+                8192 => SLAB_ALLOCATOR.lock().free::<8192>(ptr),
+                16384 => SLAB_ALLOCATOR.lock().free::<16384>(ptr),
+                32768 => SLAB_ALLOCATOR.lock().free::<32768>(ptr),
                 _ => panic!("Invalid slab allocator size: {}", size),
             }
         }
@@ -387,11 +388,10 @@ pub unsafe fn map_physical_memory(
     let offset_from_block = physical_address % BLOCK_SIZE;
     let aligned_physical_address = physical_address - offset_from_block;
     let allocated_size = align_address_up(size + offset_from_block, BLOCK_SIZE);
-    let address = unsafe {
-        HEAP_VIRTUAL_MEMORY_ALLOCATOR
-            .allocate(allocated_size)
-            .unwrap()
-    };
+    let address = HEAP_VIRTUAL_MEMORY_ALLOCATOR
+        .lock()
+        .allocate(allocated_size)
+        .unwrap();
     for (virtual_block_address, physical_block_address) in (address..(address + allocated_size))
         .step_by(BLOCK_SIZE)
         .zip(
@@ -9,6 +9,7 @@ use core::ops::Deref;
 use alloc::{collections::BTreeMap, string::String};
 
 use crate::{
+    arch_api,
     memory::{
         Array, DynamicallySized, DynamicallySizedItem, DynamicallySizedObjectIterator, Endianness,
         FromBytes, FromBytesError,
@@ -107,6 +108,20 @@ pub fn read_initial_ramdisk(initial_ramdisk: &[u8]) -> BTreeMap<String, &[u8]> {
     result
 }
 
+// SAFETY: This is only written by `load_initial_ramdisk`, before the scheduler starts.
+static mut FILES: BTreeMap<String, &'static [u8]> = BTreeMap::new();
+
+/// Read the initial_ramdisk which the bootloader gave us, so that its files can be found with `find_file`.
+pub fn load_initial_ramdisk() {
+    let initial_ramdisk =
+        arch_api::initial_ramdisk::get_initial_ramdisk().expect("No initial_ramdisk found");
+    unsafe { FILES = read_initial_ramdisk(initial_ramdisk) };
+}
+
+pub fn find_file(path: &str) -> Option<&'static [u8]> {
+    unsafe { FILES.get(path).copied() }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -0,0 +1,116 @@
+//! Handlers for interrupts from devices.
+//!
+//! A driver allocates an `Irq` for its device's interrupt, registers a handler for it and then enables it.
+//! The architecture code calls the handler when the interrupt comes in, and sends the end of interrupt once it returns.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    arch_api,
+    spinlock::{InterruptSafeSpinLock, LockLevel},
+};
+
+/// Where an interrupt comes from, and what its signal looks like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptSource {
+    /// The number ACPI gives the interrupt, which is the I/O APIC input on x86_64 and the GIC interrupt ID on aarch64.
+    pub global_system_interrupt: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// An interrupt which has been set up for a driver to handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Irq {
+    /// What the interrupt arrives as, which is the vector on x86_64 and the GIC interrupt ID on aarch64.
+    number: u32,
+    global_system_interrupt: u32,
+}
+
+impl Irq {
+    pub(crate) fn new(number: u32, global_system_interrupt: u32) -> Self {
+        Self {
+            number,
+            global_system_interrupt,
+        }
+    }
+
+    pub(crate) fn number(&self) -> u32 {
+        self.number
+    }
+
+    pub(crate) fn global_system_interrupt(&self) -> u32 {
+        self.global_system_interrupt
+    }
+}
+
+type Handler = Arc<dyn Fn() + Send + Sync>;
+
+static HANDLERS: InterruptSafeSpinLock<BTreeMap<u32, Handler>> =
+    InterruptSafeSpinLock::new(LockLevel::InterruptHandlers, BTreeMap::new());
+
+/// Set up an interrupt so that it can be handled, which leaves it disabled.
+pub fn allocate(source: InterruptSource) -> Irq {
+    arch_api::irq::allocate(source)
+}
+
+/// Call `handler` whenever `irq` comes in (once it is enabled).
+/// The handler runs with interrupts disabled, and anything it needs (like the device it is for) can be captured by the closure.
+pub fn register(irq: Irq, handler: impl Fn() + Send + Sync + 'static) {
+    let handler: Handler = Arc::new(handler);
+    let previous = HANDLERS.lock().insert(irq.number, handler);
+    assert!(previous.is_none(), "{:?} already has a handler", irq);
+}
+
+/// Stop calling the handler for `irq`, which should be disabled first.
+pub fn unregister(irq: Irq) {
+    let handler = HANDLERS.lock().remove(&irq.number);
+    assert!(handler.is_some(), "{:?} has no handler", irq);
+}
+
+pub fn enable(irq: Irq) {
+    arch_api::irq::enable(irq);
+}
+
+pub fn disable(irq: Irq) {
+    arch_api::irq::disable(irq);
+}
+
+/// Call the handler for the interrupt which arrived as `number`, returning whether there was one.
+pub(crate) fn handle(number: u32) -> bool {
+    // The lock isn't held while the handler runs, so that it can take whichever locks it wants.
+    let handler = HANDLERS.lock().get(&number).cloned();
+    match handler {
+        Some(handler) => {
+            handler();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn handler_test() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let irq = Irq::new(0x7e, 12);
+        assert!(!handle(irq.number()));
+
+        register(irq, || {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(handle(irq.number()));
+        assert!(handle(irq.number()));
+        assert!(!handle(0x7d));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+        unregister(irq);
+        assert!(!handle(irq.number()));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+}
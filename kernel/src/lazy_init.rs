@@ -1,24 +1,57 @@
 use core::{
     cell::UnsafeCell,
+    hint::spin_loop,
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU8, Ordering},
 };
 
+const UNINITIALIZED: u8 = 0;
+const INITIALIZING: u8 = 1;
+const INITIALIZED: u8 = 2;
+
 pub struct LazilyInitialized<'initializer, T: Sized + Sync> {
+    state: AtomicU8,
     value: UnsafeCell<Option<T>>,
-    initializer: &'initializer dyn Fn() -> T,
+    initializer: &'initializer (dyn Fn() -> T + Sync),
 }
 
+// SAFETY: The value is only written once, by whichever CPU gets to initialize it, and everything else waits until that is done before reading it.
+unsafe impl<'initializer, T: Sized + Send + Sync> Sync for LazilyInitialized<'initializer, T> {}
+
 impl<'initializer, T: Sized + Sync> LazilyInitialized<'initializer, T> {
-    pub const fn new(initializer: &'initializer dyn Fn() -> T) -> Self {
+    pub const fn new(initializer: &'initializer (dyn Fn() -> T + Sync)) -> Self {
         Self {
+            state: AtomicU8::new(UNINITIALIZED),
             value: UnsafeCell::new(None),
             initializer,
         }
     }
 
     pub fn is_initialized(value: &Self) -> bool {
-        // TODO: Make this safe. It should be ok for now because we don't have threads yet. Same goes for the rest of these functions.
-        unsafe { value.value.get().as_ref().unwrap().is_some() }
+        value.state.load(Ordering::Acquire) == INITIALIZED
+    }
+
+    fn initialize(&self) {
+        if self
+            .state
+            .compare_exchange(
+                UNINITIALIZED,
+                INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            let value = (self.initializer)();
+            // SAFETY: Only the CPU which changed the state to initializing gets here, and nothing reads the value until the state is initialized.
+            unsafe { *self.value.get() = Some(value) };
+            self.state.store(INITIALIZED, Ordering::Release);
+        } else {
+            // Another CPU is initializing it (or this one is, from further up the stack, in which case this never finishes).
+            while self.state.load(Ordering::Acquire) != INITIALIZED {
+                spin_loop();
+            }
+        }
     }
 }
 
@@ -26,31 +59,26 @@ impl<'initializer, T: Sized + Sync> Deref for LazilyInitialized<'initializer, T>
     type Target = T;
 
     fn deref(&self) -> &T {
-        // TODO: Make safe (see above)
-        unsafe {
-            if self.value.get().as_ref().unwrap().is_none() {
-                *self.value.get() = Some((self.initializer)());
-            }
-            self.value.get().as_ref().unwrap().as_ref().unwrap()
+        if !Self::is_initialized(self) {
+            self.initialize();
         }
+        // SAFETY: The value has been initialized, and is never written again except through `deref_mut`.
+        unsafe { (*self.value.get()).as_ref().unwrap() }
     }
 }
 
 impl<'initializer, T: Sized + Sync> DerefMut for LazilyInitialized<'initializer, T> {
     fn deref_mut(&mut self) -> &mut T {
-        // TODO: Make safe.
-        unsafe {
-            if self.value.get_mut().is_none() {
-                *self.value.get() = Some((self.initializer)());
-            }
-            self.value.get_mut().as_mut().unwrap()
+        if !Self::is_initialized(self) {
+            self.initialize();
         }
+        self.value.get_mut().as_mut().unwrap()
     }
 }
 
 macro_rules! lazy_static {
     ($visibility:vis static ref $name:ident : $type:ty = $initializer:expr ;) => {
-        $visibility static mut $name: crate::lazy_init::LazilyInitialized<$type> = crate::lazy_init::LazilyInitialized::new(&||$initializer);
+        $visibility static $name: crate::lazy_init::LazilyInitialized<$type> = crate::lazy_init::LazilyInitialized::new(&||$initializer);
     };
 }
 
@@ -58,7 +86,10 @@ pub(crate) use lazy_static;
 
 #[cfg(test)]
 mod test {
-    use crate::lazy_init::LazilyInitialized;
+    use crate::{
+        lazy_init::LazilyInitialized,
+        spinlock::{LockLevel, SpinLock},
+    };
 
     struct CantConstructAtCompileTime {
         nums: [u8; 24],
@@ -71,17 +102,17 @@ mod test {
     }
 
     lazy_static! {
-        static ref LAZY_VARIABLE: CantConstructAtCompileTime = CantConstructAtCompileTime::new(42);
+        static ref LAZY_VARIABLE: SpinLock<CantConstructAtCompileTime> =
+            SpinLock::new(LockLevel::Heap, CantConstructAtCompileTime::new(42));
     }
 
     #[test]
     fn lazy_initialization_test() {
-        unsafe {
-            assert!(!LazilyInitialized::is_initialized(&LAZY_VARIABLE));
-            LAZY_VARIABLE.nums[0] = 56;
-            assert!(LazilyInitialized::is_initialized(&LAZY_VARIABLE));
-            assert_eq!(LAZY_VARIABLE.nums[0], 56);
-            assert_eq!(LAZY_VARIABLE.nums[1], 42);
-        }
+        assert!(!LazilyInitialized::is_initialized(&LAZY_VARIABLE));
+        LAZY_VARIABLE.lock().nums[0] = 56;
+        assert!(LazilyInitialized::is_initialized(&LAZY_VARIABLE));
+        let lazy_variable = LAZY_VARIABLE.lock();
+        assert_eq!(lazy_variable.nums[0], 56);
+        assert_eq!(lazy_variable.nums[1], 42);
     }
 }
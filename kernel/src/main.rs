@@ -25,36 +25,54 @@
 mod acpi;
 mod assert;
 mod buddy;
+mod channel;
+mod clock;
 mod console;
 mod elf;
 mod font_renderer;
+mod futex;
+mod handle;
 mod heap;
 mod initial_ramdisk;
+mod irq;
 mod lazy_init;
 mod memory;
+mod memory_object;
 mod mmio;
 mod paging;
+mod per_cpu;
 mod physical_memory_manager;
+mod process;
+mod scheduler;
+mod spinlock;
+mod syscall;
+mod timer_queue;
 mod user_memory;
+mod user_stack;
+mod wall_clock;
 
 #[cfg_attr(target_arch = "x86_64", path = "x86_64/mod.rs")]
 #[cfg_attr(target_arch = "aarch64", path = "aarch64/mod.rs")]
 mod arch;
 
 pub use arch::arch_api;
-use common::elf::load_elf;
 
 use core::panic::PanicInfo;
 
-use crate::{
-    arch_api::user_mode::enter_user_mode, elf::map_sections, initial_ramdisk::read_initial_ramdisk,
-};
-
 extern crate alloc;
 
 #[cfg_attr(not(test), panic_handler)]
 fn kpanic(info: &PanicInfo) -> ! {
-    console::print!("Kernel panic: {}\n", info);
+    spinlock::stop_checking_lock_order();
+    // SAFETY: Whatever was printing when the panic happened isn't going to carry on.
+    unsafe { console::force_unlock() };
+    let uptime = clock::monotonic_time();
+    console::print!(
+        "Kernel panic after {}.{:03}s: {}\n",
+        uptime.as_secs(),
+        uptime.subsec_millis(),
+        info
+    );
     loop {}
 }
 
@@ -68,16 +86,18 @@ extern "C" fn kmain() -> ! {
     let acpi_info = arch_api::acpi::handle_acpi_info(required_acpi_tables);
     arch_api::irq::initialize(&acpi_info);
     arch_api::timer::initialize(&acpi_info);
+    match arch_api::rtc::read_time(&acpi_info) {
+        Some(time) => {
+            wall_clock::initialize(time);
+            console::println!("Time: {}", wall_clock::DateTime::from_unix_time(time));
+        }
+        None => console::println!("No real time clock found"),
+    }
+    arch_api::smp::start_application_processors(&acpi_info);
 
-    let initial_ramdisk = read_initial_ramdisk(
-        arch_api::initial_ramdisk::get_initial_ramdisk().expect("No initial_ramdisk found"),
-    );
-    let startup_program = initial_ramdisk
-        .get("services/startup")
-        .expect("No startup program found in initial ramdisk");
-    let startup_elf_info = load_elf(startup_program).expect("Failed to parse startup program");
-    map_sections(&startup_elf_info, startup_program);
-    unsafe { enter_user_mode(startup_elf_info.entrypoint) };
+    initial_ramdisk::load_initial_ramdisk();
+    process::spawn("services/startup").expect("Failed to start the startup program");
+    scheduler::start();
 }
 
 #[cfg(test)]
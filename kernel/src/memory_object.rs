@@ -0,0 +1,67 @@
+//! Memory objects are physical memory which can be mapped into any number of address spaces, so programs can share it.
+
+use alloc::vec::Vec;
+
+use common::syscall::MEMORY_OBJECT_ALIGNMENT;
+
+use crate::{
+    assert::const_assert,
+    heap::map_physical_memory,
+    memory::align_address_up,
+    paging::{MemoryType, PagePermissions},
+    physical_memory_manager::{allocate_block_address, mark_as_free, BLOCK_SIZE},
+};
+
+const_assert!(
+    MEMORY_OBJECT_ALIGNMENT == BLOCK_SIZE,
+    "Memory objects are made of physical memory blocks"
+);
+
+/// The physical memory is freed when the object is dropped, which happens once it isn't mapped anywhere and there are no handles to it.
+pub struct MemoryObject {
+    blocks: Vec<usize>,
+}
+
+impl MemoryObject {
+    /// Allocate (zeroed) memory for a new object, rounding the size up to a whole number of blocks.
+    ///
+    /// Returns `None` if there isn't enough memory.
+    pub fn new(size: usize) -> Option<Self> {
+        let block_count = align_address_up(size, BLOCK_SIZE) / BLOCK_SIZE;
+        let mut object = Self {
+            blocks: Vec::with_capacity(block_count),
+        };
+        for _ in 0..block_count {
+            // If this fails, dropping the object frees the blocks which were already allocated.
+            let physical_address = allocate_block_address()?;
+            object.blocks.push(physical_address);
+            unsafe {
+                map_physical_memory(
+                    physical_address,
+                    BLOCK_SIZE,
+                    MemoryType::Normal,
+                    PagePermissions::KERNEL_READ_WRITE,
+                )
+                .fill(0);
+            }
+        }
+        Some(object)
+    }
+
+    pub fn size(&self) -> usize {
+        self.blocks.len() * BLOCK_SIZE
+    }
+
+    /// The physical address of each block, in order.
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+}
+
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        for &physical_address in &self.blocks {
+            mark_as_free(physical_address);
+        }
+    }
+}
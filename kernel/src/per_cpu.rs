@@ -0,0 +1,105 @@
+//! Data which every CPU has its own copy of.
+//!
+//! Each CPU keeps the address of its copy in a register which only the kernel can use (the GS base on x86_64, and TPIDR_EL1 on aarch64), so getting at it doesn't need any locking.
+
+use alloc::boxed::Box;
+use core::{
+    cell::{Cell, UnsafeCell},
+    mem::offset_of,
+    ptr::null,
+    sync::atomic::AtomicU32,
+};
+
+use crate::{
+    arch_api::per_cpu::{get_per_cpu_address, set_per_cpu_address, ArchPerCpu},
+    scheduler::Thread,
+};
+
+/// The layout is fixed so that the system call entry code can find the fields it needs from assembly.
+#[repr(C)]
+pub struct PerCpu {
+    /// The address of this, since on x86_64 the register holding it can only be read by reading through it.
+    this: Cell<*const PerCpu>,
+    /// The top of the current thread's kernel stack, which the system call entry code switches to.
+    kernel_stack_top: Cell<usize>,
+    /// Where the system call entry code keeps the user's stack pointer while it switches stacks.
+    scratch: Cell<usize>,
+    /// 0 for the CPU which booted the kernel, and counting up from there in the order the others were started.
+    id: usize,
+    current_thread: UnsafeCell<Option<Box<Thread>>>,
+    /// The levels of the spinlocks which this CPU holds (see `spinlock`).
+    held_lock_levels: AtomicU32,
+    pub arch: ArchPerCpu,
+}
+
+// SAFETY: Apart from the CPU which creates it, only the CPU it belongs to ever touches it.
+unsafe impl Sync for PerCpu {}
+
+pub const THIS_OFFSET: usize = offset_of!(PerCpu, this);
+pub const KERNEL_STACK_TOP_OFFSET: usize = offset_of!(PerCpu, kernel_stack_top);
+pub const SCRATCH_OFFSET: usize = offset_of!(PerCpu, scratch);
+
+static BOOT_CPU: PerCpu = PerCpu::new(0);
+
+impl PerCpu {
+    const fn new(id: usize) -> Self {
+        Self {
+            this: Cell::new(null()),
+            kernel_stack_top: Cell::new(0),
+            scratch: Cell::new(0),
+            id,
+            current_thread: UnsafeCell::new(None),
+            held_lock_levels: AtomicU32::new(0),
+            arch: ArchPerCpu::new(),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn kernel_stack_top(&self) -> usize {
+        self.kernel_stack_top.get()
+    }
+
+    pub fn set_kernel_stack_top(&self, kernel_stack_top: usize) {
+        self.kernel_stack_top.set(kernel_stack_top);
+    }
+
+    /// The thread which this CPU is running, which only the scheduler touches (with interrupts disabled).
+    pub fn current_thread(&self) -> *mut Option<Box<Thread>> {
+        self.current_thread.get()
+    }
+
+    pub fn held_lock_levels(&self) -> &AtomicU32 {
+        &self.held_lock_levels
+    }
+}
+
+/// Make the data for another CPU, which it installs once it starts.
+pub fn allocate(id: usize) -> &'static PerCpu {
+    Box::leak(Box::new(PerCpu::new(id)))
+}
+
+/// Make `per_cpu` the current CPU's data.
+///
+/// # Safety
+/// No other CPU can be using it.
+pub unsafe fn install(per_cpu: &'static PerCpu) {
+    per_cpu.this.set(per_cpu);
+    set_per_cpu_address(per_cpu as *const PerCpu as usize);
+}
+
+/// Install the data for the CPU which booted the kernel, which has to happen before anything takes a lock.
+pub fn initialize_boot_cpu() {
+    // SAFETY: Only the boot CPU is running.
+    unsafe { install(&BOOT_CPU) };
+}
+
+/// The data for the CPU this is running on.
+///
+/// Nothing moves threads between CPUs while they are in the kernel, so this stays right for as long as it is held.
+pub fn this_cpu() -> &'static PerCpu {
+    // SAFETY: Every CPU installs its data before running anything which could get here, and it is never freed.
+    unsafe { &*(get_per_cpu_address() as *const PerCpu) }
+}
@@ -1,4 +1,8 @@
-use crate::{assert::const_assert, paging::PAGE_SIZE};
+use crate::{
+    assert::const_assert,
+    paging::PAGE_SIZE,
+    spinlock::{InterruptSafeSpinLock, LockLevel},
+};
 use core::{
     mem::{size_of, MaybeUninit},
     sync::atomic::{AtomicUsize, Ordering},
@@ -173,34 +177,31 @@ pub fn get_address(block_index: usize) -> usize {
     block_index * BLOCK_SIZE
 }
 
-pub static mut GLOBAL_PMM: MemoryBitmapAllocator<BLOCK_COUNT> = MemoryBitmapAllocator::new();
+pub static GLOBAL_PMM: InterruptSafeSpinLock<MemoryBitmapAllocator<BLOCK_COUNT>> =
+    InterruptSafeSpinLock::new(LockLevel::PhysicalMemory, MemoryBitmapAllocator::new());
 
 pub fn mark_as_free(address: usize) {
-    unsafe {
-        GLOBAL_PMM.mark_as_free(get_block_index(address));
-    }
+    GLOBAL_PMM.lock().mark_as_free(get_block_index(address));
 }
 
 pub fn mark_as_used(address: usize) {
-    unsafe {
-        GLOBAL_PMM.mark_as_used(get_block_index(address));
-    }
+    GLOBAL_PMM.lock().mark_as_used(get_block_index(address));
 }
 
 pub fn mark_range_as_free(start_address: usize, end_address: usize) {
-    unsafe {
-        GLOBAL_PMM.mark_range_as_free(get_block_index(start_address), get_block_index(end_address));
-    }
+    GLOBAL_PMM
+        .lock()
+        .mark_range_as_free(get_block_index(start_address), get_block_index(end_address));
 }
 
 pub fn mark_range_as_used(start_address: usize, end_address: usize) {
-    unsafe {
-        GLOBAL_PMM.mark_range_as_used(get_block_index(start_address), get_block_index(end_address));
-    }
+    GLOBAL_PMM
+        .lock()
+        .mark_range_as_used(get_block_index(start_address), get_block_index(end_address));
 }
 
 pub fn allocate_block_address() -> Option<usize> {
-    unsafe { GLOBAL_PMM.allocate_block().map(get_address) }
+    GLOBAL_PMM.lock().allocate_block().map(get_address)
 }
 
 // This is outside the test module because it is for testing in the real kernel environment and not part of the unit testing suite.
@@ -0,0 +1,101 @@
+//! Processes are programs loaded from the initial ramdisk, each with its own address space and handles.
+
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::RefCell;
+
+use common::{elf::load_elf, syscall::SyscallError};
+
+use crate::{
+    arch_api::thread::SavedRegisters,
+    elf::map_sections,
+    handle::HandleTable,
+    initial_ramdisk::find_file,
+    scheduler::{self, Waiter},
+    user_memory::AddressSpace,
+    user_stack::create_user_stack,
+};
+
+pub struct Process {
+    /// `None` until the process exits.
+    exit_status: Option<u64>,
+    /// Threads which are blocked until this process exits.
+    waiting_threads: Vec<Waiter>,
+    handles: HandleTable,
+}
+
+impl Process {
+    fn new() -> Self {
+        Self {
+            exit_status: None,
+            waiting_threads: Vec::new(),
+            handles: HandleTable::new(),
+        }
+    }
+
+    pub fn exit_status(&self) -> Option<u64> {
+        self.exit_status
+    }
+
+    /// Wake `waiter` up when this process exits.
+    pub fn add_waiting_thread(&mut self, waiter: Waiter) {
+        self.waiting_threads
+            .retain(|&waiter| scheduler::is_still_waiting(waiter));
+        self.waiting_threads.push(waiter);
+    }
+
+    pub fn handles(&self) -> &HandleTable {
+        &self.handles
+    }
+
+    pub fn handles_mut(&mut self) -> &mut HandleTable {
+        &mut self.handles
+    }
+}
+
+/// Load the program at `path` in the initial ramdisk and start running it in a new process.
+///
+/// The program gets its path as its only argument.
+pub fn spawn(path: &str) -> Result<Rc<RefCell<Process>>, SyscallError> {
+    let file = find_file(path).ok_or(SyscallError::NotFound)?;
+    let elf = load_elf(file).map_err(|_| SyscallError::InvalidExecutable)?;
+    let mut address_space = AddressSpace::new();
+    // The stack goes in first, so that segments which overlap it are caught by `map_sections`.
+    let stack_pointer = create_user_stack(&mut address_space, &elf, file, &[path], &[]);
+    map_sections(&mut address_space, &elf, file)?;
+    let process = Rc::new(RefCell::new(Process::new()));
+    scheduler::spawn_user_thread(
+        process.clone(),
+        address_space,
+        elf.entrypoint,
+        stack_pointer,
+    );
+    Ok(process)
+}
+
+/// Record that the current process has exited, and wake up anything which is waiting for it.
+///
+/// This doesn't stop the current thread, which is up to the caller.
+pub fn mark_current_process_exited(exit_status: u64) {
+    let process = scheduler::current_process().expect("Only user threads can exit");
+    // Take everything out first, since dropping the handles could drop other processes.
+    let (waiting_threads, handles) = {
+        let mut process = process.borrow_mut();
+        process.exit_status = Some(exit_status);
+        (
+            core::mem::take(&mut process.waiting_threads),
+            core::mem::replace(&mut process.handles, HandleTable::new()),
+        )
+    };
+    drop(handles);
+    for waiter in waiting_threads {
+        scheduler::wake_thread(waiter);
+    }
+}
+
+/// Stop the current process with the given exit status, switching to the next thread.
+///
+/// Returns the registers which should be restored when the interrupt returns.
+pub fn exit_current_process(exit_status: u64) -> *mut SavedRegisters {
+    mark_current_process_exited(exit_status);
+    scheduler::exit_current_thread()
+}
@@ -0,0 +1,363 @@
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    rc::Rc,
+};
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use common::syscall::SyscallResult;
+
+use crate::{
+    arch_api::{
+        asm::{disable_interrupts, wait_for_interrupt},
+        thread::{
+            push_initial_kernel_registers, push_initial_user_registers, resume, set_kernel_stack,
+            SavedRegisters,
+        },
+    },
+    clock::monotonic_time,
+    per_cpu::this_cpu,
+    process::Process,
+    timer_queue::{self, TimerId},
+    user_memory::AddressSpace,
+};
+
+/// How long each thread gets to run before the timer interrupt switches to the next one.
+pub const TIME_SLICE_MILLISECONDS: u64 = 10;
+
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Refers to one particular time that a thread blocks, which is what things waking it up need to keep hold of.
+///
+/// If the thread stops waiting for something (because of a timeout), it mustn't be woken up by that thing later on while it is waiting for something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waiter {
+    thread: ThreadId,
+    wake_count: u64,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    /// Every thread gets its own kernel stack, which is where its registers are saved when it is interrupted.
+    kernel_stack: Box<KernelStack>,
+    saved_registers: *mut SavedRegisters,
+    /// Kernel threads don't have one, and just run in whichever address space was active before them.
+    address_space: Option<AddressSpace>,
+    /// The process which a user thread belongs to.
+    process: Option<Rc<RefCell<Process>>>,
+    /// If the thread is blocked with a timeout, the timer which will wake it up anyway.
+    timeout_timer: Option<TimerId>,
+    /// How many times the thread has been woken up, so that old `Waiter`s don't work any more.
+    wake_count: u64,
+}
+
+impl Thread {
+    fn new(
+        address_space: Option<AddressSpace>,
+        process: Option<Rc<RefCell<Process>>>,
+        push_initial_registers: impl FnOnce(usize) -> *mut SavedRegisters,
+    ) -> Box<Self> {
+        // SAFETY: The stack is just bytes, so all zeroes is fine.
+        let kernel_stack: Box<KernelStack> = unsafe { Box::new_zeroed().assume_init() };
+        let mut thread = Box::new(Self {
+            id: ThreadId::new(),
+            kernel_stack,
+            saved_registers: core::ptr::null_mut(),
+            address_space,
+            process,
+            timeout_timer: None,
+            wake_count: 0,
+        });
+        thread.saved_registers = push_initial_registers(thread.kernel_stack_top());
+        thread
+    }
+
+    fn waiter(&self) -> Waiter {
+        Waiter {
+            thread: self.id,
+            wake_count: self.wake_count,
+        }
+    }
+
+    fn kernel_stack_top(&self) -> usize {
+        self.kernel_stack.0.as_ptr() as usize + KERNEL_STACK_SIZE
+    }
+}
+
+// SAFETY: These are only accessed with interrupts disabled (either from interrupt handlers or before the scheduler starts), and only the boot CPU runs the scheduler (the others are parked with interrupts disabled).
+static mut RUN_QUEUE: VecDeque<Box<Thread>> = VecDeque::new();
+/// Runs when there is nothing else to do. This is `None` while the idle thread is the current thread.
+static mut IDLE_THREAD: Option<Box<Thread>> = None;
+static mut IDLE_THREAD_ID: Option<ThreadId> = None;
+/// Threads which have exited, but might still be using their kernel stack or address space.
+static mut EXITED_THREADS: VecDeque<Box<Thread>> = VecDeque::new();
+/// Threads which are waiting for something, and won't run until `wake_thread` is called.
+static mut BLOCKED_THREADS: BTreeMap<ThreadId, Box<Thread>> = BTreeMap::new();
+/// The timer for the end of the current thread's time slice.
+///
+/// Threads only get a time slice when there is something else waiting to run, so this is often `None`.
+static mut TIME_SLICE_TIMER: Option<TimerId> = None;
+/// Set when the time slice ends, so that the scheduler switches threads when the timer interrupt returns.
+static mut TIME_SLICE_OVER: bool = false;
+
+/// Where the thread running on this CPU is kept, which is `None` until the scheduler starts.
+fn current_thread_slot() -> &'static mut Option<Box<Thread>> {
+    // SAFETY: Only this CPU touches it, with interrupts disabled (like the statics above).
+    unsafe { &mut *this_cpu().current_thread() }
+}
+
+extern "C" fn idle() -> ! {
+    loop {
+        wait_for_interrupt();
+    }
+}
+
+fn end_time_slice() {
+    unsafe {
+        TIME_SLICE_TIMER = None;
+        TIME_SLICE_OVER = true;
+    }
+}
+
+fn start_time_slice() {
+    let deadline = monotonic_time() + Duration::from_millis(TIME_SLICE_MILLISECONDS);
+    unsafe { TIME_SLICE_TIMER = Some(timer_queue::add_timer(deadline, end_time_slice)) };
+}
+
+/// Put a thread in the run queue, making sure that the current thread won't keep the CPU forever.
+fn make_runnable(thread: Box<Thread>) {
+    unsafe {
+        RUN_QUEUE.push_back(thread);
+        // The idle thread is switched away from at the next interrupt anyway.
+        let running_real_thread = current_thread_slot()
+            .as_ref()
+            .is_some_and(|thread| !is_idle(thread));
+        if running_real_thread && TIME_SLICE_TIMER.is_none() {
+            start_time_slice();
+        }
+    }
+}
+
+fn add_thread(thread: Box<Thread>) -> ThreadId {
+    let id = thread.id;
+    make_runnable(thread);
+    id
+}
+
+pub fn spawn_kernel_thread(entrypoint: extern "C" fn() -> !) -> ThreadId {
+    add_thread(Thread::new(None, None, |stack_top| unsafe {
+        push_initial_kernel_registers(stack_top, entrypoint)
+    }))
+}
+
+/// Create a thread which starts at `entrypoint` in user mode, with the given user stack pointer.
+///
+/// The entrypoint must already be mapped into the address space.
+pub fn spawn_user_thread(
+    process: Rc<RefCell<Process>>,
+    address_space: AddressSpace,
+    entrypoint: usize,
+    stack_pointer: usize,
+) -> ThreadId {
+    add_thread(Thread::new(
+        Some(address_space),
+        Some(process),
+        |stack_top| unsafe { push_initial_user_registers(stack_top, entrypoint, stack_pointer) },
+    ))
+}
+
+pub fn current_thread_id() -> ThreadId {
+    current_thread_slot()
+        .as_ref()
+        .expect("No thread is running")
+        .id
+}
+
+/// Something which can wake the current thread up after it blocks (and not after it is next woken up).
+pub fn current_waiter() -> Waiter {
+    current_thread_slot()
+        .as_ref()
+        .expect("No thread is running")
+        .waiter()
+}
+
+/// The process which the current thread belongs to, or `None` for kernel threads.
+pub fn current_process() -> Option<Rc<RefCell<Process>>> {
+    current_thread_slot()
+        .as_ref()
+        .and_then(|thread| thread.process.clone())
+}
+
+fn is_idle(thread: &Thread) -> bool {
+    unsafe { IDLE_THREAD_ID == Some(thread.id) }
+}
+
+/// Free the threads which have exited, as long as we aren't still using them.
+///
+/// This must be called before adding anything to `EXITED_THREADS`, since we are running on the kernel stack of the most recent one.
+fn reap_exited_threads() {
+    unsafe {
+        EXITED_THREADS.retain(|thread| {
+            thread
+                .address_space
+                .as_ref()
+                .is_some_and(|address_space| address_space.is_active())
+        });
+    }
+}
+
+/// Pick the next thread to run, giving it control of the CPU (but not actually switching to it).
+fn switch_to_next_thread() -> *mut SavedRegisters {
+    unsafe {
+        let mut next_thread = RUN_QUEUE
+            .pop_front()
+            .or_else(|| IDLE_THREAD.take())
+            .expect("Idle thread is missing");
+        TIME_SLICE_OVER = false;
+        if let Some(timer) = TIME_SLICE_TIMER.take() {
+            timer_queue::cancel_timer(timer);
+        }
+        if !is_idle(&next_thread) && !RUN_QUEUE.is_empty() {
+            start_time_slice();
+        }
+        set_kernel_stack(next_thread.kernel_stack_top());
+        if let Some(address_space) = &mut next_thread.address_space {
+            if !address_space.is_active() {
+                address_space.activate();
+            }
+        }
+        let saved_registers = next_thread.saved_registers;
+        *current_thread_slot() = Some(next_thread);
+        saved_registers
+    }
+}
+
+/// Start running threads. Interrupts are enabled by the threads themselves.
+pub fn start() -> ! {
+    unsafe {
+        disable_interrupts();
+        let idle_thread = Thread::new(None, None, |stack_top| {
+            push_initial_kernel_registers(stack_top, idle)
+        });
+        IDLE_THREAD_ID = Some(idle_thread.id);
+        IDLE_THREAD = Some(idle_thread);
+        resume(switch_to_next_thread());
+    }
+}
+
+/// Called at the end of the timer interrupt to switch to the next thread in the run queue, if the current one's time slice is over (or the CPU was idle).
+///
+/// Returns the registers which should be restored when the interrupt returns.
+pub fn preempt(saved_registers: *mut SavedRegisters) -> *mut SavedRegisters {
+    unsafe {
+        let Some(current_thread) = current_thread_slot().as_ref() else {
+            // The scheduler hasn't started yet, so just keep going.
+            return saved_registers;
+        };
+        let idle_with_work = is_idle(current_thread) && !RUN_QUEUE.is_empty();
+        if !TIME_SLICE_OVER && !idle_with_work {
+            return saved_registers;
+        }
+        let mut current_thread = current_thread_slot().take().unwrap();
+        reap_exited_threads();
+        current_thread.saved_registers = saved_registers;
+        if is_idle(&current_thread) {
+            IDLE_THREAD = Some(current_thread);
+        } else {
+            RUN_QUEUE.push_back(current_thread);
+        }
+        switch_to_next_thread()
+    }
+}
+
+/// Stop the current thread forever, switching to the next one.
+///
+/// Returns the registers which should be restored when the interrupt returns.
+pub fn exit_current_thread() -> *mut SavedRegisters {
+    unsafe {
+        let current_thread = current_thread_slot().take().expect("No thread is running");
+        assert!(!is_idle(&current_thread), "The idle thread exited");
+        reap_exited_threads();
+        EXITED_THREADS.push_back(current_thread);
+        switch_to_next_thread()
+    }
+}
+
+/// Stop running the current thread until `wake_thread` is called with a `Waiter` for it (or the timeout passes), switching to the next one.
+///
+/// Returns the registers which should be restored when the interrupt returns.
+pub fn block_current_thread(
+    saved_registers: *mut SavedRegisters,
+    timeout: Option<Duration>,
+) -> *mut SavedRegisters {
+    unsafe {
+        let mut current_thread = current_thread_slot().take().expect("No thread is running");
+        assert!(!is_idle(&current_thread), "The idle thread blocked");
+        reap_exited_threads();
+        current_thread.saved_registers = saved_registers;
+        let waiter = current_thread.waiter();
+        current_thread.timeout_timer = timeout.map(|timeout| {
+            timer_queue::add_timer(monotonic_time().saturating_add(timeout), move || {
+                wake_thread(waiter);
+            })
+        });
+        BLOCKED_THREADS.insert(current_thread.id, current_thread);
+        switch_to_next_thread()
+    }
+}
+
+/// The thread, if it is still blocked in the way that `waiter` refers to.
+fn blocked_thread(waiter: Waiter) -> Option<&'static mut Box<Thread>> {
+    unsafe {
+        BLOCKED_THREADS
+            .get_mut(&waiter.thread)
+            .filter(|thread| thread.wake_count == waiter.wake_count)
+    }
+}
+
+/// Whether `waiter` could still wake its thread, so that queues can drop the ones which timed out.
+pub fn is_still_waiting(waiter: Waiter) -> bool {
+    blocked_thread(waiter).is_some()
+}
+
+/// Let a blocked thread run again.
+///
+/// Returns false (and does nothing) if the thread isn't blocked, or has been woken up since `waiter` was made.
+pub fn wake_thread(waiter: Waiter) -> bool {
+    if blocked_thread(waiter).is_none() {
+        return false;
+    }
+    let mut thread = unsafe { BLOCKED_THREADS.remove(&waiter.thread).unwrap() };
+    thread.wake_count += 1;
+    if let Some(timer) = thread.timeout_timer.take() {
+        timer_queue::cancel_timer(timer);
+    }
+    make_runnable(thread);
+    true
+}
+
+/// Like `wake_thread`, but also change what the system call which the thread blocked in returns.
+pub fn wake_thread_with_result(waiter: Waiter, result: SyscallResult) -> bool {
+    if let Some(thread) = blocked_thread(waiter) {
+        // SAFETY: The thread isn't running, so nothing else is using its saved registers.
+        unsafe { (*thread.saved_registers).set_syscall_result(result) };
+    }
+    wake_thread(waiter)
+}
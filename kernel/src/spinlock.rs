@@ -0,0 +1,279 @@
+//! Locks for data which is shared between CPUs (and interrupt handlers).
+//!
+//! Both kinds of lock hand out tickets, so CPUs get the lock in the order they asked for it and none of them can be starved.
+//! In debug builds, every lock also has a level, and taking locks out of order panics instead of (maybe, one day) deadlocking.
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+#[cfg(not(test))]
+use crate::arch_api::asm::{restore_interrupts, save_and_disable_interrupts};
+#[cfg(all(debug_assertions, not(test)))]
+use crate::per_cpu::this_cpu;
+
+/// Where a lock comes in the order that locks have to be taken in.
+///
+/// A lock can only be taken while every lock which is already held has a lower level, so two CPUs can never each be waiting for a lock the other one holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    Console,
+    InterruptHandlers,
+    Heap,
+    HeapVirtualMemory,
+    PageTables,
+    PhysicalMemory,
+    IoApic,
+}
+
+/// Whether a lock at `level` can be taken while holding the locks in `held_levels` (a bitmask of levels).
+fn is_in_order(held_levels: u32, level: LockLevel) -> bool {
+    held_levels >> level as u32 == 0
+}
+
+/// Set once the kernel panics, since the panic handler has to print however many locks were held when it happened.
+#[cfg(all(debug_assertions, not(test)))]
+static CHECKING_LOCK_ORDER: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(true);
+
+#[cfg(all(debug_assertions, not(test)))]
+fn acquired_lock(level: LockLevel) {
+    // Each CPU keeps track of the locks it holds in its own data, since it is only a problem for a CPU to wait for a lock while holding a higher one.
+    let held_levels = this_cpu()
+        .held_lock_levels()
+        .fetch_or(1 << level as u32, Ordering::Relaxed);
+    if CHECKING_LOCK_ORDER.load(Ordering::Relaxed) && !is_in_order(held_levels, level) {
+        CHECKING_LOCK_ORDER.store(false, Ordering::Relaxed);
+        panic!(
+            "Took a {:?} lock while holding locks at these levels: {:#b}",
+            level, held_levels
+        );
+    }
+}
+
+/// Taking a lock without waiting for it can't deadlock, so it is only recorded and not checked against the order.
+#[cfg(all(debug_assertions, not(test)))]
+fn acquired_lock_without_waiting(level: LockLevel) {
+    this_cpu()
+        .held_lock_levels()
+        .fetch_or(1 << level as u32, Ordering::Relaxed);
+}
+
+#[cfg(all(debug_assertions, not(test)))]
+fn released_lock(level: LockLevel) {
+    this_cpu()
+        .held_lock_levels()
+        .fetch_and(!(1 << level as u32), Ordering::Relaxed);
+}
+
+#[cfg(not(all(debug_assertions, not(test))))]
+fn acquired_lock(_level: LockLevel) {}
+
+#[cfg(not(all(debug_assertions, not(test))))]
+fn acquired_lock_without_waiting(_level: LockLevel) {}
+
+#[cfg(not(all(debug_assertions, not(test))))]
+fn released_lock(_level: LockLevel) {}
+
+/// Stop checking the order that locks are taken in, for when the kernel has panicked.
+pub fn stop_checking_lock_order() {
+    #[cfg(all(debug_assertions, not(test)))]
+    CHECKING_LOCK_ORDER.store(false, Ordering::Relaxed);
+}
+
+/// A ticket spinlock.
+///
+/// This doesn't do anything about interrupts, so it mustn't be used for anything an interrupt handler touches (see `InterruptSafeSpinLock`).
+pub struct SpinLock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    level: LockLevel,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: Only one CPU at a time can get at the value, through the lock.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(level: LockLevel, value: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            level,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        // The order is checked before waiting, so that a lock which is already held by this CPU panics instead of waiting forever.
+        acquired_lock(self.level);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    /// Take the lock if nothing else is holding (or waiting for) it.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        acquired_lock_without_waiting(self.level);
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Release the lock, whoever is holding it.
+    ///
+    /// # Safety
+    /// Whatever was holding the lock must never use the value again, which in practice means the kernel has panicked.
+    pub unsafe fn force_unlock(&self) {
+        self.now_serving
+            .store(self.next_ticket.load(Ordering::Relaxed), Ordering::Release);
+        released_lock(self.level);
+    }
+}
+
+pub struct SpinLockGuard<'lock, T> {
+    lock: &'lock SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard means that the lock is held.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard means that the lock is held.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        released_lock(self.lock.level);
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+fn save_and_disable_interrupts() -> bool {
+    false
+}
+
+#[cfg(test)]
+unsafe fn restore_interrupts(_enabled: bool) {}
+
+/// A spinlock which disables interrupts for as long as it is held, so that an interrupt handler can't try to take it while the code it interrupted holds it.
+pub struct InterruptSafeSpinLock<T> {
+    lock: SpinLock<T>,
+}
+
+impl<T> InterruptSafeSpinLock<T> {
+    pub const fn new(level: LockLevel, value: T) -> Self {
+        Self {
+            lock: SpinLock::new(level, value),
+        }
+    }
+
+    pub fn lock(&self) -> InterruptSafeSpinLockGuard<'_, T> {
+        let interrupts_were_enabled = save_and_disable_interrupts();
+        InterruptSafeSpinLockGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Take the lock if nothing else is holding (or waiting for) it, which is all that code which can't wait (like an NMI handler) can do.
+    pub fn try_lock(&self) -> Option<InterruptSafeSpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = save_and_disable_interrupts();
+        let Some(guard) = self.lock.try_lock() else {
+            // SAFETY: This puts the interrupts back the way they were.
+            unsafe { restore_interrupts(interrupts_were_enabled) };
+            return None;
+        };
+        Some(InterruptSafeSpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            interrupts_were_enabled,
+        })
+    }
+
+    /// # Safety
+    /// See `SpinLock::force_unlock`.
+    pub unsafe fn force_unlock(&self) {
+        self.lock.force_unlock();
+    }
+}
+
+pub struct InterruptSafeSpinLockGuard<'lock, T> {
+    guard: ManuallyDrop<SpinLockGuard<'lock, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> Deref for InterruptSafeSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for InterruptSafeSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for InterruptSafeSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // The lock has to be released before interrupts come back on, otherwise an interrupt handler could find it still held.
+        // SAFETY: The guard is never used again.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            restore_interrupts(self.interrupts_were_enabled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spin_lock_test() {
+        let lock = SpinLock::new(LockLevel::Heap, 5);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(*lock.try_lock().unwrap(), 6);
+        let interrupt_safe_lock = InterruptSafeSpinLock::new(LockLevel::Console, [1, 2]);
+        interrupt_safe_lock.lock()[1] = 3;
+        assert_eq!(*interrupt_safe_lock.lock(), [1, 3]);
+    }
+
+    #[test]
+    fn lock_order_test() {
+        assert!(is_in_order(0, LockLevel::Console));
+        let heap = 1 << LockLevel::Heap as u32;
+        assert!(is_in_order(heap, LockLevel::PhysicalMemory));
+        assert!(!is_in_order(heap, LockLevel::Heap));
+        assert!(!is_in_order(heap, LockLevel::Console));
+    }
+}
@@ -0,0 +1,531 @@
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::{
+    cell::RefCell,
+    mem::{size_of, size_of_val},
+    ptr, slice, str,
+    time::Duration,
+};
+
+use common::syscall::{
+    SyscallError, SyscallNumber, SyscallResult, CLOCK_MONOTONIC, CLOCK_REALTIME, MAP_EXECUTABLE,
+    MAP_WRITABLE, MAX_MEMORY_OBJECT_SIZE, MAX_MESSAGE_HANDLES, MAX_MESSAGE_SIZE, NO_TIMEOUT,
+    RECEIVE_NON_BLOCKING, SYSCALL_COUNT,
+};
+
+use crate::{
+    arch_api::thread::SavedRegisters,
+    channel::{create_channel, ChannelEndpoint, Message},
+    clock, futex,
+    handle::{Handle, KernelObject, Rights},
+    memory::align_address_down,
+    memory_object::MemoryObject,
+    paging::{get_physical_address, PagePermissions, PAGE_SIZE},
+    print,
+    process::{self, Process},
+    scheduler,
+    user_memory::active_address_space,
+    wall_clock,
+};
+
+type SyscallHandler = fn(arguments: [u64; 6]) -> SyscallResult;
+
+/// Indexed by `SyscallNumber`.
+static SYSCALL_HANDLERS: [SyscallHandler; SYSCALL_COUNT] = [
+    debug_print,
+    spawn,
+    exit,
+    wait,
+    create_channel_handler,
+    send,
+    receive,
+    duplicate,
+    close,
+    create_memory_object,
+    map_memory_object,
+    unmap_memory_object,
+    futex_wait,
+    futex_wake,
+    clock_get_time,
+    sleep_until,
+    sleep_for,
+];
+
+/// What happens to the calling thread after the handler returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AfterSyscall {
+    /// Return the result to the thread.
+    Return,
+    /// Block the thread, and make the same system call again when it is woken up (or the timeout passes).
+    BlockAndRestart { timeout: Option<Duration> },
+    /// Block the thread, returning the handler's result if the timeout passes before something else wakes it (with `scheduler::wake_thread_with_result`).
+    Block { timeout: Option<Duration> },
+    /// Stop the thread (the handler has already dealt with the process).
+    Exit,
+}
+
+// SAFETY: Only used while handling a system call, with interrupts disabled. User threads only run on the boot CPU, so there is never more than one system call at a time.
+static mut AFTER_SYSCALL: AfterSyscall = AfterSyscall::Return;
+
+/// Called by handlers which have to wait for something. The result they return is ignored.
+///
+/// Whatever they are waiting for must call `scheduler::wake_thread` with `scheduler::current_waiter()`.
+/// Handlers with a deadline should get the timeout from `timeout_until`, so that they fail once it has passed.
+fn block_and_restart(timeout: Option<Duration>) -> SyscallResult {
+    unsafe { AFTER_SYSCALL = AfterSyscall::BlockAndRestart { timeout } };
+    Ok(0)
+}
+
+/// Called by handlers which have to wait for something which gives them a result when it wakes them.
+///
+/// Returns `timeout_result`, which is what the system call returns if it times out.
+fn block(timeout: Option<Duration>, timeout_result: SyscallResult) -> SyscallResult {
+    unsafe { AFTER_SYSCALL = AfterSyscall::Block { timeout } };
+    timeout_result
+}
+
+/// Called by the architecture-specific code when a user program makes a system call.
+///
+/// Returns the registers which should be restored when going back to user mode.
+pub fn handle_syscall(saved_registers: *mut SavedRegisters) -> *mut SavedRegisters {
+    // SAFETY: The registers were just saved by the exception handler, so nothing else is using them.
+    let registers = unsafe { &mut *saved_registers };
+    let result = SyscallNumber::try_from(registers.syscall_number())
+        .and_then(|number| SYSCALL_HANDLERS[number as usize](registers.syscall_arguments()));
+    let after_syscall = unsafe { AFTER_SYSCALL };
+    unsafe { AFTER_SYSCALL = AfterSyscall::Return };
+    match after_syscall {
+        AfterSyscall::Return => {
+            registers.set_syscall_result(result);
+            saved_registers
+        }
+        AfterSyscall::BlockAndRestart { timeout } => {
+            registers.restart_syscall();
+            scheduler::block_current_thread(saved_registers, timeout)
+        }
+        AfterSyscall::Block { timeout } => {
+            registers.set_syscall_result(result);
+            scheduler::block_current_thread(saved_registers, timeout)
+        }
+        AfterSyscall::Exit => scheduler::exit_current_thread(),
+    }
+}
+
+/// Check that `length` bytes at `address` are part of the active address space, and writable if `writable` is set.
+fn check_user_memory(address: u64, length: u64, writable: bool) -> Result<(), SyscallError> {
+    // SAFETY: The reference isn't kept.
+    let address_space = unsafe { active_address_space() }.ok_or(SyscallError::InvalidAddress)?;
+    if address_space.is_accessible(address as usize, length as usize, writable) {
+        Ok(())
+    } else {
+        Err(SyscallError::InvalidAddress)
+    }
+}
+
+/// Get a slice of user memory, checking that the program is allowed to read it.
+///
+/// # Safety
+/// The memory must not change while the slice is in use.
+unsafe fn user_slice<'a>(address: u64, length: u64) -> Result<&'a [u8], SyscallError> {
+    if length == 0 {
+        return Ok(&[]);
+    }
+    check_user_memory(address, length, false)?;
+    Ok(slice::from_raw_parts(address as *const u8, length as usize))
+}
+
+/// Like `user_slice`, but for memory which the kernel is going to write to, so it must be writable.
+///
+/// # Safety
+/// See `user_slice`.
+unsafe fn user_slice_mut<'a>(address: u64, length: u64) -> Result<&'a mut [u8], SyscallError> {
+    if length == 0 {
+        return Ok(&mut []);
+    }
+    check_user_memory(address, length, true)?;
+    Ok(slice::from_raw_parts_mut(
+        address as *mut u8,
+        length as usize,
+    ))
+}
+
+/// Read an array of handles from user memory.
+///
+/// # Safety
+/// See `user_slice`.
+unsafe fn user_handles(address: u64, count: u64) -> Result<Vec<u64>, SyscallError> {
+    if count as usize > MAX_MESSAGE_HANDLES {
+        return Err(SyscallError::InvalidArgument);
+    }
+    Ok(user_slice(address, count * size_of::<u64>() as u64)?
+        .chunks_exact(size_of::<u64>())
+        .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+        .collect())
+}
+
+/// Write handles to an array in user memory.
+///
+/// # Safety
+/// See `user_slice`.
+unsafe fn write_user_handles(address: u64, handles: &[u64]) -> Result<(), SyscallError> {
+    let bytes = user_slice_mut(address, size_of_val(handles) as u64)?;
+    for (handle, destination) in handles.iter().zip(bytes.chunks_exact_mut(size_of::<u64>())) {
+        destination.copy_from_slice(&handle.to_ne_bytes());
+    }
+    Ok(())
+}
+
+fn current_process() -> Rc<RefCell<Process>> {
+    scheduler::current_process().expect("System call from a kernel thread")
+}
+
+fn get_channel(
+    process: &RefCell<Process>,
+    handle: u64,
+    required_rights: Rights,
+) -> Result<Rc<RefCell<ChannelEndpoint>>, SyscallError> {
+    match process.borrow().handles().get(handle, required_rights)? {
+        KernelObject::Channel(endpoint) => Ok(endpoint.clone()),
+        _ => Err(SyscallError::InvalidHandle),
+    }
+}
+
+fn get_memory_object(
+    process: &RefCell<Process>,
+    handle: u64,
+    required_rights: Rights,
+) -> Result<Rc<MemoryObject>, SyscallError> {
+    match process.borrow().handles().get(handle, required_rights)? {
+        KernelObject::MemoryObject(memory_object) => Ok(memory_object.clone()),
+        _ => Err(SyscallError::InvalidHandle),
+    }
+}
+
+fn get_process(
+    process: &RefCell<Process>,
+    handle: u64,
+    required_rights: Rights,
+) -> Result<Rc<RefCell<Process>>, SyscallError> {
+    match process.borrow().handles().get(handle, required_rights)? {
+        KernelObject::Process(process) => Ok(process.clone()),
+        _ => Err(SyscallError::InvalidHandle),
+    }
+}
+
+/// Works for deadlines as well as timeouts.
+fn timeout_from_nanoseconds(nanoseconds: u64) -> Option<Duration> {
+    if nanoseconds == NO_TIMEOUT {
+        None
+    } else {
+        Some(Duration::from_nanos(nanoseconds))
+    }
+}
+
+/// How long there is until `deadline`, failing with `TimedOut` if it has already passed.
+fn timeout_until(deadline: Option<Duration>) -> Result<Option<Duration>, SyscallError> {
+    let Some(deadline) = deadline else {
+        return Ok(None);
+    };
+    let now = clock::monotonic_time();
+    if deadline <= now {
+        return Err(SyscallError::TimedOut);
+    }
+    Ok(Some(deadline - now))
+}
+
+/// Get a UTF-8 string from user memory.
+///
+/// # Safety
+/// See `user_slice`.
+unsafe fn user_str<'a>(address: u64, length: u64) -> Result<&'a str, SyscallError> {
+    str::from_utf8(user_slice(address, length)?).map_err(|_| SyscallError::InvalidArgument)
+}
+
+fn debug_print(arguments: [u64; 6]) -> SyscallResult {
+    let [address, length, ..] = arguments;
+    let string = unsafe { user_str(address, length)? };
+    print!("{}", string);
+    Ok(length)
+}
+
+fn spawn(arguments: [u64; 6]) -> SyscallResult {
+    let [path_address, path_length, handles_address, handle_count, ..] = arguments;
+    // The path is copied onto the child's stack while the child's address space is active, so it can't stay in this process's memory.
+    let path = String::from(unsafe { user_str(path_address, path_length)? });
+    let handles = unsafe { user_handles(handles_address, handle_count)? };
+    let current_process = current_process();
+    // The handles are only taken once the child has started, so that they aren't lost if it fails.
+    current_process
+        .borrow()
+        .handles()
+        .validate_transfer(&handles)?;
+    let child = process::spawn(&path)?;
+    let mut current_process = current_process.borrow_mut();
+    for handle in current_process
+        .handles_mut()
+        .remove_for_transfer(&handles)?
+    {
+        child.borrow_mut().handles_mut().add(handle);
+    }
+    Ok(current_process.handles_mut().add(Handle {
+        object: KernelObject::Process(child),
+        rights: Rights::ALL,
+    }))
+}
+
+fn exit(arguments: [u64; 6]) -> SyscallResult {
+    let [exit_status, ..] = arguments;
+    process::mark_current_process_exited(exit_status);
+    unsafe { AFTER_SYSCALL = AfterSyscall::Exit };
+    Ok(0)
+}
+
+fn wait(arguments: [u64; 6]) -> SyscallResult {
+    let [handle, deadline, ..] = arguments;
+    let child = get_process(&current_process(), handle, Rights::READ)?;
+    let mut child = child.borrow_mut();
+    match child.exit_status() {
+        Some(exit_status) => Ok(exit_status),
+        None => {
+            let timeout = timeout_until(timeout_from_nanoseconds(deadline))?;
+            child.add_waiting_thread(scheduler::current_waiter());
+            block_and_restart(timeout)
+        }
+    }
+}
+
+fn create_channel_handler(arguments: [u64; 6]) -> SyscallResult {
+    let [handles_address, ..] = arguments;
+    // Check the address first, so that we don't create handles which can't be returned.
+    unsafe { user_slice_mut(handles_address, 2 * size_of::<u64>() as u64)? };
+    let (first, second) = create_channel();
+    let current_process = current_process();
+    let mut current_process = current_process.borrow_mut();
+    let handles = [first, second].map(|endpoint| {
+        current_process.handles_mut().add(Handle {
+            object: KernelObject::Channel(endpoint),
+            rights: Rights::ALL,
+        })
+    });
+    unsafe { write_user_handles(handles_address, &handles)? };
+    Ok(0)
+}
+
+fn send(arguments: [u64; 6]) -> SyscallResult {
+    let [handle, data_address, data_length, handles_address, handle_count, _] = arguments;
+    if data_length as usize > MAX_MESSAGE_SIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let data = unsafe { user_slice(data_address, data_length)? }.to_vec();
+    let handles = unsafe { user_handles(handles_address, handle_count)? };
+    let current_process = current_process();
+    let endpoint = get_channel(&current_process, handle, Rights::WRITE)?;
+    // Either end of the channel would keep itself alive forever, even if it was sent with a different handle.
+    for &sent_handle in &handles {
+        if let Ok(KernelObject::Channel(sent_endpoint)) = current_process
+            .borrow()
+            .handles()
+            .get(sent_handle, Rights::empty())
+        {
+            if Rc::ptr_eq(sent_endpoint, &endpoint) || endpoint.borrow().is_peer(sent_endpoint) {
+                return Err(SyscallError::InvalidHandle);
+            }
+        }
+    }
+    let endpoint = endpoint.borrow();
+    endpoint.check_can_send()?;
+    let handles = current_process
+        .borrow_mut()
+        .handles_mut()
+        .remove_for_transfer(&handles)?;
+    endpoint.send(Message { data, handles })?;
+    Ok(0)
+}
+
+fn receive(arguments: [u64; 6]) -> SyscallResult {
+    let [handle, data_address, handles_address, capacities, flags, deadline] = arguments;
+    let data_capacity = capacities & 0xffff_ffff;
+    let handle_capacity = capacities >> 32;
+    let current_process = current_process();
+    let endpoint = get_channel(&current_process, handle, Rights::READ)?;
+    let mut endpoint = endpoint.borrow_mut();
+    let Some(message) = endpoint.next_message()? else {
+        if flags & RECEIVE_NON_BLOCKING != 0 {
+            return Err(SyscallError::WouldBlock);
+        }
+        let timeout = timeout_until(timeout_from_nanoseconds(deadline))?;
+        endpoint.add_waiting_thread(scheduler::current_waiter());
+        return block_and_restart(timeout);
+    };
+    let data_length = message.data.len() as u64;
+    let handle_count = message.handles.len() as u64;
+    if data_length > data_capacity || handle_count > handle_capacity {
+        return Err(SyscallError::BufferTooSmall);
+    }
+    let data_buffer = unsafe { user_slice_mut(data_address, data_length)? };
+    // Check this before taking the message, so that it isn't lost.
+    unsafe { user_slice_mut(handles_address, handle_count * size_of::<u64>() as u64)? };
+
+    let message = endpoint.take_next_message().unwrap();
+    data_buffer.copy_from_slice(&message.data);
+    let handles: Vec<u64> = {
+        let mut current_process = current_process.borrow_mut();
+        message
+            .handles
+            .into_iter()
+            .map(|handle| current_process.handles_mut().add(handle))
+            .collect()
+    };
+    unsafe { write_user_handles(handles_address, &handles)? };
+    Ok(data_length | handle_count << 32)
+}
+
+fn duplicate(arguments: [u64; 6]) -> SyscallResult {
+    let [handle, rights, ..] = arguments;
+    let rights = Rights::from_bits(rights).ok_or(SyscallError::InvalidArgument)?;
+    current_process()
+        .borrow_mut()
+        .handles_mut()
+        .duplicate(handle, rights)
+}
+
+fn close(arguments: [u64; 6]) -> SyscallResult {
+    let [handle, ..] = arguments;
+    let removed = current_process()
+        .borrow_mut()
+        .handles_mut()
+        .remove(handle)?;
+    // The process must not be borrowed when this is dropped, since dropping the object could need it (for example to wake threads).
+    drop(removed);
+    Ok(0)
+}
+
+fn create_memory_object(arguments: [u64; 6]) -> SyscallResult {
+    let [size, ..] = arguments;
+    if size == 0 || size as usize > MAX_MEMORY_OBJECT_SIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let memory_object = MemoryObject::new(size as usize).ok_or(SyscallError::OutOfMemory)?;
+    Ok(current_process().borrow_mut().handles_mut().add(Handle {
+        object: KernelObject::MemoryObject(Rc::new(memory_object)),
+        rights: Rights::ALL,
+    }))
+}
+
+fn map_memory_object(arguments: [u64; 6]) -> SyscallResult {
+    let [handle, address, flags, ..] = arguments;
+    if flags & !(MAP_WRITABLE | MAP_EXECUTABLE) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let writable = flags & MAP_WRITABLE != 0;
+    let executable = flags & MAP_EXECUTABLE != 0;
+    let required_rights = if writable {
+        Rights::READ | Rights::WRITE
+    } else {
+        Rights::READ
+    };
+    let memory_object = get_memory_object(&current_process(), handle, required_rights)?;
+    let address_space =
+        unsafe { active_address_space() }.expect("System call without an address space");
+    if !address_space.can_reserve(address as usize, memory_object.size()) {
+        return Err(SyscallError::InvalidAddress);
+    }
+    address_space.map_memory_object(
+        address as usize,
+        memory_object,
+        PagePermissions::new(true, writable, executable),
+    );
+    Ok(0)
+}
+
+fn unmap_memory_object(arguments: [u64; 6]) -> SyscallResult {
+    let [address, ..] = arguments;
+    let address_space =
+        unsafe { active_address_space() }.expect("System call without an address space");
+    if address_space.unmap_memory_object(address as usize) {
+        Ok(0)
+    } else {
+        Err(SyscallError::InvalidAddress)
+    }
+}
+
+/// Read the value of a futex, and find the physical address which identifies it.
+fn read_futex(address: u64) -> Result<(u32, usize), SyscallError> {
+    if address % size_of::<u32>() as u64 != 0 {
+        return Err(SyscallError::InvalidAddress);
+    }
+    let bytes = unsafe { user_slice(address, size_of::<u32>() as u64)? };
+    // Reading it first makes sure that it has some physical memory.
+    let value = unsafe { ptr::read_volatile(bytes.as_ptr() as *const u32) };
+    let address = address as usize;
+    // Not every architecture's `get_physical_address` includes the offset into the page.
+    let physical_page = align_address_down(get_physical_address(address), PAGE_SIZE);
+    Ok((value, physical_page + address % PAGE_SIZE))
+}
+
+fn futex_wait(arguments: [u64; 6]) -> SyscallResult {
+    let [address, expected_value, timeout, ..] = arguments;
+    let (value, physical_address) = read_futex(address)?;
+    if value as u64 != expected_value {
+        return Err(SyscallError::WouldBlock);
+    }
+    futex::add_waiting_thread(physical_address, scheduler::current_waiter());
+    block(
+        timeout_from_nanoseconds(timeout),
+        Err(SyscallError::TimedOut),
+    )
+}
+
+fn futex_wake(arguments: [u64; 6]) -> SyscallResult {
+    let [address, count, ..] = arguments;
+    let (_, physical_address) = read_futex(address)?;
+    Ok(futex::wake(physical_address, count as usize) as u64)
+}
+
+fn clock_get_time(arguments: [u64; 6]) -> SyscallResult {
+    let [clock, ..] = arguments;
+    match clock {
+        // This won't overflow for hundreds of years.
+        CLOCK_MONOTONIC => Ok(clock::monotonic_time().as_nanos() as u64),
+        CLOCK_REALTIME => wall_clock::now()
+            .map(|time| time.as_nanos() as u64)
+            .ok_or(SyscallError::Unavailable),
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+fn sleep_until(arguments: [u64; 6]) -> SyscallResult {
+    let [deadline, ..] = arguments;
+    match timeout_until(timeout_from_nanoseconds(deadline)) {
+        // Nothing else wakes a sleeping thread, so it only returns once the timeout has passed.
+        Ok(timeout) => block(timeout, Ok(0)),
+        Err(SyscallError::TimedOut) => Ok(0),
+        Err(error) => Err(error),
+    }
+}
+
+fn sleep_for(arguments: [u64; 6]) -> SyscallResult {
+    let [timeout, ..] = arguments;
+    match timeout_from_nanoseconds(timeout) {
+        Some(Duration::ZERO) => Ok(0),
+        timeout => block(timeout, Ok(0)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_address_test() {
+        // There is no active address space, so no memory belongs to the program.
+        assert_eq!(
+            debug_print([0x10000, 16, 0, 0, 0, 0]),
+            Err(SyscallError::InvalidAddress)
+        );
+        assert_eq!(
+            futex_wake([0x10000, 1, 0, 0, 0, 0]),
+            Err(SyscallError::InvalidAddress)
+        );
+        assert_eq!(debug_print([0, 0, 0, 0, 0, 0]), Ok(0));
+    }
+}
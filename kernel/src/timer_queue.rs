@@ -0,0 +1,138 @@
+//! Deadline timers, which call some code once the monotonic clock reaches a certain time.
+//!
+//! The hardware timer is only ever set to go off at the earliest deadline, so there aren't any interrupts when nothing is due (and time slices, sleeping and timeouts all work the same way).
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::time::Duration;
+
+use crate::{arch_api::timer::set_deadline, clock::monotonic_time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+type Callback = Box<dyn FnOnce()>;
+
+struct TimerQueue {
+    /// Sorted by deadline, with the id to keep timers with the same deadline apart (and in the order they were added).
+    timers: BTreeMap<(Duration, TimerId), Callback>,
+    /// So that timers can be found to cancel them.
+    deadlines: BTreeMap<TimerId, Duration>,
+    next_id: u64,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        Self {
+            timers: BTreeMap::new(),
+            deadlines: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn add(&mut self, deadline: Duration, callback: Callback) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.timers.insert((deadline, id), callback);
+        self.deadlines.insert(id, deadline);
+        id
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        match self.deadlines.remove(&id) {
+            Some(deadline) => {
+                self.timers.remove(&(deadline, id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take out the earliest timer, if its deadline is at or before `now`.
+    fn take_expired(&mut self, now: Duration) -> Option<Callback> {
+        let entry = self.timers.first_entry()?;
+        let (deadline, id) = *entry.key();
+        if deadline > now {
+            return None;
+        }
+        self.deadlines.remove(&id);
+        Some(entry.remove())
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.timers.keys().next().map(|(deadline, _)| *deadline)
+    }
+}
+
+// SAFETY: Only used with interrupts disabled, on the boot CPU. The other CPUs are parked with interrupts disabled, so they never handle timer interrupts.
+static mut TIMERS: TimerQueue = TimerQueue::new();
+
+fn program_hardware_timer() {
+    set_deadline(unsafe { TIMERS.next_deadline() });
+}
+
+/// Call `callback` (from the timer interrupt) once the monotonic clock reaches `deadline`.
+///
+/// If the deadline has already passed, it is called at the next timer interrupt, which will be very soon.
+pub fn add_timer(deadline: Duration, callback: impl FnOnce() + 'static) -> TimerId {
+    let id = unsafe { TIMERS.add(deadline, Box::new(callback)) };
+    program_hardware_timer();
+    id
+}
+
+/// Stop a timer from going off.
+///
+/// Returns false if there is no such timer (for example because it has already gone off).
+pub fn cancel_timer(id: TimerId) -> bool {
+    let cancelled = unsafe { TIMERS.cancel(id) };
+    program_hardware_timer();
+    cancelled
+}
+
+/// Run the callbacks for all of the timers which are due, and set the hardware timer for the next one.
+///
+/// Called from the timer interrupt.
+pub fn handle_timer_interrupt() {
+    // The callbacks can add and cancel timers, so each one is taken out before it is called.
+    while let Some(callback) = unsafe { TIMERS.take_expired(monotonic_time()) } {
+        callback();
+    }
+    program_hardware_timer();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    #[test]
+    fn timer_queue_test() {
+        let mut queue = TimerQueue::new();
+        let called = Rc::new(RefCell::new(Vec::new()));
+        let add = |queue: &mut TimerQueue, deadline: u64, name: &'static str| {
+            let called = called.clone();
+            queue.add(
+                Duration::from_millis(deadline),
+                Box::new(move || called.borrow_mut().push(name)),
+            )
+        };
+        add(&mut queue, 20, "second");
+        add(&mut queue, 10, "first");
+        let cancelled = add(&mut queue, 15, "cancelled");
+        add(&mut queue, 20, "third");
+        assert!(queue.cancel(cancelled));
+        assert!(!queue.cancel(cancelled));
+        assert_eq!(queue.next_deadline(), Some(Duration::from_millis(10)));
+
+        let now = Duration::from_millis(20);
+        while let Some(callback) = queue.take_expired(now) {
+            callback();
+        }
+        assert_eq!(*called.borrow(), ["first", "second", "third"]);
+        assert_eq!(queue.next_deadline(), None);
+
+        add(&mut queue, 30, "later");
+        assert!(queue.take_expired(now).is_none());
+        assert_eq!(queue.next_deadline(), Some(Duration::from_millis(30)));
+    }
+}
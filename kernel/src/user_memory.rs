@@ -1,28 +1,368 @@
+use alloc::{collections::BTreeMap, rc::Rc, vec::Vec};
+use core::ptr::null_mut;
+
 use crate::{
-    arch_api::paging::is_valid_user_address,
-    paging::{map_block, MemoryType, PagePermissions},
-    physical_memory_manager::{allocate_block_address, BLOCK_SIZE},
+    arch_api::paging::{
+        activate_user_page_table, create_user_page_table, destroy_user_page_table,
+        get_active_user_page_table, is_valid_user_address,
+    },
+    heap::map_physical_memory,
+    memory::align_address_down,
+    memory_object::MemoryObject,
+    paging::{change_block_permissions, map_block, unmap_block, MemoryType, PagePermissions},
+    physical_memory_manager::{allocate_block_address, mark_as_free, BLOCK_SIZE},
 };
 
-pub fn allocate_user_memory_at(virtual_address: usize, size: usize, permissions: PagePermissions) {
-    assert_eq!(
-        virtual_address % BLOCK_SIZE,
-        0,
-        "virtual_address must be BLOCK_SIZE aligned"
-    );
-    assert!(
-        is_valid_user_address(virtual_address),
-        "Invalid virtual address {}",
-        virtual_address
-    );
-    assert!(
-        is_valid_user_address(virtual_address + size),
-        "Invalid virtual address {}",
-        virtual_address + size
-    );
-
-    for virtual_block_address in (virtual_address..virtual_address + size).step_by(BLOCK_SIZE) {
-        let physical_address = allocate_block_address().expect("Out of memory");
-        map_block(virtual_block_address, physical_address, MemoryType::Normal, permissions);
+/// A range of virtual memory which the program is allowed to use, but which might not have physical memory behind it yet.
+struct Region {
+    start: usize,
+    size: usize,
+    /// Guard regions have no permissions, and are never given physical memory.
+    permissions: Option<PagePermissions>,
+    /// Shared regions are mapped to a memory object straight away, rather than getting their own memory when they are used.
+    memory_object: Option<Rc<MemoryObject>>,
+}
+
+impl Region {
+    fn contains(&self, address: usize) -> bool {
+        address >= self.start && address - self.start < self.size
+    }
+}
+
+/// The lower half of the virtual address space belonging to a program, with its own root page table.
+///
+/// The kernel (in the higher half) is mapped in every address space.
+///
+/// Memory is reserved up front, but physical memory is only allocated (by the page fault handler) when it is first used.
+pub struct AddressSpace {
+    page_table: usize,
+    regions: Vec<Region>,
+    /// The physical block behind each virtual block which has been used (not including memory objects). These are freed along with the address space.
+    mapped_blocks: BTreeMap<usize, usize>,
+}
+
+/// The address space which was most recently activated, so the page fault handler can find its regions.
+///
+/// Address spaces must not be moved while they are active, or this will point to the wrong place.
+static mut ACTIVE_ADDRESS_SPACE: *mut AddressSpace = null_mut();
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        Self {
+            page_table: create_user_page_table(),
+            regions: Vec::new(),
+            mapped_blocks: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        get_active_user_page_table() == self.page_table
+    }
+
+    pub fn activate(&mut self) {
+        // SAFETY: The page table lives as long as we do.
+        unsafe {
+            activate_user_page_table(self.page_table);
+            ACTIVE_ADDRESS_SPACE = self;
+        }
+    }
+
+    /// Run `function` with this address space active, and then switch back to whichever one was active before.
+    pub fn with_active<T>(&mut self, function: impl FnOnce(&mut Self) -> T) -> T {
+        if self.is_active() {
+            return function(self);
+        }
+        let previous_page_table = get_active_user_page_table();
+        let previous_address_space = unsafe { ACTIVE_ADDRESS_SPACE };
+        self.activate();
+        let result = function(self);
+        // SAFETY: It was active a moment ago, so it is still valid.
+        unsafe {
+            activate_user_page_table(previous_page_table);
+            ACTIVE_ADDRESS_SPACE = previous_address_space;
+        }
+        result
+    }
+
+    /// Reserve a region of memory, which will be backed by physical memory when it is first accessed.
+    pub fn reserve_memory_at(
+        &mut self,
+        virtual_address: usize,
+        size: usize,
+        permissions: PagePermissions,
+    ) {
+        self.add_region(virtual_address, size, Some(permissions), None);
+    }
+
+    /// Reserve a region of memory which can never be accessed, so that nothing else can be put there.
+    ///
+    /// This is useful for catching stack overflows.
+    pub fn reserve_guard_at(&mut self, virtual_address: usize, size: usize) {
+        self.add_region(virtual_address, size, None, None);
+    }
+
+    /// Check whether a region could be added at `virtual_address` without overlapping anything.
+    pub fn can_reserve(&self, virtual_address: usize, size: usize) -> bool {
+        virtual_address % BLOCK_SIZE == 0
+            && virtual_address
+                .checked_add(size)
+                .is_some_and(is_valid_user_address)
+            && is_valid_user_address(virtual_address)
+            && !self.overlaps_region(virtual_address, size)
+    }
+
+    /// Check whether all of `[address, address + size)` is in regions which the program can access, and can write to if `writable` is set.
+    ///
+    /// The memory might not have been given physical memory yet, but the page fault handler will do that when it is used.
+    pub fn is_accessible(&self, address: usize, size: usize, writable: bool) -> bool {
+        regions_cover(&self.regions, address, size, writable)
+    }
+
+    fn overlaps_region(&self, virtual_address: usize, size: usize) -> bool {
+        self.regions.iter().any(|region| {
+            virtual_address < region.start + region.size && region.start < virtual_address + size
+        })
+    }
+
+    /// Map all of a memory object at `virtual_address`. The object stays alive at least until it is unmapped.
+    pub fn map_memory_object(
+        &mut self,
+        virtual_address: usize,
+        memory_object: Rc<MemoryObject>,
+        permissions: PagePermissions,
+    ) {
+        self.add_region(
+            virtual_address,
+            memory_object.size(),
+            Some(permissions),
+            Some(memory_object.clone()),
+        );
+        self.with_active(|_| {
+            for (index, &physical_address) in memory_object.blocks().iter().enumerate() {
+                map_block(
+                    virtual_address + index * BLOCK_SIZE,
+                    physical_address,
+                    MemoryType::Normal,
+                    permissions,
+                );
+            }
+        });
+    }
+
+    /// Unmap the memory object which was mapped at `virtual_address`.
+    ///
+    /// Returns false if there isn't one there.
+    pub fn unmap_memory_object(&mut self, virtual_address: usize) -> bool {
+        let Some(index) = self
+            .regions
+            .iter()
+            .position(|region| region.start == virtual_address && region.memory_object.is_some())
+        else {
+            return false;
+        };
+        let region = self.regions.remove(index);
+        self.with_active(|_| {
+            for block_address in (region.start..region.start + region.size).step_by(BLOCK_SIZE) {
+                unmap_block(block_address);
+            }
+        });
+        true
+    }
+
+    fn add_region(
+        &mut self,
+        virtual_address: usize,
+        size: usize,
+        permissions: Option<PagePermissions>,
+        memory_object: Option<Rc<MemoryObject>>,
+    ) {
+        assert_eq!(
+            virtual_address % BLOCK_SIZE,
+            0,
+            "virtual_address must be BLOCK_SIZE aligned"
+        );
+        assert!(
+            is_valid_user_address(virtual_address),
+            "Invalid virtual address {}",
+            virtual_address
+        );
+        assert!(
+            is_valid_user_address(virtual_address + size),
+            "Invalid virtual address {}",
+            virtual_address + size
+        );
+        assert!(
+            !self.overlaps_region(virtual_address, size),
+            "Memory at {:x} is already reserved",
+            virtual_address
+        );
+
+        self.regions.push(Region {
+            start: virtual_address,
+            size,
+            permissions,
+            memory_object,
+        });
+    }
+
+    /// Change the permissions of a region which was reserved with `reserve_memory_at`.
+    pub fn change_permissions(&mut self, virtual_address: usize, permissions: PagePermissions) {
+        let region = self
+            .regions
+            .iter_mut()
+            .find(|region| region.start == virtual_address && region.memory_object.is_none())
+            .expect("Changing the permissions of memory which wasn't reserved");
+        assert!(
+            region.permissions.is_some(),
+            "Changing the permissions of a guard region"
+        );
+        region.permissions = Some(permissions);
+        let region_end = region.start + region.size;
+        let blocks_to_change: Vec<usize> = self
+            .mapped_blocks
+            .range(virtual_address..region_end)
+            .map(|(&virtual_block_address, _)| virtual_block_address)
+            .collect();
+        self.with_active(|_| {
+            for virtual_block_address in blocks_to_change {
+                change_block_permissions(virtual_block_address, MemoryType::Normal, permissions);
+            }
+        });
+    }
+
+    /// Give the block containing `address` some (zeroed) physical memory, if it is part of a region.
+    ///
+    /// Returns false if the address wasn't reserved, or there's no memory left to give it.
+    fn map_reserved_block(&mut self, address: usize) -> bool {
+        let Some(permissions) = self
+            .regions
+            .iter()
+            .find(|region| region.contains(address) && region.memory_object.is_none())
+            .and_then(|region| region.permissions)
+        else {
+            return false;
+        };
+        let virtual_block_address = align_address_down(address, BLOCK_SIZE);
+        if self.mapped_blocks.contains_key(&virtual_block_address) {
+            // It's already there, so the fault was about something else (like permissions).
+            return false;
+        }
+        // Running out of memory is the program's problem, so the fault handler kills it rather than the kernel panicking.
+        let Some(physical_address) = allocate_block_address() else {
+            return false;
+        };
+        // The block could have anything in it, and we don't want programs to see each other's data.
+        unsafe {
+            map_physical_memory(
+                physical_address,
+                BLOCK_SIZE,
+                MemoryType::Normal,
+                PagePermissions::KERNEL_READ_WRITE,
+            )
+            .fill(0);
+        }
+        map_block(
+            virtual_block_address,
+            physical_address,
+            MemoryType::Normal,
+            permissions,
+        );
+        self.mapped_blocks
+            .insert(virtual_block_address, physical_address);
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            if ACTIVE_ADDRESS_SPACE == self as *mut Self {
+                ACTIVE_ADDRESS_SPACE = null_mut();
+            }
+        }
+        for &physical_address in self.mapped_blocks.values() {
+            mark_as_free(physical_address);
+        }
+        // SAFETY: Nobody else has our page table, and destroy_user_page_table checks that it isn't active.
+        unsafe { destroy_user_page_table(self.page_table) };
+    }
+}
+
+/// Check whether `[address, address + size)` is covered by non-guard regions, which must be writable if `writable` is set.
+fn regions_cover(regions: &[Region], mut address: usize, size: usize, writable: bool) -> bool {
+    let Some(end) = address.checked_add(size) else {
+        return false;
+    };
+    while address < end {
+        let Some(region) = regions.iter().find(|region| region.contains(address)) else {
+            return false;
+        };
+        match region.permissions {
+            Some(permissions) if permissions.writable || !writable => {}
+            _ => return false,
+        }
+        address = region.start + region.size;
+    }
+    true
+}
+
+/// The address space of the user thread which is running, for system calls which change it.
+///
+/// # Safety
+/// The reference must not be kept after switching to another address space.
+pub unsafe fn active_address_space<'a>() -> Option<&'a mut AddressSpace> {
+    ACTIVE_ADDRESS_SPACE
+        .as_mut()
+        .filter(|address_space| address_space.is_active())
+}
+
+/// Called by the page fault handlers when `address` isn't mapped.
+///
+/// Returns true if the address was reserved in the active address space, in which case it is now mapped and the faulting instruction can be tried again.
+///
+/// The handlers run with interrupts disabled, so the active address space can't change underneath this.
+pub fn handle_page_fault(address: usize) -> bool {
+    if !is_valid_user_address(address) {
+        return false;
+    }
+    // SAFETY: The address space is active, so it hasn't been freed or moved.
+    let Some(address_space) = (unsafe { active_address_space() }) else {
+        return false;
+    };
+    address_space.map_reserved_block(address)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(start: usize, size: usize, permissions: Option<PagePermissions>) -> Region {
+        Region {
+            start,
+            size,
+            permissions,
+            memory_object: None,
+        }
+    }
+
+    #[test]
+    fn regions_cover_test() {
+        let regions = [
+            region(0x10000, 0x10000, Some(PagePermissions::USER_READ_ONLY)),
+            region(0x20000, 0x10000, Some(PagePermissions::USER_READ_WRITE)),
+            region(0x30000, 0x10000, None),
+            region(0x50000, 0x10000, Some(PagePermissions::USER_READ_WRITE)),
+        ];
+        assert!(regions_cover(&regions, 0x10000, 0x10000, false));
+        assert!(!regions_cover(&regions, 0x10000, 0x10000, true));
+        assert!(regions_cover(&regions, 0x1fff0, 0x20, false));
+        assert!(!regions_cover(&regions, 0x1fff0, 0x20, true));
+        assert!(regions_cover(&regions, 0x28000, 0x8000, true));
+        // Guard regions and gaps can't be used, even partly.
+        assert!(!regions_cover(&regions, 0x2fff0, 0x20, false));
+        assert!(!regions_cover(&regions, 0x3fff0, 0x20, false));
+        assert!(!regions_cover(&regions, 0x40000, 0x100, false));
+        assert!(!regions_cover(&regions, 0x5fff0, 0x20, true));
+        assert!(!regions_cover(&regions, 0x50000, usize::MAX, false));
     }
 }
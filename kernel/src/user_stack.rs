@@ -0,0 +1,141 @@
+//! Creates the stack which a program starts with, in the layout described by [`common::initial_stack`].
+
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr, slice};
+
+use common::{elf::ElfBinary, initial_stack::AuxiliaryVectorType};
+
+use crate::{
+    arch_api::asm::read_cycle_counter,
+    memory::align_address_down,
+    paging::{PagePermissions, PAGE_SIZE},
+    physical_memory_manager::BLOCK_SIZE,
+    user_memory::AddressSpace,
+};
+
+/// The stack grows down from here. It is well away from where programs are usually linked.
+pub const USER_STACK_TOP: usize = 0x0000_7000_0000_0000;
+pub const USER_STACK_SIZE: usize = 1024 * 1024;
+/// Left unmapped below the stack so that overflowing it causes a page fault.
+const GUARD_SIZE: usize = BLOCK_SIZE;
+
+/// The arguments and environment have to fit in the top of the stack (with plenty left over for the program).
+pub const MAX_INITIAL_DATA_SIZE: usize = USER_STACK_SIZE / 4;
+
+/// Not cryptographically secure, but good enough to give each program something different.
+fn random_bytes() -> [u8; 16] {
+    // splitmix64
+    let mut state = read_cycle_counter();
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&next().to_ne_bytes());
+    bytes[8..].copy_from_slice(&next().to_ne_bytes());
+    bytes
+}
+
+/// # Safety
+/// The memory below `stack_pointer` must be writable.
+unsafe fn push_bytes(stack_pointer: &mut usize, bytes: &[u8], alignment: usize) -> usize {
+    *stack_pointer = align_address_down(*stack_pointer - bytes.len(), alignment);
+    ptr::copy_nonoverlapping(bytes.as_ptr(), *stack_pointer as *mut u8, bytes.len());
+    *stack_pointer
+}
+
+/// # Safety
+/// The memory below `stack_pointer` must be writable.
+unsafe fn push_string(stack_pointer: &mut usize, string: &str) -> u64 {
+    push_bytes(stack_pointer, &[0], 1);
+    push_bytes(stack_pointer, string.as_bytes(), 1) as u64
+}
+
+/// Reserve a stack in the address space and fill in the arguments, environment and auxiliary vector.
+///
+/// Returns the stack pointer which the program should start with.
+///
+/// The strings are copied while `address_space` is active, so they have to be in kernel memory rather than another program's.
+pub fn create_user_stack(
+    address_space: &mut AddressSpace,
+    elf: &ElfBinary,
+    file: &[u8],
+    arguments: &[&str],
+    environment: &[&str],
+) -> usize {
+    let strings_size: usize = arguments
+        .iter()
+        .chain(environment)
+        .map(|string| string.len() + 1)
+        .sum();
+    assert!(
+        strings_size <= MAX_INITIAL_DATA_SIZE,
+        "Too many arguments for the initial stack"
+    );
+
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    address_space.reserve_guard_at(stack_bottom - GUARD_SIZE, GUARD_SIZE);
+    // Like with ELF segments, we have to be able to write to it first.
+    address_space.reserve_memory_at(
+        stack_bottom,
+        USER_STACK_SIZE,
+        PagePermissions::KERNEL_READ_WRITE,
+    );
+
+    let program_headers = &file[elf.program_header_offset
+        ..elf.program_header_offset
+            + elf.program_header_entry_size * elf.program_header_entry_count];
+
+    let stack_pointer = address_space.with_active(|_| unsafe {
+        let mut stack_pointer = USER_STACK_TOP;
+        let argument_pointers: Vec<u64> = arguments
+            .iter()
+            .map(|argument| push_string(&mut stack_pointer, argument))
+            .collect();
+        let environment_pointers: Vec<u64> = environment
+            .iter()
+            .map(|variable| push_string(&mut stack_pointer, variable))
+            .collect();
+        let random_bytes_address = push_bytes(&mut stack_pointer, &random_bytes(), 16);
+        let program_headers_address =
+            push_bytes(&mut stack_pointer, program_headers, size_of::<u64>());
+
+        let auxiliary_vector = [
+            (
+                AuxiliaryVectorType::ProgramHeaders,
+                program_headers_address as u64,
+            ),
+            (
+                AuxiliaryVectorType::ProgramHeaderSize,
+                elf.program_header_entry_size as u64,
+            ),
+            (
+                AuxiliaryVectorType::ProgramHeaderCount,
+                elf.program_header_entry_count as u64,
+            ),
+            (AuxiliaryVectorType::PageSize, PAGE_SIZE as u64),
+            (AuxiliaryVectorType::Entry, elf.entrypoint as u64),
+            (AuxiliaryVectorType::Random, random_bytes_address as u64),
+            (AuxiliaryVectorType::Null, 0),
+        ];
+        let mut words = Vec::new();
+        words.push(argument_pointers.len() as u64);
+        words.extend_from_slice(&argument_pointers);
+        words.push(0);
+        words.extend_from_slice(&environment_pointers);
+        words.push(0);
+        for (entry_type, value) in auxiliary_vector {
+            words.push(entry_type as u64);
+            words.push(value);
+        }
+        let word_bytes =
+            slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * size_of::<u64>());
+        push_bytes(&mut stack_pointer, word_bytes, 16)
+    });
+
+    address_space.change_permissions(stack_bottom, PagePermissions::new(true, true, false));
+    stack_pointer
+}
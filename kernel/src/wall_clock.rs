@@ -0,0 +1,142 @@
+//! The wall clock, which is the real (UTC) time, as read from the RTC at boot and kept going by the monotonic clock.
+
+use core::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use crate::clock::monotonic_time;
+
+/// A calendar date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    /// From 1 to 12.
+    pub month: u8,
+    /// From 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// These use the algorithms from http://howardhinnant.github.io/date_algorithms.html, with years starting in March so that leap days come at the end.
+// Dates before 1970 aren't supported, since RTCs can't go back that far anyway.
+
+impl DateTime {
+    /// The time since the Unix epoch (1970-01-01 00:00:00 UTC).
+    pub fn to_unix_time(self) -> Duration {
+        let month = self.month as u64;
+        let year = self.year as u64 - (month <= 2) as u64;
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        // 719468 days from 0000-03-01 to 1970-01-01.
+        let days = era * 146097 + day_of_era - 719468;
+        let seconds = self.hour as u64 * 60 * 60 + self.minute as u64 * 60 + self.second as u64;
+        Duration::from_secs(days * SECONDS_PER_DAY + seconds)
+    }
+
+    pub fn from_unix_time(time: Duration) -> Self {
+        let days = time.as_secs() / SECONDS_PER_DAY + 719468;
+        let seconds = time.as_secs() % SECONDS_PER_DAY;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = era * 400 + year_of_era + (month <= 2) as u64;
+        Self {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / (60 * 60)) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// SAFETY: Only written once during boot, before anything reads it.
+/// The Unix time when the monotonic clock started, or `None` if there isn't an RTC.
+static mut BOOT_TIME: Option<Duration> = None;
+
+/// Start the wall clock at `time` (since the Unix epoch), which should have just been read from the RTC.
+pub fn initialize(time: Duration) {
+    unsafe { BOOT_TIME = Some(time.saturating_sub(monotonic_time())) };
+}
+
+/// The time since the Unix epoch, or `None` if the wall clock wasn't initialized (because there isn't an RTC).
+pub fn now() -> Option<Duration> {
+    unsafe { BOOT_TIME }.map(|boot_time| boot_time + monotonic_time())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unix_time_test() {
+        let epoch = DateTime {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        assert_eq!(epoch.to_unix_time(), Duration::ZERO);
+        assert_eq!(DateTime::from_unix_time(Duration::ZERO), epoch);
+
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        assert_eq!(leap_day.to_unix_time(), Duration::from_secs(1_709_213_862));
+        assert_eq!(
+            DateTime::from_unix_time(Duration::from_secs(1_709_213_862)),
+            leap_day
+        );
+
+        let end_of_century = DateTime {
+            year: 2099,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert_eq!(
+            DateTime::from_unix_time(end_of_century.to_unix_time()),
+            end_of_century
+        );
+        assert_eq!(
+            DateTime::from_unix_time(end_of_century.to_unix_time() + Duration::from_secs(1)).year,
+            2100
+        );
+    }
+}
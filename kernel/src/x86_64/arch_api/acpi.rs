@@ -1,7 +1,7 @@
 use alloc::vec::Vec;
 
 use crate::{
-    acpi::{madt::MadtInfo, AcpiTableHandle},
+    acpi::{fadt::FadtInfo, madt::MadtInfo, AcpiTableHandle},
     arch::acpi::hpet::HpetInfo,
 };
 
@@ -29,17 +29,22 @@ pub fn get_root_table_address() -> Option<usize> {
 
 pub struct AcpiInfo {
     pub madt: MadtInfo,
+    pub fadt: FadtInfo,
     pub hpet: HpetInfo,
 }
 
 pub fn handle_acpi_info(acpi_tables: Vec<AcpiTableHandle>) -> AcpiInfo {
     let mut madt = None;
+    let mut fadt = None;
     let mut hpet = None;
     for table in acpi_tables {
         match table.identifier() {
             b"APIC" => {
                 madt = Some(MadtInfo::new(&table));
             }
+            b"FACP" => {
+                fadt = Some(FadtInfo::new(&table));
+            }
             b"HPET" => {
                 hpet = Some(HpetInfo::new(&table));
             }
@@ -48,10 +53,12 @@ pub fn handle_acpi_info(acpi_tables: Vec<AcpiTableHandle>) -> AcpiInfo {
     }
 
     crate::println!("MADT: {:?}", madt);
+    crate::println!("FADT: {:?}", fadt);
     crate::println!("HPET: {:?}", hpet);
 
     AcpiInfo {
         madt: madt.expect("MADT not found"),
+        fadt: fadt.expect("FADT not found"),
         hpet: hpet.expect("HPET not found"),
     }
 }
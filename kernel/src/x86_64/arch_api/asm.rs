@@ -5,3 +5,44 @@ pub fn memory_barrier() {
         asm!("mfence");
     }
 }
+
+/// # Safety
+/// Code which relies on interrupts staying enabled (such as anything waiting for the timer) will stop working until they are enabled again.
+pub unsafe fn disable_interrupts() {
+    asm!("cli", options(nomem, nostack));
+}
+
+/// Disable interrupts, returning whether they were enabled before.
+pub fn save_and_disable_interrupts() -> bool {
+    let flags: u64;
+    // This isn't `nomem`, so that memory accesses can't be moved to before interrupts are disabled.
+    unsafe { asm!("pushfq", "pop {}", "cli", out(reg) flags) };
+    flags & (1 << 9) != 0
+}
+
+/// Enable interrupts again if they were enabled before `save_and_disable_interrupts`.
+///
+/// # Safety
+/// The same as enabling interrupts, if they were enabled.
+pub unsafe fn restore_interrupts(enabled: bool) {
+    if enabled {
+        asm!("sti", options(nostack));
+    }
+}
+
+/// Pause the CPU until the next interrupt arrives.
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("hlt", options(nomem, nostack));
+    }
+}
+
+/// Read a counter which increases quickly and steadily (but not at any particular rate).
+pub fn read_cycle_counter() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (high as u64) << 32 | low as u64
+}
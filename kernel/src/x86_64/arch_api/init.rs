@@ -1,8 +1,8 @@
 use core::ptr::addr_of;
 
 use crate::{
-    arch::{interrupts, task_state_segment},
-    memory, physical_memory_manager,
+    arch::{interrupts, syscall_instruction, task_state_segment},
+    memory, per_cpu, physical_memory_manager,
 };
 
 use super::{super::multiboot, paging};
@@ -27,6 +27,7 @@ static stack_end: () = ();
 
 #[allow(unused_unsafe)] // It isn't actually unused, but I think there is a bug in the compiler since removing it causes an error.
 pub fn arch_init() {
+    per_cpu::initialize_boot_cpu();
     interrupts::init();
     multiboot::parse_multiboot_structures();
     // Unless we really want to have difficulties in the near future (possibly as soon as the very next function), we must tell people not to use the kernel's memory as a heap.]
@@ -40,4 +41,5 @@ pub fn arch_init() {
     paging::initialize_paging();
 
     task_state_segment::initialize(unsafe { addr_of!(stack_end) as u64 });
+    syscall_instruction::init();
 }
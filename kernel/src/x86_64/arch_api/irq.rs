@@ -1,6 +1,13 @@
-use crate::arch::{
-    asm::{enable_interrupts, io_wait, write_port8},
-    local_apic,
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    arch::{
+        asm::{enable_interrupts, io_wait, write_port8},
+        interrupts::SYSCALL_INTERRUPT,
+        io_apic,
+        local_apic::{self, LocalInterruptConfiguration},
+    },
+    irq::{InterruptSource, Irq},
 };
 
 use super::acpi::AcpiInfo;
@@ -8,6 +15,13 @@ use super::acpi::AcpiInfo;
 pub fn initialize(acpi_info: &AcpiInfo) {
     // SAFETY: The MADT is required to contain the correct address for the local APIC, and this is the first place it is ever used.
     unsafe { local_apic::initialize(acpi_info.madt.local_interrupt_controller_address as usize) };
+    // SAFETY: The local APIC was just initialized.
+    unsafe {
+        local_apic::configure_local_interrupts(LocalInterruptConfiguration::from_madt(
+            &acpi_info.madt,
+            local_apic::id(),
+        ))
+    };
 
     if acpi_info.madt.flags & 0b1 != 0 {
         // Legacy PIC present
@@ -31,8 +45,34 @@ pub fn initialize(acpi_info: &AcpiInfo) {
         }
     }
 
-    // TODO: Initialize IO APICs.
+    // SAFETY: The MADT has the right addresses, and nothing else uses the I/O APICs.
+    unsafe { io_apic::initialize(&acpi_info.madt) };
 
     // SAFETY: This is called from main, which doesn't expect interrupts to be disabled.
     unsafe { enable_interrupts() };
 }
+
+/// Device interrupts get the vectors from here up to the system call vector, leaving the ones below for the local APIC.
+const FIRST_DEVICE_VECTOR: u8 = 0x30;
+
+static NEXT_DEVICE_VECTOR: AtomicU8 = AtomicU8::new(FIRST_DEVICE_VECTOR);
+
+/// Give the interrupt a vector of its own, and send it to this CPU.
+pub fn allocate(source: InterruptSource) -> Irq {
+    let vector = NEXT_DEVICE_VECTOR.fetch_add(1, Ordering::SeqCst);
+    assert!(vector < SYSCALL_INTERRUPT, "Out of interrupt vectors");
+    // SAFETY: The local APIC was initialized along with the I/O APICs.
+    let apic_id = unsafe { local_apic::id() };
+    // Without interrupt remapping, the I/O APICs can only send interrupts to the first 256 CPUs.
+    let apic_id = u8::try_from(apic_id).expect("APIC ID too big for the I/O APICs");
+    io_apic::route(source, vector, apic_id);
+    Irq::new(vector as u32, source.global_system_interrupt)
+}
+
+pub fn enable(irq: Irq) {
+    io_apic::unmask(irq.global_system_interrupt());
+}
+
+pub fn disable(irq: Irq) {
+    io_apic::mask(irq.global_system_interrupt());
+}
@@ -1,10 +1,12 @@
-use core::arch::asm;
+use core::{arch::asm, slice};
 
 use crate::{
     buddy::BuddyAllocator,
+    heap::{map_physical_memory, PhysicalAddressHandle},
     lazy_init::lazy_static,
     paging::{MemoryType, PagePermissions},
     physical_memory_manager,
+    spinlock::{InterruptSafeSpinLock, LockLevel},
 };
 
 pub const PAGE_SIZE: usize = 4096;
@@ -150,50 +152,44 @@ fn deconstruct_virtual_address(address: usize) -> PageTableIndices {
 }
 
 lazy_static! {
-    static ref PAGE_TABLE_ALLOCATION_POOL: &'static mut BuddyAllocator<128, { physical_memory_manager::LOG2_BLOCK_SIZE }, 12> = {
+    static ref PAGE_TABLE_ALLOCATION_POOL: InterruptSafeSpinLock<
+        &'static mut BuddyAllocator<128, { physical_memory_manager::LOG2_BLOCK_SIZE }, 12>,
+    > = {
         static mut ACTUAL_ALLOCATOR: BuddyAllocator<
             128,
             { physical_memory_manager::LOG2_BLOCK_SIZE },
             12,
         > = BuddyAllocator::unusable();
-        unsafe { ACTUAL_ALLOCATOR.all_unused() }
+        InterruptSafeSpinLock::new(LockLevel::PageTables, unsafe {
+            ACTUAL_ALLOCATOR.all_unused()
+        })
     };
 }
 
 fn allocate_page_table() -> usize {
-    unsafe {
-        if let Some(allocated_page) = PAGE_TABLE_ALLOCATION_POOL.allocate(4096) {
-            allocated_page
-        } else {
-            PAGE_TABLE_ALLOCATION_POOL.add_entry(
-                physical_memory_manager::BLOCK_SIZE,
-                physical_memory_manager::allocate_block_address()
-                    .expect("Failed to get physical memory for page tables"),
-            );
-            PAGE_TABLE_ALLOCATION_POOL
-                .allocate(4096)
-                .expect("Adding new entry to page table allocation pool didn't change anything")
-        }
+    let mut pool = PAGE_TABLE_ALLOCATION_POOL.lock();
+    if let Some(allocated_page) = pool.allocate(4096) {
+        allocated_page
+    } else {
+        pool.add_entry(
+            physical_memory_manager::BLOCK_SIZE,
+            physical_memory_manager::allocate_block_address()
+                .expect("Failed to get physical memory for page tables"),
+        );
+        pool.allocate(4096)
+            .expect("Adding new entry to page table allocation pool didn't change anything")
     }
 }
 fn free_page_table(address: usize) {
-    unsafe {
-        PAGE_TABLE_ALLOCATION_POOL.free(4096, address);
-        // If this merged into a 64 kb block, return it to the physical memory manager (PMM) so it can be used by someone else.
-        if let Some(free_block) =
-            PAGE_TABLE_ALLOCATION_POOL.allocate(physical_memory_manager::BLOCK_SIZE)
-        {
-            physical_memory_manager::mark_as_free(free_block);
-        }
+    let mut pool = PAGE_TABLE_ALLOCATION_POOL.lock();
+    pool.free(4096, address);
+    // If this merged into a 64 kb block, return it to the physical memory manager (PMM) so it can be used by someone else.
+    if let Some(free_block) = pool.allocate(physical_memory_manager::BLOCK_SIZE) {
+        physical_memory_manager::mark_as_free(free_block);
     }
 }
 
-fn ensure_page_table_exists(
-    user_page: bool,
-    pml4_index: usize,
-    pml3_index: usize,
-    pml2_index: usize,
-) {
+fn create_pml3_if_absent(user_page: bool, pml4_index: usize) {
     // Indexing with the first indices set to RECURSIVE_PAGE_TABLE_INDEX will give us the next layer up in the page tables.
     let pml4_entry = unsafe {
         read_page_table_entry(
@@ -233,6 +229,15 @@ fn ensure_page_table_exists(
             core::ptr::write_bytes(address as *mut u8, 0, 4096);
         }
     }
+}
+
+fn ensure_page_table_exists(
+    user_page: bool,
+    pml4_index: usize,
+    pml3_index: usize,
+    pml2_index: usize,
+) {
+    create_pml3_if_absent(user_page, pml4_index);
     let pml3_entry = unsafe {
         read_page_table_entry(
             RECURSIVE_PAGE_TABLE_INDEX,
@@ -474,6 +479,128 @@ pub(super) fn initialize_paging() {
         unsafe { core::slice::from_raw_parts(0xffffffff80000000 as *const u8, 4096) };
     assert_eq!(slice_in_low_memory, slice_in_high_memory);
     unmap_page(4096);
+    // Every user page table gets a copy of the kernel's pml4 entries, so they must never change after this point.
+    // We create all of the pml3s for the higher half now so nothing ever has to add a new one.
+    for pml4_index in RECURSIVE_PAGE_TABLE_INDEX + 1..512 {
+        create_pml3_if_absent(false, pml4_index);
+    }
+}
+
+unsafe fn read_cr3() -> usize {
+    let cr3: usize;
+    asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+    cr3
+}
+
+unsafe fn write_cr3(cr3: usize) {
+    asm!("mov cr3, {}", in(reg) cr3, options(nostack));
+}
+
+/// Create a new pml4 with the kernel mapped into the higher half and nothing in the lower half, returning its physical address.
+pub fn create_user_page_table() -> usize {
+    let page_table_address = allocate_page_table();
+    unsafe {
+        let mut page_table_handle = map_physical_memory(
+            page_table_address,
+            PAGE_SIZE,
+            MemoryType::Normal,
+            PagePermissions::KERNEL_READ_WRITE,
+        );
+        let new_pml4 = slice::from_raw_parts_mut(
+            PhysicalAddressHandle::as_mut_ptr(&mut page_table_handle) as *mut u64,
+            512,
+        );
+        let current_pml4 = slice::from_raw_parts(
+            get_page_table_entry_address(
+                RECURSIVE_PAGE_TABLE_INDEX,
+                RECURSIVE_PAGE_TABLE_INDEX,
+                RECURSIVE_PAGE_TABLE_INDEX,
+                0,
+            ),
+            512,
+        );
+        new_pml4[..RECURSIVE_PAGE_TABLE_INDEX].fill(0);
+        new_pml4[RECURSIVE_PAGE_TABLE_INDEX..]
+            .copy_from_slice(&current_pml4[RECURSIVE_PAGE_TABLE_INDEX..]);
+        new_pml4[RECURSIVE_PAGE_TABLE_INDEX] = construct_page_table_entry(PageTableEntry {
+            present: true,
+            writeable: true,
+            user_accessible: false,
+            write_through: false,
+            cache_disabled: false,
+            accessed: false,
+            dirty: false,
+            huge_page: false,
+            global: false,
+            physical_address: page_table_address as u64,
+            no_execute: true,
+        });
+    }
+    page_table_address
+}
+
+/// # Safety
+/// The page table must have come from `create_user_page_table` (or be the one we started with), and must not have been destroyed.
+pub unsafe fn activate_user_page_table(page_table_address: usize) {
+    // Kernel pages are global, so this only flushes the lower half from the TLB.
+    write_cr3(page_table_address);
+}
+
+pub fn get_active_user_page_table() -> usize {
+    unsafe { read_cr3() & PHYSICAL_ADDRESS_MASK as usize }
+}
+
+/// Free a page table from `create_user_page_table`, along with all of the lower level tables under it.
+///
+/// This does not free the pages which were mapped with it.
+///
+/// # Safety
+/// The page table must not be the active one, and must never be used again.
+pub unsafe fn destroy_user_page_table(page_table_address: usize) {
+    let previous_page_table = get_active_user_page_table();
+    assert_ne!(
+        previous_page_table, page_table_address,
+        "Destroying the active page table"
+    );
+    // We need the page table to be active to reach the lower levels through the recursive mapping.
+    activate_user_page_table(page_table_address);
+    for pml4_index in 0..RECURSIVE_PAGE_TABLE_INDEX {
+        let pml4_entry = read_page_table_entry(
+            RECURSIVE_PAGE_TABLE_INDEX,
+            RECURSIVE_PAGE_TABLE_INDEX,
+            RECURSIVE_PAGE_TABLE_INDEX,
+            pml4_index,
+        );
+        if !pml4_entry.present {
+            continue;
+        }
+        for pml3_index in 0..512 {
+            let pml3_entry = read_page_table_entry(
+                RECURSIVE_PAGE_TABLE_INDEX,
+                RECURSIVE_PAGE_TABLE_INDEX,
+                pml4_index,
+                pml3_index,
+            );
+            if !pml3_entry.present {
+                continue;
+            }
+            for pml2_index in 0..512 {
+                let pml2_entry = read_page_table_entry(
+                    RECURSIVE_PAGE_TABLE_INDEX,
+                    pml4_index,
+                    pml3_index,
+                    pml2_index,
+                );
+                if pml2_entry.present {
+                    free_page_table(pml2_entry.physical_address as usize);
+                }
+            }
+            free_page_table(pml3_entry.physical_address as usize);
+        }
+        free_page_table(pml4_entry.physical_address as usize);
+    }
+    activate_user_page_table(previous_page_table);
+    free_page_table(page_table_address);
 }
 
 pub fn is_valid_user_address(address: usize) -> bool {
@@ -0,0 +1,54 @@
+use core::{arch::asm, cell::Cell, ptr::null_mut};
+
+use crate::{
+    arch::{asm::write_msr, task_state_segment::TaskStateSegment},
+    per_cpu::THIS_OFFSET,
+};
+
+pub(in crate::arch) const GS_BASE: u32 = 0xC000_0101;
+
+/// The parts of each CPU's data which only x86_64 needs.
+pub struct ArchPerCpu {
+    /// Where the CPU finds the stack to switch to when an interrupt arrives in user mode.
+    task_state_segment: Cell<*mut TaskStateSegment>,
+}
+
+impl ArchPerCpu {
+    pub const fn new() -> Self {
+        Self {
+            task_state_segment: Cell::new(null_mut()),
+        }
+    }
+
+    pub(in crate::arch) fn task_state_segment(&self) -> *mut TaskStateSegment {
+        self.task_state_segment.get()
+    }
+
+    pub(in crate::arch) fn set_task_state_segment(
+        &self,
+        task_state_segment: *mut TaskStateSegment,
+    ) {
+        self.task_state_segment.set(task_state_segment);
+    }
+}
+
+impl Default for ArchPerCpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Safety
+/// The address has to point to this CPU's data, and nothing which uses the GS base can be running.
+pub unsafe fn set_per_cpu_address(address: usize) {
+    // User mode gets the value in KERNEL_GS_BASE whenever the kernel runs SWAPGS on the way out (see syscall_instruction.rs).
+    write_msr(GS_BASE, address as u64);
+}
+
+pub fn get_per_cpu_address() -> usize {
+    let address: usize;
+    unsafe {
+        asm!("mov {}, gs:[{this}]", out(reg) address, this = const THIS_OFFSET, options(nostack, readonly, preserves_flags));
+    }
+    address
+}
@@ -0,0 +1,180 @@
+//! The real time clock in the CMOS.
+
+use core::time::Duration;
+
+use crate::{
+    acpi::fadt::X86BootFlags,
+    arch::asm::{read_port8, write_port8},
+    wall_clock::DateTime,
+};
+
+use super::acpi::AcpiInfo;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const SECONDS_REGISTER: u8 = 0x00;
+const MINUTES_REGISTER: u8 = 0x02;
+const HOURS_REGISTER: u8 = 0x04;
+const DAY_REGISTER: u8 = 0x07;
+const MONTH_REGISTER: u8 = 0x08;
+const YEAR_REGISTER: u8 = 0x09;
+const STATUS_A_REGISTER: u8 = 0x0A;
+const STATUS_B_REGISTER: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// In 12 hour mode, this is set in the hours register in the afternoon.
+const HOUR_PM: u8 = 1 << 7;
+
+fn read_register(register: u8) -> u8 {
+    // SAFETY: Reading the CMOS doesn't change anything.
+    unsafe {
+        write_port8(CMOS_ADDRESS_PORT, register);
+        read_port8(CMOS_DATA_PORT)
+    }
+}
+
+/// The time registers (and the century, or 0), in the order they are used by `read_time`.
+fn read_time_registers(century_register: Option<u8>) -> [u8; 7] {
+    while read_register(STATUS_A_REGISTER) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    [
+        read_register(SECONDS_REGISTER),
+        read_register(MINUTES_REGISTER),
+        read_register(HOURS_REGISTER),
+        read_register(DAY_REGISTER),
+        read_register(MONTH_REGISTER),
+        read_register(YEAR_REGISTER),
+        century_register.map_or(0, read_register),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+/// Whether the time from the RTC makes sense, since a CMOS with a flat battery could have anything in it, and `DateTime::to_unix_time` can't handle dates before 1970.
+fn is_valid(date_time: &DateTime) -> bool {
+    date_time.year >= 1970
+        && (1..=12).contains(&date_time.month)
+        && (1..=31).contains(&date_time.day)
+        && date_time.hour < 24
+        && date_time.minute < 60
+        && date_time.second < 60
+}
+
+/// Read the time (since the Unix epoch) from the RTC, if there is one (and it has a valid time).
+pub fn read_time(acpi_info: &AcpiInfo) -> Option<Duration> {
+    if acpi_info
+        .fadt
+        .x86_boot_flags
+        .contains(X86BootFlags::CMOS_RTC_NOT_PRESENT)
+    {
+        return None;
+    }
+    let century_register = acpi_info.fadt.century_register;
+    // The RTC could tick part way through reading it, so keep going until we get the same time twice in a row.
+    let mut registers = read_time_registers(century_register);
+    loop {
+        let again = read_time_registers(century_register);
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    let [second, minute, raw_hour, day, month, year, century] = registers;
+
+    let status_b = read_register(STATUS_B_REGISTER);
+    let decode = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let mut hour = decode(raw_hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, and 12 PM is midday.
+        hour %= 12;
+        if raw_hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    // Without a century register, the best we can do is assume that it's this century.
+    let century = match century_register {
+        Some(_) => decode(century) as u32,
+        None => 20,
+    };
+    let date_time = DateTime {
+        year: century * 100 + decode(year) as u32,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    };
+    if !is_valid(&date_time) {
+        return None;
+    }
+    Some(date_time.to_unix_time())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn is_valid_test() {
+        let date_time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert!(is_valid(&date_time));
+        assert!(is_valid(&DateTime {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }));
+        assert!(!is_valid(&DateTime {
+            year: 1969,
+            ..date_time
+        }));
+        assert!(!is_valid(&DateTime {
+            month: 0,
+            ..date_time
+        }));
+        assert!(!is_valid(&DateTime {
+            month: 13,
+            ..date_time
+        }));
+        assert!(!is_valid(&DateTime {
+            day: 0,
+            ..date_time
+        }));
+        assert!(!is_valid(&DateTime {
+            day: 32,
+            ..date_time
+        }));
+        assert!(!is_valid(&DateTime {
+            hour: 24,
+            ..date_time
+        }));
+        assert!(!is_valid(&DateTime {
+            minute: 60,
+            ..date_time
+        }));
+        // The registers read as 0xff with no CMOS, which is 165 after decoding BCD.
+        assert!(!is_valid(&DateTime {
+            second: 165,
+            ..date_time
+        }));
+    }
+}
@@ -0,0 +1,370 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::global_asm,
+    mem::offset_of,
+    ops::Range,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::{
+        interrupts,
+        local_apic::{self, LocalInterruptConfiguration},
+        syscall_instruction,
+        task_state_segment::DescriptorTables,
+    },
+    arch_api::{asm::wait_for_interrupt, timer},
+    clock::{busy_wait, monotonic_time},
+    heap::{map_physical_memory, PhysicalAddressHandle},
+    paging::{map_page, unmap_page, MemoryType, PagePermissions, PAGE_SIZE},
+    per_cpu::{self, PerCpu},
+    println,
+};
+
+use super::{acpi::AcpiInfo, paging::get_active_user_page_table};
+
+/// The pages where the other CPUs can start running, which have to be below 1MiB.
+/// The kernel's own memory (which is marked as used from address 0) covers them, so nothing else will be there.
+///
+/// Only the first one is normally used, but a CPU which doesn't start in time keeps its page in case it starts later on.
+const TRAMPOLINE_PAGES: Range<usize> = 0x8000..0x10000;
+
+const APPLICATION_PROCESSOR_STACK_SIZE: usize = 16 * 1024;
+
+/// How long to wait for a CPU to say it has started before giving up on it.
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Filled in for each CPU before it is started, and read by the trampoline.
+#[repr(C)]
+struct TrampolineData {
+    /// The physical address of the pml4, which has to be below 4GiB since it is loaded in 32-bit mode.
+    page_table: u64,
+    stack_top: u64,
+    entry: u64,
+    processor: u64,
+}
+
+// The trampoline is copied to one of the pages in `TRAMPOLINE_PAGES` and runs from there. The CPU starts with CS set to that page, so the trampoline works out where it is from that rather than from where it was linked.
+// It keeps the address in ebx (and then rbx), and fills in the addresses in its far jumps and GDT pointer before using them.
+// It gets from real mode to long mode in the same way as the boot code, using the kernel's page table (which needs the trampoline identity mapped while the CPUs start).
+// The temporary GDT has the same 64-bit code segment as the real one, and its descriptors are already marked as accessed so that the CPU doesn't write to the read-only page.
+global_asm!(
+    ".globl ap_trampoline_start",
+    ".globl ap_trampoline_data",
+    ".globl ap_trampoline_end",
+    ".set GDT, ap_trampoline_gdt - ap_trampoline_start",
+    ".set PROTECTED_MODE, ap_trampoline_protected_mode - ap_trampoline_start",
+    ".set LONG_MODE, ap_trampoline_long_mode - ap_trampoline_start",
+    ".set GDT_POINTER, ap_trampoline_gdt_pointer - ap_trampoline_start",
+    ".set PROTECTED_MODE_JUMP, ap_trampoline_protected_mode_jump - ap_trampoline_start",
+    ".set LONG_MODE_JUMP, ap_trampoline_long_mode_jump - ap_trampoline_start",
+    ".set DATA, ap_trampoline_data - ap_trampoline_start",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    movzx ebx, ax",
+    "    shl ebx, 4",
+    "    lea eax, [ebx + GDT]",
+    "    mov [GDT_POINTER + 2], eax",
+    "    lea eax, [ebx + PROTECTED_MODE]",
+    "    mov [PROTECTED_MODE_JUMP], eax",
+    "    lea eax, [ebx + LONG_MODE]",
+    "    mov [LONG_MODE_JUMP], eax",
+    "    lgdt [GDT_POINTER]",
+    "    mov eax, cr0",
+    "    or eax, 1",
+    "    mov cr0, eax",
+    "    jmp fword ptr [PROTECTED_MODE_JUMP]",
+    ".code32",
+    "ap_trampoline_protected_mode:",
+    "    mov ax, 0x18",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    // Physical address extensions
+    "    mov eax, cr4",
+    "    or eax, 1 << 5",
+    "    mov cr4, eax",
+    "    mov eax, [ebx + DATA + {page_table}]",
+    "    mov cr3, eax",
+    // Long mode and no-execute pages in the EFER
+    "    mov ecx, 0xC0000080",
+    "    rdmsr",
+    "    or eax, (1 << 8) | (1 << 11)",
+    "    wrmsr",
+    // Paging and write-protect
+    "    mov eax, cr0",
+    "    or eax, (1 << 31) | (1 << 16)",
+    "    mov cr0, eax",
+    "    jmp fword ptr [ebx + LONG_MODE_JUMP]",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    xor eax, eax",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    // The top half of rbx is undefined after switching to long mode.
+    "    mov ebx, ebx",
+    "    mov rsp, [rbx + DATA + {stack_top}]",
+    "    mov rdi, [rbx + DATA + {processor}]",
+    "    mov rax, [rbx + DATA + {entry}]",
+    "    xor ebp, ebp",
+    "    call rax",
+    "    ud2",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    // 64-bit code
+    "    .quad 0x00AF9B000000FFFF",
+    // 32-bit code
+    "    .quad 0x00CF9B000000FFFF",
+    // 32-bit data
+    "    .quad 0x00CF93000000FFFF",
+    "ap_trampoline_gdt_pointer:",
+    "    .word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1",
+    "    .long 0",
+    // The offsets are filled in, followed by the code segments' selectors.
+    "ap_trampoline_protected_mode_jump:",
+    "    .long 0",
+    "    .word 0x10",
+    "ap_trampoline_long_mode_jump:",
+    "    .long 0",
+    "    .word 0x08",
+    ".balign 8",
+    "ap_trampoline_data:",
+    "    .space {data_size}",
+    "ap_trampoline_end:",
+    page_table = const offset_of!(TrampolineData, page_table),
+    stack_top = const offset_of!(TrampolineData, stack_top),
+    entry = const offset_of!(TrampolineData, entry),
+    processor = const offset_of!(TrampolineData, processor),
+    data_size = const core::mem::size_of::<TrampolineData>(),
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+struct ApplicationProcessorStack([u8; APPLICATION_PROCESSOR_STACK_SIZE]);
+
+/// A CPU other than the one which booted the kernel.
+struct ApplicationProcessor {
+    apic_id: u32,
+    local_interrupt_configuration: LocalInterruptConfiguration,
+    stack: Box<ApplicationProcessorStack>,
+    descriptor_tables: DescriptorTables,
+    per_cpu: &'static PerCpu,
+    /// Set by the CPU once it has finished starting up.
+    started: AtomicBool,
+    /// The frequency of the CPU's own APIC timer, which the CPU measures while it starts.
+    timer_frequency: AtomicU64,
+}
+
+impl ApplicationProcessor {
+    fn new(
+        apic_id: u32,
+        local_interrupt_configuration: LocalInterruptConfiguration,
+        id: usize,
+    ) -> Self {
+        let stack: Box<ApplicationProcessorStack> = unsafe { Box::new_zeroed().assume_init() };
+        let descriptor_tables = DescriptorTables::new(stack_top(&stack));
+        let per_cpu = per_cpu::allocate(id);
+        per_cpu
+            .arch
+            .set_task_state_segment(descriptor_tables.task_state_segment());
+        Self {
+            apic_id,
+            local_interrupt_configuration,
+            stack,
+            descriptor_tables,
+            per_cpu,
+            started: AtomicBool::new(false),
+            timer_frequency: AtomicU64::new(0),
+        }
+    }
+}
+
+fn stack_top(stack: &ApplicationProcessorStack) -> u64 {
+    stack.0.as_ptr() as u64 + APPLICATION_PROCESSOR_STACK_SIZE as u64
+}
+
+// SAFETY: Only touched by `start_application_processors`, while nothing else is running.
+/// The CPUs which have been started, which stay around for as long as the kernel runs.
+static mut APPLICATION_PROCESSORS: Vec<&'static ApplicationProcessor> = Vec::new();
+
+/// The number of CPUs which are running, including the one which booted the kernel.
+pub fn cpu_count() -> usize {
+    // SAFETY: See above.
+    unsafe { (*addr_of!(APPLICATION_PROCESSORS)).len() + 1 }
+}
+
+/// A copy of the trampoline in one of the pages from `TRAMPOLINE_PAGES`, which CPUs are started at one at a time.
+struct Trampoline {
+    address: usize,
+    /// Keeps the page mapped so that the data can be filled in for each CPU.
+    _page: PhysicalAddressHandle,
+    data: *mut TrampolineData,
+}
+
+impl Trampoline {
+    /// Copy the trampoline to the page at `address`, and identity map it.
+    fn new(address: usize) -> Self {
+        // SAFETY: Nothing else uses the trampoline pages (see above).
+        let mut page = unsafe {
+            map_physical_memory(
+                address,
+                PAGE_SIZE,
+                MemoryType::Normal,
+                PagePermissions::KERNEL_READ_WRITE,
+            )
+        };
+        let trampoline_start = unsafe { addr_of!(ap_trampoline_start) as usize };
+        let trampoline_size = unsafe { addr_of!(ap_trampoline_end) as usize } - trampoline_start;
+        let data_offset = unsafe { addr_of!(ap_trampoline_data) as usize } - trampoline_start;
+        assert!(trampoline_size <= PAGE_SIZE);
+        let page_pointer = PhysicalAddressHandle::as_mut_ptr(&mut page);
+        // SAFETY: Both the trampoline code and its new page are `trampoline_size` bytes long.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                trampoline_start as *const u8,
+                page_pointer,
+                trampoline_size,
+            );
+        }
+        let data = unsafe { page_pointer.add(data_offset) } as *mut TrampolineData;
+        // The trampoline keeps running for a while after it turns on paging, so it needs to be at the same address in the page table.
+        map_page(
+            address,
+            address,
+            MemoryType::Normal,
+            PagePermissions::KERNEL_READ_EXECUTE,
+        );
+        Self {
+            address,
+            _page: page,
+            data,
+        }
+    }
+}
+
+/// Start every CPU other than this one, leaving them idle with interrupts disabled.
+///
+/// This has to be called after the local APIC and the clock are initialized, while the page table from the boot code is still active.
+pub fn start_application_processors(acpi_info: &AcpiInfo) {
+    // SAFETY: The local APIC was initialized with the rest of the interrupts.
+    let own_apic_id = unsafe { local_apic::id() };
+    let page_table = get_active_user_page_table();
+    assert!(
+        page_table < 1 << 32,
+        "The page table has to be reachable from 32-bit mode"
+    );
+
+    let mut trampoline_addresses = TRAMPOLINE_PAGES.step_by(PAGE_SIZE);
+    let mut trampoline = Trampoline::new(trampoline_addresses.next().unwrap());
+    let mut used_trampolines = 1;
+    let mut any_timed_out = false;
+    // CPUs which fail to start still use up an ID, since they might start later on.
+    let mut next_cpu_id = 1;
+    for local_apic_entry in &acpi_info.madt.local_apic_entries {
+        let apic_id = local_apic_entry.apic_id;
+        if apic_id == own_apic_id {
+            continue;
+        }
+        let processor: &'static ApplicationProcessor =
+            Box::leak(Box::new(ApplicationProcessor::new(
+                apic_id,
+                LocalInterruptConfiguration::from_madt(&acpi_info.madt, apic_id),
+                next_cpu_id,
+            )));
+        next_cpu_id += 1;
+        // SAFETY: The data is in the trampoline's page, and no other CPU is using this trampoline (see below).
+        unsafe {
+            trampoline.data.write_volatile(TrampolineData {
+                page_table: page_table as u64,
+                stack_top: stack_top(&processor.stack),
+                entry: application_processor_entry as usize as u64,
+                processor: processor as *const ApplicationProcessor as u64,
+            });
+        }
+
+        // The startup sequence from the Intel manual: INIT, then two startup interrupts (the second one is ignored if the first one worked).
+        // SAFETY: The CPU hasn't been started yet, so it isn't doing anything, and the trampoline is where we are telling it to start.
+        unsafe {
+            local_apic::send_init(apic_id);
+            busy_wait(Duration::from_millis(10));
+            local_apic::send_startup(apic_id, trampoline.address);
+            busy_wait(Duration::from_micros(200));
+            if !processor.started.load(Ordering::Acquire) {
+                local_apic::send_startup(apic_id, trampoline.address);
+            }
+        }
+
+        let deadline = monotonic_time() + START_TIMEOUT;
+        while !processor.started.load(Ordering::Acquire) && monotonic_time() < deadline {
+            core::hint::spin_loop();
+        }
+        if !processor.started.load(Ordering::Acquire) {
+            any_timed_out = true;
+            // It might still start later on, so it can't be allowed to find another CPU's data in its trampoline.
+            let Some(address) = trampoline_addresses.next() else {
+                println!(
+                    "CPU with APIC ID {} didn't start, and there are no trampoline pages left, so giving up on the rest",
+                    apic_id
+                );
+                break;
+            };
+            println!("CPU with APIC ID {} didn't start in time", apic_id);
+            trampoline = Trampoline::new(address);
+            used_trampolines += 1;
+            continue;
+        }
+        println!(
+            "Started CPU with APIC ID {} (APIC timer frequency: {}Hz)",
+            apic_id,
+            processor.timer_frequency.load(Ordering::Relaxed)
+        );
+        // SAFETY: See `APPLICATION_PROCESSORS`.
+        unsafe { (*core::ptr::addr_of_mut!(APPLICATION_PROCESSORS)).push(processor) };
+    }
+
+    // A CPU which didn't start in time could still need its trampoline later on.
+    if !any_timed_out {
+        for address in TRAMPOLINE_PAGES.step_by(PAGE_SIZE).take(used_trampolines) {
+            unmap_page(address);
+        }
+    }
+    println!("{} CPUs running", cpu_count());
+}
+
+extern "C" fn application_processor_entry(processor: &'static ApplicationProcessor) -> ! {
+    interrupts::init();
+    // SAFETY: The tables and the CPU's data were made just for this CPU, and are never freed.
+    // The data has to be installed after the tables are loaded, since that clears the GS base.
+    unsafe {
+        processor.descriptor_tables.load();
+        per_cpu::install(processor.per_cpu);
+    }
+    syscall_instruction::init();
+    // SAFETY: The first CPU initialized the local APIC before starting this one.
+    unsafe {
+        local_apic::enable();
+        local_apic::configure_local_interrupts(processor.local_interrupt_configuration);
+    }
+    let timer_frequency = timer::initialize_application_processor_timer();
+    processor
+        .timer_frequency
+        .store(timer_frequency, Ordering::Relaxed);
+    processor.started.store(true, Ordering::Release);
+
+    // Interrupts stay disabled until the scheduler can run threads on more than one CPU, so this waits forever.
+    loop {
+        wait_for_interrupt();
+    }
+}
@@ -0,0 +1,25 @@
+use common::syscall::{encode_result, SyscallResult};
+
+use super::thread::SavedRegisters;
+
+// See common::syscall for a description of the ABI.
+impl SavedRegisters {
+    pub fn syscall_number(&self) -> u64 {
+        self.rax
+    }
+
+    pub fn syscall_arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    pub fn set_syscall_result(&mut self, result: SyscallResult) {
+        (self.rax, self.rdx) = encode_result(result);
+    }
+
+    /// Go back to the system call instruction, so the system call happens again when the thread is resumed.
+    ///
+    /// Both `syscall` and `int 0x80` are two bytes long.
+    pub fn restart_syscall(&mut self) {
+        self.rip -= 2;
+    }
+}
@@ -0,0 +1,89 @@
+use core::{arch::asm, mem::size_of};
+
+use crate::{arch::task_state_segment, per_cpu::this_cpu};
+
+pub use crate::arch::interrupts::SavedRegisters;
+
+pub(in crate::arch) const KERNEL_CODE_SEGMENT: u64 = 0x08;
+pub(in crate::arch) const KERNEL_STACK_SEGMENT: u64 = 0x10;
+// The order of these is dictated by SYSRET.
+pub(in crate::arch) const USER_STACK_SEGMENT: u64 = 0x1b;
+pub(in crate::arch) const USER_CODE_SEGMENT: u64 = 0x23;
+
+const INTERRUPTS_ENABLED_FLAG: u64 = 0x200;
+
+/// Write the given registers to the top of a kernel stack, returning a pointer to where they were written.
+///
+/// # Safety
+/// `stack_top` must be the (16-byte aligned) top of a kernel stack with room for the registers.
+unsafe fn push_registers(stack_top: usize, registers: SavedRegisters) -> *mut SavedRegisters {
+    let registers_pointer = (stack_top - size_of::<SavedRegisters>()) as *mut SavedRegisters;
+    registers_pointer.write(registers);
+    registers_pointer
+}
+
+/// Set up the initial registers for a thread which runs `entrypoint` in kernel mode.
+///
+/// # Safety
+/// `stack_top` must be the (16-byte aligned) top of a kernel stack, which is then owned by the new thread.
+pub unsafe fn push_initial_kernel_registers(
+    stack_top: usize,
+    entrypoint: extern "C" fn() -> !,
+) -> *mut SavedRegisters {
+    // The registers are popped before the thread starts, so the thread's own stack can start just below our fake return address.
+    let stack_pointer = stack_top - 8;
+    push_registers(
+        stack_pointer - 8,
+        SavedRegisters {
+            rip: entrypoint as usize as u64,
+            cs: KERNEL_CODE_SEGMENT,
+            rflags: INTERRUPTS_ENABLED_FLAG,
+            rsp: stack_pointer as u64,
+            ss: KERNEL_STACK_SEGMENT,
+            ..Default::default()
+        },
+    )
+}
+
+/// Set up the initial registers for a thread which starts at `entrypoint` in user mode.
+///
+/// # Safety
+/// `stack_top` must be the (16-byte aligned) top of a kernel stack, which is then owned by the new thread.
+pub unsafe fn push_initial_user_registers(
+    stack_top: usize,
+    entrypoint: usize,
+    stack_pointer: usize,
+) -> *mut SavedRegisters {
+    // The CPU pushes the registers to the top of the kernel stack when an interrupt arrives in user mode, so that is where they go for the first switch as well.
+    push_registers(
+        stack_top,
+        SavedRegisters {
+            rip: entrypoint as u64,
+            cs: USER_CODE_SEGMENT,
+            rflags: INTERRUPTS_ENABLED_FLAG,
+            rsp: stack_pointer as u64,
+            ss: USER_STACK_SEGMENT,
+            ..Default::default()
+        },
+    )
+}
+
+/// Tell the CPU which kernel stack to use for interrupts which arrive while running the current thread.
+pub fn set_kernel_stack(stack_top: usize) {
+    // Interrupts find it in the task state segment, and the SYSCALL entry code in the CPU's data.
+    task_state_segment::set_kernel_stack(stack_top as u64);
+    this_cpu().set_kernel_stack_top(stack_top);
+}
+
+/// Restore the given registers, continuing wherever they were saved.
+///
+/// # Safety
+/// The registers must have been produced by an interrupt handler or one of the functions above, and must not be in use by anything else.
+pub unsafe fn resume(registers: *mut SavedRegisters) -> ! {
+    asm!(
+        "mov rsp, {}",
+        "jmp restore_registers_and_iret",
+        in(reg) registers,
+        options(noreturn)
+    );
+}
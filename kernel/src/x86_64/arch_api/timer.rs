@@ -1,10 +1,33 @@
+use core::{
+    arch::x86_64::__cpuid,
+    ptr::addr_of,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
 use crate::{
     arch::{hpet::Hpet, local_apic},
+    arch_api::asm::read_cycle_counter,
+    clock::monotonic_time,
     println,
 };
 
 use super::acpi::AcpiInfo;
 
+// SAFETY: Only written once by `initialize`, before anything reads the clock.
+/// The HPET, if the monotonic clock uses it instead of the TSC.
+static mut CLOCK_HPET: Option<Hpet> = None;
+/// In ticks per second. 0 until the clock is initialized.
+static CLOCK_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The raw counter value when the clock was initialized, which counts as the start of the clock.
+static CLOCK_START: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC keeps ticking at the same rate whatever the CPU is doing (including sleeping and changing frequency).
+fn has_invariant_tsc() -> bool {
+    // SAFETY: Every x86_64 CPU has the cpuid instruction.
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 }
+}
+
 pub fn initialize(acpi_info: &AcpiInfo) {
     // The prefered timer is the APIC timer, which is specific to each CPU and has a very nice frequency.
     // The only drawback is that the frequency is specific to the CPU, so we have to synchronize it somehow with another timer.
@@ -14,6 +37,7 @@ pub fn initialize(acpi_info: &AcpiInfo) {
     // 1. Set the APIC timer to count down from 0xffffffff (the highest possible value).
     // 2. (using the HPET) wait for a specific period of time (we'll go with 100ms).
     // 3. Read the APIC timer count and calculate the frequency based on the difference from 0xffffffff.
+    // We measure the TSC over the same period, in case we can use it for the monotonic clock.
 
     // SAFETY: The ACPI tables are required to give us a good HPET.
     // Additionally, this function is only called once, and then before anything else has had a chance to use the HPET.
@@ -23,15 +47,76 @@ pub fn initialize(acpi_info: &AcpiInfo) {
     unsafe { local_apic::set_timer(0xffffffff) };
 
     unsafe { hpet.reset() };
+    let tsc_start = read_cycle_counter();
 
     let end = unsafe { hpet.counter_value() + hpet.frequency() / 10 };
 
     while unsafe { hpet.counter_value() } < end {}
 
     let frequency = 10 * (0xffffffff - unsafe { local_apic::read_timer() });
+    let tsc_frequency = (read_cycle_counter() - tsc_start) * 10;
     local_apic::set_timer_frequency(frequency);
 
     println!("APIC timer frequency: {}Hz", frequency);
 
-    unsafe { local_apic::set_timer(frequency) };
+    // The TSC is much quicker to read than the HPET, but it's only any good as a clock if its rate never changes.
+    if has_invariant_tsc() {
+        CLOCK_START.store(read_cycle_counter(), Ordering::SeqCst);
+        CLOCK_FREQUENCY.store(tsc_frequency, Ordering::SeqCst);
+        println!("Using the TSC as the clock ({}Hz)", tsc_frequency);
+    } else {
+        CLOCK_START.store(unsafe { hpet.counter_value() }, Ordering::SeqCst);
+        CLOCK_FREQUENCY.store(hpet.frequency(), Ordering::SeqCst);
+        println!("Using the HPET as the clock ({}Hz)", hpet.frequency());
+        unsafe { CLOCK_HPET = Some(hpet) };
+    }
+    // The timer is left stopped until something sets a deadline.
+}
+
+/// How long the other CPUs spend measuring their APIC timers.
+const APPLICATION_PROCESSOR_CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+/// Set up the APIC timer of a CPU which starts after the clock, and measure its frequency against the clock instead of the HPET.
+/// The timer is left stopped, just like on the first CPU.
+pub(in crate::arch) fn initialize_application_processor_timer() -> u64 {
+    unsafe { local_apic::initialize_timer() };
+    let start = monotonic_time();
+    unsafe { local_apic::set_timer(0xffffffff) };
+    while monotonic_time() - start < APPLICATION_PROCESSOR_CALIBRATION_TIME {}
+    let ticks = 0xffffffff - unsafe { local_apic::read_timer() };
+    let elapsed = monotonic_time() - start;
+    unsafe { local_apic::set_timer(0) };
+    (ticks as u128 * 1_000_000_000 / elapsed.as_nanos()) as u64
+}
+
+/// How many ticks of the clock have passed since it was initialized.
+pub fn read_clock() -> u64 {
+    // SAFETY: `CLOCK_HPET` isn't written to after `initialize`.
+    let counter = match unsafe { &*addr_of!(CLOCK_HPET) } {
+        Some(hpet) => unsafe { hpet.counter_value() },
+        None => read_cycle_counter(),
+    };
+    counter.wrapping_sub(CLOCK_START.load(Ordering::Relaxed))
+}
+
+/// In ticks per second, or 0 if the clock hasn't been initialized yet.
+pub fn clock_frequency() -> u64 {
+    CLOCK_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Make the timer interrupt happen when the monotonic clock reaches `deadline`, or never if it's `None`.
+///
+/// This replaces any deadline which was set before.
+pub fn set_deadline(deadline: Option<Duration>) {
+    let ticks = match deadline {
+        // Writing 0 stops the timer.
+        None => 0,
+        Some(deadline) => {
+            let remaining = deadline.saturating_sub(monotonic_time()).as_nanos();
+            let ticks = remaining * local_apic::get_timer_frequency() as u128 / 1_000_000_000;
+            // If it is too far away for the counter, the interrupt comes early and the deadline is set again.
+            ticks.clamp(1, u32::MAX as u128) as u64
+        }
+    };
+    unsafe { local_apic::set_timer(ticks) };
 }
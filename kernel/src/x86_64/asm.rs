@@ -7,6 +7,14 @@ pub unsafe fn write_port8(port: u16, value: u8) {
     asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
 }
 
+/// # Safety
+/// Reading some ports has side effects, just like writing to them.
+pub unsafe fn read_port8(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack));
+    value
+}
+
 pub fn io_wait() {
     unsafe {
         asm!("out dx, al", in("dx") 0x80, in("al") 0u8, options(nomem, nostack));
@@ -36,6 +44,63 @@ pub unsafe fn iret(
         in(reg) stack_segment, in(reg) stack_pointer, in(reg) flags, in(reg) code_segment, in(reg) instruction_pointer, options(nomem, nostack, noreturn));
 }
 
+/// # Safety
+/// Model specific registers can change just about anything about how the CPU behaves.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nomem, nostack));
+}
+
+/// # Safety
+/// Some model specific registers don't exist on every CPU, and reading one which doesn't exist causes a general protection fault.
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    (high as u64) << 32 | low as u64
+}
+
+/// Get the address which caused the most recent page fault.
+pub fn read_cr2() -> usize {
+    let cr2: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack)) };
+    cr2
+}
+
 pub unsafe fn load_task_state_segment(selector: u16) {
     asm!("ltr ax", in("ax") selector, options(nomem, nostack));
 }
+
+/// The operand of LGDT, LIDT, SGDT and SIDT.
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+/// The address and limit (the size minus one) of the current CPU's global descriptor table.
+pub fn read_global_descriptor_table() -> (u64, u16) {
+    let mut pointer = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt [{}]", in(reg) &mut pointer, options(nostack)) };
+    (pointer.base, pointer.limit)
+}
+
+/// Load a global descriptor table, and set the data segment registers to the null segment like the boot code does.
+///
+/// # Safety
+/// The table must stay where it is for as long as it is loaded, and must have the same code segment as the one we are running in.
+/// Loading GS also clears its base, so this has to happen before anything sets it.
+pub unsafe fn load_global_descriptor_table(base: u64, limit: u16) {
+    let pointer = DescriptorTablePointer { limit, base };
+    asm!(
+        "lgdt [{}]",
+        "xor eax, eax",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        in(reg) &pointer,
+        out("eax") _,
+        options(readonly, nostack),
+    );
+}
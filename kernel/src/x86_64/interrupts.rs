@@ -1,15 +1,28 @@
 use bitflags::bitflags;
-use core::arch::{asm, global_asm};
+use core::{
+    arch::{asm, global_asm},
+    fmt,
+};
 
-use crate::{arch::local_apic, lazy_init::lazy_static, print, println};
+use common::syscall::KILLED_EXIT_STATUS;
+
+use crate::{
+    arch::{
+        arch_api::per_cpu::GS_BASE,
+        asm::{read_cr2, read_port8},
+        local_apic,
+        task_state_segment::NMI_STACK_INDEX,
+    },
+    console, irq,
+    lazy_init::lazy_static,
+    println, process, scheduler, syscall, timer_queue, user_memory,
+};
 
 bitflags! {
     struct IdtFlags: u8 {
         const PRESENT = 1 << 7;
         const INTERRUPT_GATE = 0xE;
         const TRAP_GATE = 0xF;
-        const KERNEL_PRIVILEGE = 0 << 5;
-        const USER_PRIVILEGE = 3 << 5;
     }
 }
 
@@ -25,12 +38,13 @@ struct IdtEntry {
 }
 
 impl IdtEntry {
-    const fn new(offset: u64, trap: bool) -> Self {
+    /// `privilege` is the lowest privilege level (where 3 is user mode) which can use the vector with an `int` instruction.
+    const fn new(offset: u64, trap: bool, privilege: u8) -> Self {
         Self {
             offset_low: offset as u16,
             selector: 0x08,
             ist: 0,
-            flags: IdtFlags::from_bits_truncate(
+            flags: IdtFlags::from_bits_retain(
                 IdtFlags::PRESENT.bits()
                     | if trap {
                         IdtFlags::TRAP_GATE
@@ -38,7 +52,7 @@ impl IdtEntry {
                         IdtFlags::INTERRUPT_GATE
                     }
                     .bits()
-                    | IdtFlags::USER_PRIVILEGE.bits(),
+                    | privilege << 5,
             ),
             offset_middle: (offset >> 16) as u16,
             offset_high: (offset >> 32) as u32,
@@ -53,7 +67,17 @@ macro_rules! asm_interrupt_handler {
                 stringify!(.globl $function_name),
                 stringify!(.type $function_name, @function),
                 stringify!($function_name:),
-                "push rax
+                // Exceptions without an error code get a fake one so that every handler sees the same layout.
+                ".if {error_code_length} == 0
+                 push 0
+                 .endif
+                 // Coming from user mode, GS has the user's value (see syscall_instruction.rs).
+                 // Interrupts are disabled on entry (see `IDT`), so nothing else can run before it is swapped.
+                 test qword ptr [rsp + 16], 3
+                 jz 1f
+                 swapgs
+                 1:
+                 push rax
                  push rcx
                  push rdx
                  push rbx
@@ -70,24 +94,10 @@ macro_rules! asm_interrupt_handler {
                  push r15
                  mov rsi, rsp
                  mov rdi, {number}
+                 and rsp, ~0xf
                  call {handler}
-                 pop r15
-                 pop r14
-                 pop r13
-                 pop r12
-                 pop r11
-                 pop r10
-                 pop r9
-                 pop r8
-                    pop rbp
-                 pop rdi
-                 pop rsi
-                    pop rbx
-                 pop rdx
-                 pop rcx
-                 pop rax
-                 sub rsp, {error_code_length}
-                 iretq",
+                 mov rsp, rax
+                 jmp restore_registers_and_iret",
                 number = const $number,
                 handler = sym $handler,
                 error_code_length = const $error_code_length,
@@ -98,10 +108,103 @@ macro_rules! asm_interrupt_handler {
     };
 }
 
+// The handlers return a pointer to the registers which should be restored, which is how the scheduler switches between threads.
+global_asm!(
+    ".globl restore_registers_and_iret
+     restore_registers_and_iret:
+     pop r15
+     pop r14
+     pop r13
+     pop r12
+     pop r11
+     pop r10
+     pop r9
+     pop r8
+     pop rbp
+     pop rdi
+     pop rsi
+     pop rbx
+     pop rdx
+     pop rcx
+     pop rax
+     add rsp, 8
+     test qword ptr [rsp + 8], 3
+     jz 1f
+     swapgs
+     1:
+     iretq"
+);
+
+// NMIs can arrive anywhere, including between a system call instruction and the SWAPGS after it, so the CS they came from doesn't say whether GS has the user's value.
+// Instead, this looks at the GS base itself: the CPU's data is in the top half of the address space, and user code can't put GS there.
+// The handler always returns to the code it interrupted.
+global_asm!(
+    ".globl h2
+     .type h2, @function
+     h2:
+     push 0
+     push rax
+     push rcx
+     push rdx
+     push rbx
+     push rsi
+     push rdi
+     push rbp
+     push r8
+     push r9
+     push r10
+     push r11
+     push r12
+     push r13
+     push r14
+     push r15
+     // RBX and RBP are preserved by the handler, so they remember whether to swap back and where the registers are.
+     mov ecx, {gs_base}
+     rdmsr
+     xor ebx, ebx
+     test edx, edx
+     js 1f
+     swapgs
+     mov ebx, 1
+     1:
+     mov rbp, rsp
+     mov rsi, rsp
+     mov rdi, 2
+     and rsp, ~0xf
+     call {handler}
+     mov rsp, rbp
+     test ebx, ebx
+     jz 2f
+     swapgs
+     2:
+     pop r15
+     pop r14
+     pop r13
+     pop r12
+     pop r11
+     pop r10
+     pop r9
+     pop r8
+     pop rbp
+     pop rdi
+     pop rsi
+     pop rbx
+     pop rdx
+     pop rcx
+     pop rax
+     add rsp, 8
+     iretq",
+    gs_base = const GS_BASE,
+    handler = sym non_maskable_interrupt,
+);
+extern "C" {
+    static h2: u8;
+}
+
 // The first 32 are CPU exceptions:
 asm_interrupt_handler!(h0, 0, divide_by_zero, 0);
 asm_interrupt_handler!(h1, 1, debug, 0);
-asm_interrupt_handler!(h2, 2, non_maskable_interrupt, 0);
+// NMI is vector 2, which has its own entry (see below).
 asm_interrupt_handler!(h3, 3, breakpoint, 0);
 asm_interrupt_handler!(h4, 4, overflow, 0);
 asm_interrupt_handler!(h5, 5, bound_range_exceeded, 0);
@@ -146,33 +249,45 @@ asm_interrupt_handlers! {
 }
 
 #[repr(C)]
-#[derive(Debug)]
-struct SavedRegisters {
+#[derive(Debug, Default)]
+pub struct SavedRegisters {
     // It's important that we get the order right. Remember that we pushed r15 last, so it is the first.
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    r11: u64,
-    r10: u64,
-    r9: u64,
-    r8: u64,
-    rbp: u64,
-    rdi: u64,
-    rsi: u64,
-    rbx: u64,
-    rdx: u64,
-    rcx: u64,
-    rax: u64,
+    pub(in crate::arch) r15: u64,
+    pub(in crate::arch) r14: u64,
+    pub(in crate::arch) r13: u64,
+    pub(in crate::arch) r12: u64,
+    pub(in crate::arch) r11: u64,
+    pub(in crate::arch) r10: u64,
+    pub(in crate::arch) r9: u64,
+    pub(in crate::arch) r8: u64,
+    pub(in crate::arch) rbp: u64,
+    pub(in crate::arch) rdi: u64,
+    pub(in crate::arch) rsi: u64,
+    pub(in crate::arch) rbx: u64,
+    pub(in crate::arch) rdx: u64,
+    pub(in crate::arch) rcx: u64,
+    pub(in crate::arch) rax: u64,
+    pub(in crate::arch) error_code: u64,
+    // The rest is pushed by the CPU.
+    pub(in crate::arch) rip: u64,
+    pub(in crate::arch) cs: u64,
+    pub(in crate::arch) rflags: u64,
+    pub(in crate::arch) rsp: u64,
+    pub(in crate::arch) ss: u64,
 }
 
 macro_rules! unhandled_interrupt {
     ($function_name:ident, $interrupt_name:expr) => {
         #[no_mangle]
-        extern "C" fn $function_name(number: u64, saved_registers: &SavedRegisters) {
+        extern "C" fn $function_name(
+            number: u64,
+            saved_registers: *mut SavedRegisters,
+        ) -> *mut SavedRegisters {
             panic!(
                 "Unhandled interrupt: {} (0x{:x})\n{:x?}",
-                $interrupt_name, number, saved_registers
+                $interrupt_name,
+                number,
+                unsafe { &*saved_registers }
             );
         }
     };
@@ -186,41 +301,194 @@ macro_rules! unhandled_interrupts {
     };
 }
 
-unhandled_interrupts!(divide_by_zero "divide by zero", debug "debug", non_maskable_interrupt "non maskable interrupt", breakpoint "breakpoint", overflow "overflow", bound_range_exceeded "bound range exceeded", device_not_available "device not available", invalid_opcode "invalid opcode", double_fault "double fault", coprocessor_segment_overrun "coprocessor segment overrun", invalid_tss "invalid tss", segment_not_present "segment not present", stack_segment_fault "stack segment fault", general_protection_fault "general protection fault", page_fault "page fault", reserved "reserved exception", x87_floating_point "x87 floating point", alignment_check "alignment check", machine_check "machine check", simd_floating_point "simd floating point", virtualization "virtualization", security_exception "security exception");
+unhandled_interrupts!(debug "debug", double_fault "double fault", coprocessor_segment_overrun "coprocessor segment overrun", invalid_tss "invalid tss", reserved "reserved exception", machine_check "machine check", virtualization "virtualization", security_exception "security exception");
+
+/// Exceptions which are caused by the code which was running, so if it was a user program we can just get rid of it.
+macro_rules! user_fault {
+    ($function_name:ident, $interrupt_name:expr) => {
+        #[no_mangle]
+        extern "C" fn $function_name(
+            number: u64,
+            saved_registers: *mut SavedRegisters,
+        ) -> *mut SavedRegisters {
+            let registers = unsafe { &*saved_registers };
+            if is_user_mode(registers) {
+                kill_user_program(registers, format_args!("{}", $interrupt_name))
+            } else {
+                panic!(
+                    "Unhandled interrupt: {} (0x{:x})\n{:x?}",
+                    $interrupt_name, number, registers
+                );
+            }
+        }
+    };
+}
+
+macro_rules! user_faults {
+    ($($function_name:ident $interrupt_name:expr),* $(,)?) => {
+        $(
+            user_fault!($function_name, $interrupt_name);
+        )*
+    };
+}
+
+user_faults!(divide_by_zero "divide by zero", breakpoint "breakpoint", overflow "overflow", bound_range_exceeded "bound range exceeded", invalid_opcode "invalid opcode", device_not_available "device not available", segment_not_present "segment not present", stack_segment_fault "stack segment fault", general_protection_fault "general protection fault", x87_floating_point "x87 floating point", alignment_check "alignment check", simd_floating_point "simd floating point");
+
+fn is_user_mode(saved_registers: &SavedRegisters) -> bool {
+    saved_registers.cs & 3 != 0
+}
+
+/// Stop the program which caused an exception, switching to the next thread.
+///
+/// Every exception uses an interrupt gate, so this runs with interrupts disabled like the rest of the scheduler.
+fn kill_user_program(
+    saved_registers: &SavedRegisters,
+    reason: fmt::Arguments,
+) -> *mut SavedRegisters {
+    println!(
+        "Killed user program: {} at {:p}",
+        reason, saved_registers.rip as *const ()
+    );
+    process::exit_current_process(KILLED_EXIT_STATUS)
+}
+
+bitflags! {
+    struct PageFaultErrorCode: u64 {
+        const PRESENT = 1 << 0;
+        const WRITE = 1 << 1;
+        const USER = 1 << 2;
+        const RESERVED_WRITE = 1 << 3;
+        const INSTRUCTION_FETCH = 1 << 4;
+    }
+}
+
+#[no_mangle]
+extern "C" fn page_fault(
+    _number: u64,
+    saved_registers: *mut SavedRegisters,
+) -> *mut SavedRegisters {
+    let registers = unsafe { &*saved_registers };
+    let address = read_cr2();
+    let error_code = PageFaultErrorCode::from_bits_truncate(registers.error_code);
+    // This also happens in kernel mode, when a system call uses user memory for the first time.
+    if !error_code.contains(PageFaultErrorCode::PRESENT) && user_memory::handle_page_fault(address)
+    {
+        return saved_registers;
+    }
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "executing"
+    } else if error_code.contains(PageFaultErrorCode::WRITE) {
+        "writing"
+    } else {
+        "reading"
+    };
+    let problem = if error_code.contains(PageFaultErrorCode::RESERVED_WRITE) {
+        "reserved bit set in page table"
+    } else if error_code.contains(PageFaultErrorCode::PRESENT) {
+        "permission denied"
+    } else {
+        "page not present"
+    };
+    if is_user_mode(registers) {
+        kill_user_program(
+            registers,
+            format_args!(
+                "page fault {} {:p} ({})",
+                access, address as *const (), problem
+            ),
+        )
+    } else {
+        panic!(
+            "Page fault {} {:p} ({})\n{:x?}",
+            access, address as *const (), problem, registers
+        );
+    }
+}
+
+/// Port B of the legacy system control ports, which says whether an NMI came from a hardware error on the motherboard.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+
+bitflags! {
+    struct SystemControlPortB: u8 {
+        const IO_CHANNEL_CHECK = 1 << 6;
+        const MEMORY_PARITY_ERROR = 1 << 7;
+    }
+}
+
+extern "C" fn non_maskable_interrupt(
+    _number: u64,
+    saved_registers: *mut SavedRegisters,
+) -> *mut SavedRegisters {
+    let registers = unsafe { &*saved_registers };
+    // SAFETY: Reading the status doesn't change anything.
+    let status =
+        SystemControlPortB::from_bits_truncate(unsafe { read_port8(SYSTEM_CONTROL_PORT_B) });
+    if status.contains(SystemControlPortB::MEMORY_PARITY_ERROR) {
+        panic!("NMI: memory parity error\n{:x?}", registers);
+    }
+    if status.contains(SystemControlPortB::IO_CHANNEL_CHECK) {
+        panic!("NMI: I/O channel check\n{:x?}", registers);
+    }
+    // Anything else came through a LINT pin (see `local_apic::LocalInterruptConfiguration`), or from another CPU.
+    // This CPU could have been printing when the NMI arrived, so the report is skipped if the console is busy.
+    console::try_write_fmt(format_args!(
+        "NMI from an unknown source at {:p}\n",
+        registers.rip as *const ()
+    ));
+    saved_registers
+}
 
 pub const TIMER_INTERRUPT: u8 = 0x20;
 
+pub const SYSCALL_INTERRUPT: u8 = 0x80;
+
 pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xFF;
 
-fn handle_interrupt(number: u64, saved_registers: &SavedRegisters) {
+extern "C" fn handle_interrupt(
+    number: u64,
+    saved_registers: *mut SavedRegisters,
+) -> *mut SavedRegisters {
     if number == SPURIOUS_INTERRUPT_VECTOR as u64 {
-        return;
+        return saved_registers;
     }
-    // Testing code to make sure the timer IRQ is working properly.
     if number == TIMER_INTERRUPT as u64 {
-        print!(".");
-        unsafe { local_apic::set_timer(local_apic::get_timer_frequency()) };
+        timer_queue::handle_timer_interrupt();
+        unsafe { local_apic::end_of_interrupt() };
+        return scheduler::preempt(saved_registers);
+    }
+    if number == SYSCALL_INTERRUPT as u64 {
+        return syscall::handle_syscall(saved_registers);
+    }
+    if irq::handle(number as u32) {
         unsafe { local_apic::end_of_interrupt() };
-        return;
+        return saved_registers;
     }
 
     println!("Interrupt: {}", number);
-    println!("Saved registers: {:?}", saved_registers);
+    println!("Saved registers: {:?}", unsafe { &*saved_registers });
+    saved_registers
 }
 
 macro_rules! idt {
-    ($($function_name:ident $trap:expr),* $(,)?) => {
+    ($($function_name:ident $trap:literal $privilege:literal),* $(,)?) => {
         [
             $(
-                unsafe {IdtEntry::new(&$function_name as *const _ as u64, $trap)},
+                unsafe {IdtEntry::new(&$function_name as *const _ as u64, $trap, $privilege)},
             )*
         ]
     };
 }
 
 lazy_static! {
-    static ref IDT: [IdtEntry; 256] = idt! {
-        h0 true, h1 true, h2 true, h3 true, h4 true, h5 true, h6 true, h7 true, h8 true, h9 true, h10 true, h11 true, h12 true, h13 true, h14 true, h15 true, h16 true, h17 true, h18 true, h19 true, h20 true, h21 true, h22 true, h23 true, h24 true, h25 true, h26 true, h27 true, h28 true, h29 true, h30 true, h31 true, h32 false, h33 false, h34 false, h35 false, h36 false, h37 false, h38 false, h39 false, h40 false, h41 false, h42 false, h43 false, h44 false, h45 false, h46 false, h47 false, h48 false, h49 false, h50 false, h51 false, h52 false, h53 false, h54 false, h55 false, h56 false, h57 false, h58 false, h59 false, h60 false, h61 false, h62 false, h63 false, h64 false, h65 false, h66 false, h67 false, h68 false, h69 false, h70 false, h71 false, h72 false, h73 false, h74 false, h75 false, h76 false, h77 false, h78 false, h79 false, h80 false, h81 false, h82 false, h83 false, h84 false, h85 false, h86 false, h87 false, h88 false, h89 false, h90 false, h91 false, h92 false, h93 false, h94 false, h95 false, h96 false, h97 false, h98 false, h99 false, h100 false, h101 false, h102 false, h103 false, h104 false, h105 false, h106 false, h107 false, h108 false, h109 false, h110 false, h111 false, h112 false, h113 false, h114 false, h115 false, h116 false, h117 false, h118 false, h119 false, h120 false, h121 false, h122 false, h123 false, h124 false, h125 false, h126 false, h127 false, h128 false, h129 false, h130 false, h131 false, h132 false, h133 false, h134 false, h135 false, h136 false, h137 false, h138 false, h139 false, h140 false, h141 false, h142 false, h143 false, h144 false, h145 false, h146 false, h147 false, h148 false, h149 false, h150 false, h151 false, h152 false, h153 false, h154 false, h155 false, h156 false, h157 false, h158 false, h159 false, h160 false, h161 false, h162 false, h163 false, h164 false, h165 false, h166 false, h167 false, h168 false, h169 false, h170 false, h171 false, h172 false, h173 false, h174 false, h175 false, h176 false, h177 false, h178 false, h179 false, h180 false, h181 false, h182 false, h183 false, h184 false, h185 false, h186 false, h187 false, h188 false, h189 false, h190 false, h191 false, h192 false, h193 false, h194 false, h195 false, h196 false, h197 false, h198 false, h199 false, h200 false, h201 false, h202 false, h203 false, h204 false, h205 false, h206 false, h207 false, h208 false, h209 false, h210 false, h211 false, h212 false, h213 false, h214 false, h215 false, h216 false, h217 false, h218 false, h219 false, h220 false, h221 false, h222 false, h223 false, h224 false, h225 false, h226 false, h227 false, h228 false, h229 false, h230 false, h231 false, h232 false, h233 false, h234 false, h235 false, h236 false, h237 false, h238 false, h239 false, h240 false, h241 false, h242 false, h243 false, h244 false, h245 false, h246 false, h247 false, h248 false, h249 false, h250 false, h251 false, h252 false, h253 false, h254 false, h255 false,
+    static ref IDT: [IdtEntry; 256] = {
+        // Every vector uses an interrupt gate, so that nothing can interrupt a handler before it has swapped GS, or while it is using the scheduler.
+        // User programs can only use `int` for system calls, `int3` and `into`. Anything else would let them fake an exception (without the error code the handler expects) or a device interrupt.
+        let mut idt = idt! {
+            h0 false 0, h1 false 0, h2 false 0, h3 false 3, h4 false 3, h5 false 0, h6 false 0, h7 false 0, h8 false 0, h9 false 0, h10 false 0, h11 false 0, h12 false 0, h13 false 0, h14 false 0, h15 false 0, h16 false 0, h17 false 0, h18 false 0, h19 false 0, h20 false 0, h21 false 0, h22 false 0, h23 false 0, h24 false 0, h25 false 0, h26 false 0, h27 false 0, h28 false 0, h29 false 0, h30 false 0, h31 false 0, h32 false 0, h33 false 0, h34 false 0, h35 false 0, h36 false 0, h37 false 0, h38 false 0, h39 false 0, h40 false 0, h41 false 0, h42 false 0, h43 false 0, h44 false 0, h45 false 0, h46 false 0, h47 false 0, h48 false 0, h49 false 0, h50 false 0, h51 false 0, h52 false 0, h53 false 0, h54 false 0, h55 false 0, h56 false 0, h57 false 0, h58 false 0, h59 false 0, h60 false 0, h61 false 0, h62 false 0, h63 false 0, h64 false 0, h65 false 0, h66 false 0, h67 false 0, h68 false 0, h69 false 0, h70 false 0, h71 false 0, h72 false 0, h73 false 0, h74 false 0, h75 false 0, h76 false 0, h77 false 0, h78 false 0, h79 false 0, h80 false 0, h81 false 0, h82 false 0, h83 false 0, h84 false 0, h85 false 0, h86 false 0, h87 false 0, h88 false 0, h89 false 0, h90 false 0, h91 false 0, h92 false 0, h93 false 0, h94 false 0, h95 false 0, h96 false 0, h97 false 0, h98 false 0, h99 false 0, h100 false 0, h101 false 0, h102 false 0, h103 false 0, h104 false 0, h105 false 0, h106 false 0, h107 false 0, h108 false 0, h109 false 0, h110 false 0, h111 false 0, h112 false 0, h113 false 0, h114 false 0, h115 false 0, h116 false 0, h117 false 0, h118 false 0, h119 false 0, h120 false 0, h121 false 0, h122 false 0, h123 false 0, h124 false 0, h125 false 0, h126 false 0, h127 false 0, h128 false 3, h129 false 0, h130 false 0, h131 false 0, h132 false 0, h133 false 0, h134 false 0, h135 false 0, h136 false 0, h137 false 0, h138 false 0, h139 false 0, h140 false 0, h141 false 0, h142 false 0, h143 false 0, h144 false 0, h145 false 0, h146 false 0, h147 false 0, h148 false 0, h149 false 0, h150 false 0, h151 false 0, h152 false 0, h153 false 0, h154 false 0, h155 false 0, h156 false 0, h157 false 0, h158 false 0, h159 false 0, h160 false 0, h161 false 0, h162 false 0, h163 false 0, h164 false 0, h165 false 0, h166 false 0, h167 false 0, h168 false 0, h169 false 0, h170 false 0, h171 false 0, h172 false 0, h173 false 0, h174 false 0, h175 false 0, h176 false 0, h177 false 0, h178 false 0, h179 false 0, h180 false 0, h181 false 0, h182 false 0, h183 false 0, h184 false 0, h185 false 0, h186 false 0, h187 false 0, h188 false 0, h189 false 0, h190 false 0, h191 false 0, h192 false 0, h193 false 0, h194 false 0, h195 false 0, h196 false 0, h197 false 0, h198 false 0, h199 false 0, h200 false 0, h201 false 0, h202 false 0, h203 false 0, h204 false 0, h205 false 0, h206 false 0, h207 false 0, h208 false 0, h209 false 0, h210 false 0, h211 false 0, h212 false 0, h213 false 0, h214 false 0, h215 false 0, h216 false 0, h217 false 0, h218 false 0, h219 false 0, h220 false 0, h221 false 0, h222 false 0, h223 false 0, h224 false 0, h225 false 0, h226 false 0, h227 false 0, h228 false 0, h229 false 0, h230 false 0, h231 false 0, h232 false 0, h233 false 0, h234 false 0, h235 false 0, h236 false 0, h237 false 0, h238 false 0, h239 false 0, h240 false 0, h241 false 0, h242 false 0, h243 false 0, h244 false 0, h245 false 0, h246 false 0, h247 false 0, h248 false 0, h249 false 0, h250 false 0, h251 false 0, h252 false 0, h253 false 0, h254 false 0, h255 false 0,
+        };
+        // NMIs can interrupt anything, so they have a stack of their own.
+        idt[2].ist = NMI_STACK_INDEX;
+        idt
     };
 }
 
@@ -239,3 +507,22 @@ pub fn init() {
         asm!("lidt [{}]", in(reg) &idtr);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn privilege_test() {
+        for (vector, entry) in IDT.iter().enumerate() {
+            let flags = entry.flags.bits();
+            let expected_privilege = match vector {
+                3 | 4 => 3,
+                vector if vector == SYSCALL_INTERRUPT as usize => 3,
+                _ => 0,
+            };
+            assert_eq!(flags >> 5 & 3, expected_privilege, "vector {}", vector);
+            assert_eq!(flags & 0xf, IdtFlags::INTERRUPT_GATE.bits());
+        }
+    }
+}
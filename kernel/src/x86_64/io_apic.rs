@@ -0,0 +1,297 @@
+//! The I/O APICs, which turn interrupts from devices (identified by their global system interrupt, or GSI) into interrupts on a chosen CPU.
+
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+
+use crate::{
+    acpi::madt::{GeneralAPICInterruptFlags, InterruptSourceOverrideInfo, MadtInfo},
+    irq::InterruptSource,
+    mmio::MmioMemoryHandle,
+    paging::PagePermissions,
+    println,
+    spinlock::{InterruptSafeSpinLock, LockLevel},
+};
+
+const IO_APIC_MMIO_SIZE: usize = 0x20;
+
+// The registers are accessed indirectly, by writing the register number to the select register and then using the window.
+const IO_APIC_REGISTER_SELECT_OFFSET: usize = 0x00;
+const IO_APIC_WINDOW_OFFSET: usize = 0x10;
+
+const IO_APIC_VERSION_REGISTER: u32 = 0x01;
+/// Each redirection entry takes up two registers, with the low half first.
+const IO_APIC_REDIRECTION_TABLE_REGISTER: u32 = 0x10;
+
+/// The ISA IRQs, which are the only ones the interrupt source overrides can apply to.
+const LEGACY_IRQ_COUNT: u8 = 16;
+/// The MADT calls the ISA bus bus 0.
+const ISA_BUS: u8 = 0;
+
+bitflags! {
+    struct RedirectionEntryFlags: u64 {
+        // const DELIVERY_MODE_FIXED = 0b000 << 8; // The default
+        // const DESTINATION_MODE_PHYSICAL = 0 << 11; // Also the default
+        const DELIVERY_PENDING = 1 << 12;
+        const ACTIVE_LOW = 1 << 13;
+        const REMOTE_INTERRUPT_REQUEST = 1 << 14;
+        const LEVEL_TRIGGERED = 1 << 15;
+        const MASKED = 1 << 16;
+    }
+}
+
+const REDIRECTION_ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+impl InterruptSource {
+    /// An ISA IRQ which isn't overridden, which is wired to the GSI with the same number.
+    fn isa(irq: u8) -> Self {
+        Self {
+            global_system_interrupt: irq as u32,
+            active_low: false,
+            level_triggered: false,
+        }
+    }
+
+    /// The flags can also say that the interrupt conforms to the bus, which (since only ISA IRQs are overridden) means active high and edge triggered.
+    fn from_override(entry: &InterruptSourceOverrideInfo) -> Self {
+        Self {
+            global_system_interrupt: entry.global_system_interrupt,
+            active_low: entry.flags.contains(GeneralAPICInterruptFlags::ACTIVE_LOW),
+            level_triggered: entry
+                .flags
+                .contains(GeneralAPICInterruptFlags::LEVEL_TRIGGERED),
+        }
+    }
+}
+
+fn redirection_entry(
+    source: InterruptSource,
+    vector: u8,
+    destination_apic_id: u8,
+    masked: bool,
+) -> u64 {
+    let mut flags = RedirectionEntryFlags::empty();
+    flags.set(RedirectionEntryFlags::ACTIVE_LOW, source.active_low);
+    flags.set(
+        RedirectionEntryFlags::LEVEL_TRIGGERED,
+        source.level_triggered,
+    );
+    flags.set(RedirectionEntryFlags::MASKED, masked);
+    vector as u64
+        | flags.bits()
+        | (destination_apic_id as u64) << REDIRECTION_ENTRY_DESTINATION_SHIFT
+}
+
+struct IoApic {
+    mmio_handle: MmioMemoryHandle,
+    global_system_interrupt_base: u32,
+    redirection_entry_count: u32,
+}
+
+// SAFETY: The I/O APIC can be used from any CPU, as long as only one of them at a time uses the register select and window (which the lock makes sure of).
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    /// # Safety
+    /// The physical address must point to an I/O APIC, which isn't in use by anything else.
+    unsafe fn new(physical_address: usize, global_system_interrupt_base: u32) -> Self {
+        let mut io_apic = Self {
+            mmio_handle: MmioMemoryHandle::new(
+                physical_address,
+                IO_APIC_MMIO_SIZE,
+                PagePermissions::KERNEL_READ_WRITE,
+            ),
+            global_system_interrupt_base,
+            redirection_entry_count: 0,
+        };
+        // Bits 16-23 hold the index of the last redirection entry.
+        io_apic.redirection_entry_count =
+            ((io_apic.read_register(IO_APIC_VERSION_REGISTER) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    unsafe fn read_register(&mut self, register: u32) -> u32 {
+        self.mmio_handle
+            .at_offset::<u32>(IO_APIC_REGISTER_SELECT_OFFSET)
+            .write(register);
+        self.mmio_handle
+            .at_offset::<u32>(IO_APIC_WINDOW_OFFSET)
+            .read()
+    }
+
+    unsafe fn write_register(&mut self, register: u32, value: u32) {
+        self.mmio_handle
+            .at_offset::<u32>(IO_APIC_REGISTER_SELECT_OFFSET)
+            .write(register);
+        self.mmio_handle
+            .at_offset::<u32>(IO_APIC_WINDOW_OFFSET)
+            .write(value);
+    }
+
+    fn handles(&self, global_system_interrupt: u32) -> bool {
+        (self.global_system_interrupt_base
+            ..self.global_system_interrupt_base + self.redirection_entry_count)
+            .contains(&global_system_interrupt)
+    }
+
+    fn redirection_register(&self, global_system_interrupt: u32) -> u32 {
+        IO_APIC_REDIRECTION_TABLE_REGISTER
+            + (global_system_interrupt - self.global_system_interrupt_base) * 2
+    }
+
+    unsafe fn read_redirection_entry(&mut self, global_system_interrupt: u32) -> u64 {
+        let register = self.redirection_register(global_system_interrupt);
+        self.read_register(register) as u64 | (self.read_register(register + 1) as u64) << 32
+    }
+
+    /// The high half (with the destination) is written first, so that the entry is only unmasked once it is complete.
+    unsafe fn write_redirection_entry(&mut self, global_system_interrupt: u32, entry: u64) {
+        let register = self.redirection_register(global_system_interrupt);
+        self.write_register(register + 1, (entry >> 32) as u32);
+        self.write_register(register, entry as u32);
+    }
+}
+
+struct IoApics {
+    io_apics: Vec<IoApic>,
+    /// The ISA IRQs which aren't wired to the GSI with the same number, or which don't use the ISA polarity and trigger mode.
+    legacy_irq_overrides: Vec<(u8, InterruptSource)>,
+}
+
+impl IoApics {
+    fn handling(&mut self, global_system_interrupt: u32) -> &mut IoApic {
+        self.io_apics
+            .iter_mut()
+            .find(|io_apic| io_apic.handles(global_system_interrupt))
+            .unwrap_or_else(|| panic!("No I/O APIC handles GSI {}", global_system_interrupt))
+    }
+
+    fn legacy_irq_source(&self, irq: u8) -> InterruptSource {
+        self.legacy_irq_overrides
+            .iter()
+            .find(|(overridden_irq, _)| *overridden_irq == irq)
+            .map_or(InterruptSource::isa(irq), |(_, source)| *source)
+    }
+}
+
+static IO_APICS: InterruptSafeSpinLock<IoApics> = InterruptSafeSpinLock::new(
+    LockLevel::IoApic,
+    IoApics {
+        io_apics: Vec::new(),
+        legacy_irq_overrides: Vec::new(),
+    },
+);
+
+/// Set up the I/O APICs in the MADT, with all of their interrupts masked.
+///
+/// # Safety
+/// The MADT has to have the right addresses for the I/O APICs, and this can only be called once.
+pub unsafe fn initialize(madt: &MadtInfo) {
+    let mut io_apics: Vec<IoApic> = madt
+        .io_apic_entries
+        .iter()
+        .map(|entry| IoApic::new(entry.address as usize, entry.global_system_interrupt_base))
+        .collect();
+    for io_apic in &mut io_apics {
+        println!(
+            "I/O APIC for GSIs {} to {}",
+            io_apic.global_system_interrupt_base,
+            io_apic.global_system_interrupt_base + io_apic.redirection_entry_count - 1
+        );
+        for global_system_interrupt in io_apic.global_system_interrupt_base
+            ..io_apic.global_system_interrupt_base + io_apic.redirection_entry_count
+        {
+            io_apic.write_redirection_entry(
+                global_system_interrupt,
+                RedirectionEntryFlags::MASKED.bits(),
+            );
+        }
+    }
+    let legacy_irq_overrides = madt
+        .interrupt_source_override_entries
+        .iter()
+        .filter(|entry| entry.bus_source == ISA_BUS && entry.irq_source < LEGACY_IRQ_COUNT)
+        .map(|entry| (entry.irq_source, InterruptSource::from_override(entry)))
+        .collect();
+
+    let mut io_apics_lock = IO_APICS.lock();
+    io_apics_lock.io_apics = io_apics;
+    io_apics_lock.legacy_irq_overrides = legacy_irq_overrides;
+}
+
+/// Where an ISA IRQ (like the keyboard, the RTC or a serial port) comes into the I/O APICs, taking the interrupt source overrides into account.
+pub fn legacy_irq_source(irq: u8) -> InterruptSource {
+    assert!(irq < LEGACY_IRQ_COUNT);
+    IO_APICS.lock().legacy_irq_source(irq)
+}
+
+/// Send the interrupt from `source` to `vector` on the CPU with the given local APIC ID.
+/// It stays masked until `unmask` is called.
+pub fn route(source: InterruptSource, vector: u8, destination_apic_id: u8) {
+    // The first 32 vectors are for exceptions.
+    assert!(vector >= 0x20);
+    let entry = redirection_entry(source, vector, destination_apic_id, true);
+    let mut io_apics = IO_APICS.lock();
+    // SAFETY: The entry is masked, so nothing happens until the caller is ready for it.
+    unsafe {
+        io_apics
+            .handling(source.global_system_interrupt)
+            .write_redirection_entry(source.global_system_interrupt, entry)
+    };
+}
+
+fn update_redirection_entry(global_system_interrupt: u32, update: impl FnOnce(u64) -> u64) {
+    let mut io_apics = IO_APICS.lock();
+    let io_apic = io_apics.handling(global_system_interrupt);
+    // SAFETY: The entry was set up by `route`, and only the parts the caller asked for change.
+    unsafe {
+        let entry = io_apic.read_redirection_entry(global_system_interrupt);
+        io_apic.write_redirection_entry(global_system_interrupt, update(entry));
+    }
+}
+
+pub fn mask(global_system_interrupt: u32) {
+    update_redirection_entry(global_system_interrupt, |entry| {
+        entry | RedirectionEntryFlags::MASKED.bits()
+    });
+}
+
+pub fn unmask(global_system_interrupt: u32) {
+    update_redirection_entry(global_system_interrupt, |entry| {
+        entry & !RedirectionEntryFlags::MASKED.bits()
+    });
+}
+
+/// Send the interrupt to a different CPU from now on.
+pub fn set_destination(global_system_interrupt: u32, destination_apic_id: u8) {
+    update_redirection_entry(global_system_interrupt, |entry| {
+        (entry & !(0xff << REDIRECTION_ENTRY_DESTINATION_SHIFT))
+            | (destination_apic_id as u64) << REDIRECTION_ENTRY_DESTINATION_SHIFT
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redirection_entry_test() {
+        // QEMU overrides IRQ 9 (ACPI) to be level triggered and active high.
+        let source = InterruptSource::from_override(&InterruptSourceOverrideInfo {
+            bus_source: ISA_BUS,
+            irq_source: 9,
+            global_system_interrupt: 9,
+            flags: GeneralAPICInterruptFlags::from_bits_retain(0b1101),
+        });
+        assert!(!source.active_low);
+        assert!(source.level_triggered);
+        assert_eq!(
+            redirection_entry(source, 0x31, 2, true),
+            0x0200_0000_0001_8031
+        );
+
+        let source = InterruptSource::isa(1);
+        assert_eq!(source.global_system_interrupt, 1);
+        assert_eq!(redirection_entry(source, 0x40, 0, false), 0x40);
+    }
+}
@@ -1,12 +1,31 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use bitflags::bitflags;
 
-use crate::{mmio::MmioMemoryHandle, paging::PagePermissions};
+use crate::{
+    acpi::madt::{GeneralAPICInterruptFlags, MadtInfo, ALL_PROCESSORS},
+    mmio::MmioMemoryHandle,
+    paging::PagePermissions,
+    println,
+};
 
-use super::interrupts::{SPURIOUS_INTERRUPT_VECTOR, TIMER_INTERRUPT};
+use super::{
+    asm::{read_msr, write_msr},
+    interrupts::{SPURIOUS_INTERRUPT_VECTOR, TIMER_INTERRUPT},
+};
 
 static mut APIC_HANDLE: Option<MmioMemoryHandle> = None;
+/// Set if the local APICs are used in x2APIC mode, where the registers are MSRs instead of being mapped into memory.
+static X2APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// In x2APIC mode, the register at offset n in the memory mapped window is MSR 0x800 + n / 0x10.
+const X2APIC_MSR_BASE: u32 = 0x800;
 
 const LOCAL_APIC_MEMORY_RANGE_SIZE: usize = 0x1000;
 
@@ -41,30 +60,154 @@ const LOCAL_APIC_TIMER_DIVIDE_CONFIGURATION_OFFSET: usize = 0x3E0;
 /// The physical address must both point to a APIC, and also not be in use by another instance of the APIC driver or be mapped anywhere else.
 /// Additionally, there will be massive confusion if the legacy PIC is not disabled by now, so callers must ensure that it is disabled.
 pub unsafe fn initialize(address: usize) {
-    APIC_HANDLE = Some(MmioMemoryHandle::new(
-        address,
-        LOCAL_APIC_MEMORY_RANGE_SIZE,
-        PagePermissions::KERNEL_READ_WRITE,
-    ));
+    if x2apic_supported() {
+        X2APIC_MODE.store(true, Ordering::Relaxed);
+        println!("Using the local APIC in x2APIC mode");
+    } else {
+        APIC_HANDLE = Some(MmioMemoryHandle::new(
+            address,
+            LOCAL_APIC_MEMORY_RANGE_SIZE,
+            PagePermissions::KERNEL_READ_WRITE,
+        ));
+    }
+
+    enable();
+}
+
+fn x2apic_supported() -> bool {
+    // SAFETY: Every x86_64 CPU has the cpuid instruction.
+    unsafe { __cpuid(1).ecx & (1 << 21) != 0 }
+}
+
+fn x2apic_register(offset: usize) -> u32 {
+    X2APIC_MSR_BASE + (offset / 0x10) as u32
+}
 
+/// Read a register, given its offset in the memory mapped window.
+///
+/// # Safety
+/// The APIC must be initialized properly (see above).
+unsafe fn read_register(offset: usize) -> u32 {
+    if X2APIC_MODE.load(Ordering::Relaxed) {
+        return read_msr(x2apic_register(offset)) as u32;
+    }
     let Some(apic_handle) = APIC_HANDLE.as_mut() else {
         panic!("APIC handle not initialized");
     };
 
-    // To enable the APIC, we have to set the spurious interrupt vector with bit 8 set to 1.
-    apic_handle
-        .at_offset::<u32>(LOCAL_APIC_SPURIOUS_INTERRUPT_VECTOR_OFFSET)
-        .write(SPURIOUS_INTERRUPT_VECTOR as u32 | 0x100);
+    apic_handle.at_offset::<u32>(offset).read()
 }
 
+/// Write a register, given its offset in the memory mapped window.
+///
 /// # Safety
 /// The APIC must be initialized properly (see above).
-pub unsafe fn end_of_interrupt() {
+unsafe fn write_register(offset: usize, value: u32) {
+    if X2APIC_MODE.load(Ordering::Relaxed) {
+        write_msr(x2apic_register(offset), value as u64);
+        return;
+    }
     let Some(apic_handle) = APIC_HANDLE.as_mut() else {
         panic!("APIC handle not initialized");
     };
 
-    apic_handle.at_offset::<u32>(LOCAL_APIC_EOI_OFFSET).write(0);
+    apic_handle.at_offset::<u32>(offset).write(value);
+}
+
+/// Enable the local APIC of the current CPU.
+/// Every CPU sees its own local APIC at the same address, so the other CPUs only need to call this once the first one has called `initialize`.
+///
+/// # Safety
+/// The APIC must be initialized properly (see above).
+pub unsafe fn enable() {
+    if X2APIC_MODE.load(Ordering::Relaxed) {
+        // Every CPU's APIC starts off in xAPIC mode, and it has to be enabled before it can be switched to x2APIC mode.
+        let apic_base = read_msr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+        write_msr(IA32_APIC_BASE, apic_base);
+        write_msr(IA32_APIC_BASE, apic_base | APIC_BASE_X2APIC_ENABLE);
+    }
+
+    // To enable the APIC, we have to set the spurious interrupt vector with bit 8 set to 1.
+    write_register(
+        LOCAL_APIC_SPURIOUS_INTERRUPT_VECTOR_OFFSET,
+        SPURIOUS_INTERRUPT_VECTOR as u32 | 0x100,
+    );
+}
+
+/// The ID of the current CPU's local APIC, which is what the MADT and interrupt commands use to refer to the CPU.
+///
+/// # Safety
+/// The APIC must be initialized properly (see above).
+pub unsafe fn id() -> u32 {
+    let id_register = read_register(LOCAL_APIC_ID_OFFSET);
+    // The x2APIC ID takes up the whole register, but the xAPIC ID is only the top 8 bits.
+    if X2APIC_MODE.load(Ordering::Relaxed) {
+        id_register
+    } else {
+        id_register >> 24
+    }
+}
+
+const INTERRUPT_COMMAND_DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const INTERRUPT_COMMAND_DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const INTERRUPT_COMMAND_DELIVERY_PENDING: u32 = 1 << 12;
+const INTERRUPT_COMMAND_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Send an interrupt command to another CPU, and wait until the APIC has delivered it.
+///
+/// # Safety
+/// The APIC must be initialized properly (see above).
+unsafe fn send_interrupt_command(apic_id: u32, command: u32) {
+    // In x2APIC mode, the whole command is a single MSR with the destination in the high half, and there is nothing to wait for.
+    if X2APIC_MODE.load(Ordering::Relaxed) {
+        write_msr(
+            x2apic_register(LOCAL_APIC_INTERRUPT_COMMAND_OFFSET),
+            (apic_id as u64) << 32 | command as u64,
+        );
+        return;
+    }
+    assert!(apic_id <= 0xff, "xAPIC IDs only have 8 bits");
+
+    // The high half holds the destination, and writing the low half sends the command.
+    write_register(LOCAL_APIC_INTERRUPT_COMMAND_OFFSET + 0x10, apic_id << 24);
+    write_register(LOCAL_APIC_INTERRUPT_COMMAND_OFFSET, command);
+
+    while read_register(LOCAL_APIC_INTERRUPT_COMMAND_OFFSET) & INTERRUPT_COMMAND_DELIVERY_PENDING
+        != 0
+    {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset another CPU, after which it waits for a startup interrupt.
+///
+/// # Safety
+/// The APIC must be initialized properly (see above), and the CPU mustn't be doing anything important.
+pub unsafe fn send_init(apic_id: u32) {
+    send_interrupt_command(
+        apic_id,
+        INTERRUPT_COMMAND_DELIVERY_MODE_INIT | INTERRUPT_COMMAND_LEVEL_ASSERT,
+    );
+}
+
+/// Start a CPU which is waiting after `send_init`, in real mode at the start of the given physical page (which has to be below 1MiB).
+///
+/// # Safety
+/// The APIC must be initialized properly (see above), and there must be code for the CPU to run at the address.
+pub unsafe fn send_startup(apic_id: u32, start_address: usize) {
+    assert!(start_address % 0x1000 == 0 && start_address < 0x10_0000);
+    send_interrupt_command(
+        apic_id,
+        INTERRUPT_COMMAND_DELIVERY_MODE_STARTUP
+            | INTERRUPT_COMMAND_LEVEL_ASSERT
+            | (start_address >> 12) as u32,
+    );
+}
+
+/// # Safety
+/// The APIC must be initialized properly (see above).
+pub unsafe fn end_of_interrupt() {
+    write_register(LOCAL_APIC_EOI_OFFSET, 0);
 }
 
 bitflags! {
@@ -73,6 +216,7 @@ bitflags! {
         const MASKED = 1 << 16;
         const TRIGGER_MODE_LEVEL = 1 << 15;
         const INTERRUPT_ACTIVE = 1 << 14;
+        const ACTIVE_LOW = 1 << 13;
         const INTERRUPT_PENDING = 1 << 12;
         // const MESSAGE_TYPE_FIXED = 0b000 << 8; // The default
         const MESSAGE_TYPE_SMI = 0b010 << 8;
@@ -84,22 +228,57 @@ bitflags! {
 /// # Safety
 /// The APIC must be initialized properly (see above).
 pub unsafe fn initialize_timer() {
-    let Some(apic_handle) = APIC_HANDLE.as_mut() else {
-        panic!("APIC handle not initialized");
-    };
-
     // We set the timer to be one-shot, with an initial count of 0 and a divisor of 64.
-    apic_handle
-        .at_offset::<u32>(LOCAL_APIC_LVT_TIMER_OFFSET)
-        .write(TIMER_INTERRUPT as u32);
+    write_register(LOCAL_APIC_LVT_TIMER_OFFSET, TIMER_INTERRUPT as u32);
+    write_register(LOCAL_APIC_TIMER_INITIAL_COUNT_OFFSET, 0);
+    write_register(LOCAL_APIC_TIMER_DIVIDE_CONFIGURATION_OFFSET, 0b1001);
+}
 
-    apic_handle
-        .at_offset::<u32>(LOCAL_APIC_TIMER_INITIAL_COUNT_OFFSET)
-        .write(0);
+/// What each of a CPU's two LINT pins is connected to, as the values for their LVT registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalInterruptConfiguration {
+    lint: [u32; 2],
+}
 
-    apic_handle
-        .at_offset::<u32>(LOCAL_APIC_TIMER_DIVIDE_CONFIGURATION_OFFSET)
-        .write(0b1001);
+impl LocalInterruptConfiguration {
+    /// The MADT's NMI entries say which pins are NMIs, for every CPU or just for one.
+    /// Any other pin is where the legacy PIC would be connected, which stays masked since the I/O APICs are used instead.
+    pub fn from_madt(madt: &MadtInfo, apic_id: u32) -> Self {
+        let acpi_processor_uid = madt
+            .local_apic_entries
+            .iter()
+            .find(|entry| entry.apic_id == apic_id)
+            .map(|entry| entry.acpi_processor_uid);
+        let mut lint = [(LvtFlags::MESSAGE_TYPE_EXTINT | LvtFlags::MASKED).bits(); 2];
+        for entry in &madt.local_apic_nmi_entries {
+            if entry.acpi_processor_uid != ALL_PROCESSORS
+                && Some(entry.acpi_processor_uid) != acpi_processor_uid
+            {
+                continue;
+            }
+            let Some(lvt_value) = lint.get_mut(entry.local_apic_lint as usize) else {
+                println!("Ignoring NMI entry for LINT{}", entry.local_apic_lint);
+                continue;
+            };
+            // NMIs are always edge triggered, so only the polarity comes from the flags.
+            let mut flags = LvtFlags::MESSAGE_TYPE_NMI;
+            flags.set(
+                LvtFlags::ACTIVE_LOW,
+                entry.flags.contains(GeneralAPICInterruptFlags::ACTIVE_LOW),
+            );
+            *lvt_value = flags.bits();
+        }
+        Self { lint }
+    }
+}
+
+/// Set up the current CPU's LINT pins.
+///
+/// # Safety
+/// The APIC must be initialized properly (see above), and enabled on this CPU.
+pub unsafe fn configure_local_interrupts(configuration: LocalInterruptConfiguration) {
+    write_register(LOCAL_APIC_LVT_LINT0_OFFSET, configuration.lint[0]);
+    write_register(LOCAL_APIC_LVT_LINT1_OFFSET, configuration.lint[1]);
 }
 
 static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
@@ -118,13 +297,7 @@ pub fn get_timer_frequency() -> u64 {
 /// # Safety
 /// The APIC must be initialized properly (see above).
 pub unsafe fn read_timer() -> u64 {
-    let Some(apic_handle) = APIC_HANDLE.as_mut() else {
-        panic!("APIC handle not initialized");
-    };
-
-    apic_handle
-        .at_offset::<u32>(LOCAL_APIC_TIMER_CURRENT_COUNT_OFFSET)
-        .read() as u64
+    read_register(LOCAL_APIC_TIMER_CURRENT_COUNT_OFFSET) as u64
 }
 
 /// Set the timer to fire after the given number of ticks.
@@ -132,11 +305,60 @@ pub unsafe fn read_timer() -> u64 {
 /// # Safety
 /// The APIC must be initialized properly (see above).
 pub unsafe fn set_timer(ticks: u64) {
-    let Some(apic_handle) = APIC_HANDLE.as_mut() else {
-        panic!("APIC handle not initialized");
-    };
+    write_register(LOCAL_APIC_TIMER_INITIAL_COUNT_OFFSET, ticks as u32);
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
 
-    apic_handle
-        .at_offset::<u32>(LOCAL_APIC_TIMER_INITIAL_COUNT_OFFSET)
-        .write(ticks as u32);
+    use crate::acpi::madt::{LocalApicInfo, LocalApicNmiInfo};
+
+    use super::*;
+
+    #[test]
+    fn local_interrupt_configuration_test() {
+        let madt = MadtInfo {
+            local_apic_entries: vec![
+                LocalApicInfo {
+                    apic_id: 0,
+                    acpi_processor_uid: 0,
+                },
+                LocalApicInfo {
+                    apic_id: 1,
+                    acpi_processor_uid: 5,
+                },
+            ],
+            local_apic_nmi_entries: vec![
+                LocalApicNmiInfo {
+                    acpi_processor_uid: ALL_PROCESSORS,
+                    flags: GeneralAPICInterruptFlags::empty(),
+                    local_apic_lint: 1,
+                },
+                LocalApicNmiInfo {
+                    acpi_processor_uid: 5,
+                    flags: GeneralAPICInterruptFlags::ACTIVE_LOW,
+                    local_apic_lint: 0,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let masked_extint = (LvtFlags::MESSAGE_TYPE_EXTINT | LvtFlags::MASKED).bits();
+        assert_eq!(
+            LocalInterruptConfiguration::from_madt(&madt, 0),
+            LocalInterruptConfiguration {
+                lint: [masked_extint, LvtFlags::MESSAGE_TYPE_NMI.bits()],
+            }
+        );
+        assert_eq!(
+            LocalInterruptConfiguration::from_madt(&madt, 1),
+            LocalInterruptConfiguration {
+                lint: [
+                    (LvtFlags::MESSAGE_TYPE_NMI | LvtFlags::ACTIVE_LOW).bits(),
+                    LvtFlags::MESSAGE_TYPE_NMI.bits()
+                ],
+            }
+        );
+    }
 }
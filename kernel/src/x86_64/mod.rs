@@ -2,8 +2,10 @@ pub mod arch_api;
 mod asm;
 mod hpet;
 mod interrupts;
+mod io_apic;
 mod local_apic;
 mod multiboot;
+mod syscall_instruction;
 mod task_state_segment;
 
 mod acpi {
@@ -0,0 +1,122 @@
+use core::arch::global_asm;
+
+use super::{
+    arch_api::thread::{
+        SavedRegisters, KERNEL_CODE_SEGMENT, USER_CODE_SEGMENT, USER_STACK_SEGMENT,
+    },
+    asm::{read_msr, write_msr},
+};
+use crate::{
+    per_cpu::{KERNEL_STACK_TOP_OFFSET, SCRATCH_OFFSET},
+    syscall,
+};
+
+const EFER: u32 = 0xC000_0080;
+const STAR: u32 = 0xC000_0081;
+const LSTAR: u32 = 0xC000_0082;
+const SFMASK: u32 = 0xC000_0084;
+const KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const EFER_SYSTEM_CALL_EXTENSIONS: u64 = 1 << 0;
+
+const INTERRUPT_FLAG: u64 = 1 << 9;
+const TRAP_FLAG: u64 = 1 << 8;
+const DIRECTION_FLAG: u64 = 1 << 10;
+const ALIGNMENT_CHECK_FLAG: u64 = 1 << 18;
+
+// SYSCALL leaves the user's stack pointer alone, so we have to find the kernel stack ourselves.
+// GS points to the CPU's data while we are in the kernel (see per_cpu.rs), and SWAPGS gets us there from user mode.
+// The registers are saved in exactly the same layout as an interrupt, so that everything else (including the scheduler) can treat them the same way.
+global_asm!(
+    ".globl syscall_entry
+     syscall_entry:
+     swapgs
+     mov gs:[{scratch_offset}], rsp
+     mov rsp, gs:[{kernel_stack_top_offset}]
+     push {user_stack_segment}
+     push gs:[{scratch_offset}]
+     push r11
+     push {user_code_segment}
+     push rcx
+     push 0
+     push rax
+     push rcx
+     push rdx
+     push rbx
+     push rsi
+     push rdi
+     push rbp
+     push r8
+     push r9
+     push r10
+     push r11
+     push r12
+     push r13
+     push r14
+     push r15
+     mov rdi, rsp
+     mov rbx, rsp
+     and rsp, ~0xf
+     call {handler}
+     mov rsp, rax
+     // If we are going somewhere other than where we came from (because the scheduler switched threads), rcx and r11 might be important so we have to use IRET.
+     cmp rax, rbx
+     jne restore_registers_and_iret
+     pop r15
+     pop r14
+     pop r13
+     pop r12
+     pop r11
+     pop r10
+     pop r9
+     pop r8
+     pop rbp
+     pop rdi
+     pop rsi
+     pop rbx
+     pop rdx
+     pop rcx
+     pop rax
+     add rsp, 8
+     mov rcx, [rsp]
+     mov r11, [rsp + 16]
+     mov rsp, [rsp + 24]
+     swapgs
+     sysretq",
+    scratch_offset = const SCRATCH_OFFSET,
+    kernel_stack_top_offset = const KERNEL_STACK_TOP_OFFSET,
+    user_stack_segment = const USER_STACK_SEGMENT,
+    user_code_segment = const USER_CODE_SEGMENT,
+    handler = sym handle_syscall_instruction,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+extern "C" fn handle_syscall_instruction(
+    saved_registers: *mut SavedRegisters,
+) -> *mut SavedRegisters {
+    syscall::handle_syscall(saved_registers)
+}
+
+/// Set up the SYSCALL instruction on the current CPU.
+pub fn init() {
+    unsafe {
+        write_msr(EFER, read_msr(EFER) | EFER_SYSTEM_CALL_EXTENSIONS);
+        // SYSCALL loads CS from bits 32-47 (and SS from the next descriptor).
+        // SYSRET loads SS from 8 more than bits 48-63, and CS from 16 more (with the privilege level set to 3).
+        write_msr(
+            STAR,
+            KERNEL_CODE_SEGMENT << 32 | (USER_STACK_SEGMENT - 8) << 48,
+        );
+        write_msr(LSTAR, syscall_entry as usize as u64);
+        // Interrupts stay off until we've switched stacks.
+        write_msr(
+            SFMASK,
+            INTERRUPT_FLAG | TRAP_FLAG | DIRECTION_FLAG | ALIGNMENT_CHECK_FLAG,
+        );
+        // The kernel runs with GS pointing at the CPU's data, and user code gets whatever is in KERNEL_GS_BASE until SWAPGS swaps them back.
+        write_msr(KERNEL_GS_BASE, 0);
+    }
+}
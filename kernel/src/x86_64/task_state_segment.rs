@@ -1,8 +1,16 @@
-use core::{mem::size_of, ptr::addr_of};
+use alloc::boxed::Box;
+use core::{
+    mem::size_of,
+    ptr::{addr_of, addr_of_mut},
+    slice,
+};
 
 use bitflags::bitflags;
 
-use super::asm::load_task_state_segment;
+use super::asm::{
+    load_global_descriptor_table, load_task_state_segment, read_global_descriptor_table,
+};
+use crate::per_cpu::this_cpu;
 
 bitflags! {
     #[repr(C)]
@@ -29,7 +37,7 @@ struct TaskStateSegmentDescriptor {
 const DEFAULT_ADDITIONAL_FLAGS: u8 = 0b00000000;
 
 #[repr(C, packed)]
-struct TaskStateSegment {
+pub(in crate::arch) struct TaskStateSegment {
     _reserved: u32,
     rsp0: u64,
     rsp1: u64,
@@ -47,45 +55,143 @@ struct TaskStateSegment {
     iomap_base: u16,
 }
 
-static mut TASK_STATE_SEGMENT: TaskStateSegment = TaskStateSegment {
-    _reserved: 0,
-    rsp0: 0,
-    rsp1: 0,
-    rsp2: 0,
-    _reserved2: 0,
-    ist1: 0,
-    ist2: 0,
-    ist3: 0,
-    ist4: 0,
-    ist5: 0,
-    ist6: 0,
-    ist7: 0,
-    _reserved3: 0,
-    _reserved4: 0,
-    iomap_base: 0,
-};
+impl TaskStateSegment {
+    const fn new() -> Self {
+        Self {
+            _reserved: 0,
+            rsp0: 0,
+            rsp1: 0,
+            rsp2: 0,
+            _reserved2: 0,
+            ist1: 0,
+            ist2: 0,
+            ist3: 0,
+            ist4: 0,
+            ist5: 0,
+            ist6: 0,
+            ist7: 0,
+            _reserved3: 0,
+            _reserved4: 0,
+            iomap_base: 0,
+        }
+    }
+}
+
+static mut TASK_STATE_SEGMENT: TaskStateSegment = TaskStateSegment::new();
+
+/// The interrupt stack table entry for NMIs, which can arrive anywhere (even while the kernel is switching stacks), so they always get a stack of their own.
+pub const NMI_STACK_INDEX: u8 = 1;
+
+const NMI_STACK_SIZE: usize = 0x4000;
+
+#[repr(C, align(16))]
+struct NmiStack([u8; NMI_STACK_SIZE]);
+
+static mut BOOT_CPU_NMI_STACK: NmiStack = NmiStack([0; NMI_STACK_SIZE]);
+
+fn nmi_stack_top(stack: *const NmiStack) -> u64 {
+    stack as u64 + NMI_STACK_SIZE as u64
+}
 
 extern "C" {
     static mut task_state_segment_descriptor: TaskStateSegmentDescriptor;
 }
 
-pub fn initialize(rsp0_address: u64) {
-    let task_state_segment_address = unsafe { addr_of!(TASK_STATE_SEGMENT) as usize };
+/// The GDT selector of the task state segment descriptor (which takes up two entries).
+const TASK_STATE_SEGMENT_SELECTOR: u16 = 0x28;
+
+fn descriptor(task_state_segment_address: usize) -> TaskStateSegmentDescriptor {
     let limit = size_of::<TaskStateSegment>() - 1;
+    TaskStateSegmentDescriptor {
+        limit_low: limit as u16,
+        offset_low: task_state_segment_address as u16,
+        offset_low_middle: (task_state_segment_address >> 16) as u8,
+        flags: TaskStateSegmentDescriptorFlags::TSS_TYPE | TaskStateSegmentDescriptorFlags::VALID,
+        limit_high_and_additional_flags: ((limit >> 16) as u8) | DEFAULT_ADDITIONAL_FLAGS,
+        offset_high_middle: (task_state_segment_address >> 24) as u8,
+        offset_high: (task_state_segment_address >> 32) as u32,
+        zero: 0,
+    }
+}
+
+pub fn initialize(rsp0_address: u64) {
+    let task_state_segment = unsafe { addr_of_mut!(TASK_STATE_SEGMENT) };
     unsafe {
-        task_state_segment_descriptor = TaskStateSegmentDescriptor {
-            limit_low: limit as u16,
-            offset_low: task_state_segment_address as u16,
-            offset_low_middle: (task_state_segment_address >> 16) as u8,
-            flags: TaskStateSegmentDescriptorFlags::TSS_TYPE
-                | TaskStateSegmentDescriptorFlags::VALID,
-            limit_high_and_additional_flags: ((limit >> 16) as u8) | DEFAULT_ADDITIONAL_FLAGS,
-            offset_high_middle: (task_state_segment_address >> 24) as u8,
-            offset_high: (task_state_segment_address >> 32) as u32,
-            zero: 0,
-        };
+        task_state_segment_descriptor = descriptor(task_state_segment as usize);
         TASK_STATE_SEGMENT.rsp0 = rsp0_address;
+        TASK_STATE_SEGMENT.ist1 = nmi_stack_top(addr_of!(BOOT_CPU_NMI_STACK));
+
+        load_task_state_segment(TASK_STATE_SEGMENT_SELECTOR);
+    }
+    this_cpu().arch.set_task_state_segment(task_state_segment);
+}
+
+/// The descriptor tables of a CPU other than the first one (which uses the ones from the boot code).
+///
+/// Every CPU needs its own task state segment, and so its own GDT to describe it in, since loading a task state segment marks its descriptor as busy.
+pub struct DescriptorTables {
+    global_descriptor_table: Box<[u64]>,
+    nmi_stack: Box<NmiStack>,
+    /// This is written to through the CPU's data (see `set_kernel_stack`), so it is never freed.
+    task_state_segment: *mut TaskStateSegment,
+}
+
+impl DescriptorTables {
+    /// Make a copy of the current CPU's GDT, with a new task state segment in it.
+    pub fn new(rsp0_address: u64) -> Self {
+        let nmi_stack: Box<NmiStack> = unsafe { Box::new_zeroed().assume_init() };
+        let mut task_state_segment = Box::new(TaskStateSegment::new());
+        task_state_segment.rsp0 = rsp0_address;
+        task_state_segment.ist1 = nmi_stack_top(&*nmi_stack);
+        let task_state_segment = Box::into_raw(task_state_segment);
+
+        let (base, limit) = read_global_descriptor_table();
+        // SAFETY: The current GDT is always mapped, and is `limit + 1` bytes long.
+        let mut global_descriptor_table: Box<[u64]> = unsafe {
+            slice::from_raw_parts(base as *const u64, (limit as usize + 1) / size_of::<u64>())
+        }
+        .into();
+        let descriptor_index = TASK_STATE_SEGMENT_SELECTOR as usize / size_of::<u64>();
+        assert!(
+            descriptor_index + size_of::<TaskStateSegmentDescriptor>() / size_of::<u64>()
+                <= global_descriptor_table.len()
+        );
+        // SAFETY: The descriptor fits in the table (checked above), and the pointer doesn't need to be aligned since the descriptor is packed.
+        unsafe {
+            (global_descriptor_table.as_mut_ptr().add(descriptor_index)
+                as *mut TaskStateSegmentDescriptor)
+                .write(descriptor(task_state_segment as usize));
+        }
 
-        load_task_state_segment(0x28);
+        Self {
+            global_descriptor_table,
+            nmi_stack,
+            task_state_segment,
+        }
+    }
+
+    /// Load the tables on the current CPU.
+    ///
+    /// # Safety
+    /// The tables must not be loaded on any other CPU, and must not be dropped while they are in use.
+    /// Like `load_global_descriptor_table`, this clears the GS base.
+    pub unsafe fn load(&self) {
+        load_global_descriptor_table(
+            self.global_descriptor_table.as_ptr() as u64,
+            (self.global_descriptor_table.len() * size_of::<u64>() - 1) as u16,
+        );
+        load_task_state_segment(TASK_STATE_SEGMENT_SELECTOR);
+    }
+
+    pub(in crate::arch) fn task_state_segment(&self) -> *mut TaskStateSegment {
+        self.task_state_segment
+    }
+}
+
+/// Set the stack which the current CPU switches to when an interrupt arrives in user mode.
+pub fn set_kernel_stack(rsp0_address: u64) {
+    // SAFETY: Interrupts are disabled whenever we switch between threads, and each CPU has its own task state segment, so nothing else can be using it.
+    unsafe {
+        (*this_cpu().arch.task_state_segment()).rsp0 = rsp0_address;
     }
 }
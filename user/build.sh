@@ -18,3 +18,4 @@ copy_artifact() {
 }
 
 copy_artifact services startup
+copy_artifact tests spawn-test
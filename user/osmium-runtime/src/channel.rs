@@ -0,0 +1,132 @@
+//! Sending messages (and handles) between programs.
+
+use core::time::Duration;
+
+use crate::{
+    handle,
+    syscall::{syscall, SyscallError, SyscallNumber},
+    time::deadline_after,
+};
+
+use common::syscall::RECEIVE_NON_BLOCKING;
+pub use common::syscall::{MAX_MESSAGE_HANDLES, MAX_MESSAGE_SIZE, MAX_QUEUED_MESSAGES};
+
+/// One end of a channel.
+#[derive(Debug)]
+pub struct Channel {
+    handle: u64,
+}
+
+impl Channel {
+    /// Create a pair of connected endpoints. Messages sent on one are received from the other.
+    pub fn create() -> Result<(Channel, Channel), SyscallError> {
+        let mut handles = [0u64; 2];
+        unsafe {
+            syscall(
+                SyscallNumber::CreateChannel,
+                [handles.as_mut_ptr() as u64, 0, 0, 0, 0, 0],
+            )?;
+        }
+        Ok((
+            Channel { handle: handles[0] },
+            Channel { handle: handles[1] },
+        ))
+    }
+
+    /// Take ownership of a channel handle (for example one which was received in a message). It is closed when this is dropped.
+    ///
+    /// # Safety
+    /// The handle must refer to a channel, and nothing else may be using it.
+    pub unsafe fn from_handle(handle: u64) -> Self {
+        Self { handle }
+    }
+
+    pub fn handle(&self) -> u64 {
+        self.handle
+    }
+
+    /// Give up ownership of the handle without closing it (for example to send it to another process).
+    pub fn into_handle(self) -> u64 {
+        let handle = self.handle;
+        core::mem::forget(self);
+        handle
+    }
+
+    /// Send a message to the other end, moving `handles` out of this process.
+    pub fn send(&self, data: &[u8], handles: &[u64]) -> Result<(), SyscallError> {
+        unsafe {
+            syscall(
+                SyscallNumber::Send,
+                [
+                    self.handle,
+                    data.as_ptr() as u64,
+                    data.len() as u64,
+                    handles.as_ptr() as u64,
+                    handles.len() as u64,
+                    0,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Wait for a message, returning how many bytes and handles were written to the buffers.
+    pub fn receive(
+        &self,
+        data: &mut [u8],
+        handles: &mut [u64],
+    ) -> Result<(usize, usize), SyscallError> {
+        self.receive_with_flags(data, handles, 0, None)
+    }
+
+    /// Like `receive`, but fails with `TimedOut` if there isn't a message before the timeout passes.
+    pub fn receive_with_timeout(
+        &self,
+        data: &mut [u8],
+        handles: &mut [u64],
+        timeout: Duration,
+    ) -> Result<(usize, usize), SyscallError> {
+        self.receive_with_flags(data, handles, 0, Some(timeout))
+    }
+
+    /// Like `receive`, but fails with `WouldBlock` if there isn't a message yet.
+    pub fn try_receive(
+        &self,
+        data: &mut [u8],
+        handles: &mut [u64],
+    ) -> Result<(usize, usize), SyscallError> {
+        self.receive_with_flags(data, handles, RECEIVE_NON_BLOCKING, None)
+    }
+
+    fn receive_with_flags(
+        &self,
+        data: &mut [u8],
+        handles: &mut [u64],
+        flags: u64,
+        timeout: Option<Duration>,
+    ) -> Result<(usize, usize), SyscallError> {
+        // Anything bigger than this couldn't be filled anyway.
+        let capacities = data.len().min(u32::MAX as usize) as u64
+            | (handles.len().min(u32::MAX as usize) as u64) << 32;
+        let lengths = unsafe {
+            syscall(
+                SyscallNumber::Receive,
+                [
+                    self.handle,
+                    data.as_mut_ptr() as u64,
+                    handles.as_mut_ptr() as u64,
+                    capacities,
+                    flags,
+                    deadline_after(timeout),
+                ],
+            )?
+        };
+        Ok(((lengths & 0xffff_ffff) as usize, (lengths >> 32) as usize))
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let _ = handle::close(self.handle);
+    }
+}
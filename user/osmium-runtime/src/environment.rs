@@ -0,0 +1,74 @@
+//! The arguments, environment variables and auxiliary vector which the kernel put on the initial stack.
+
+use core::{ffi::CStr, ptr::null};
+
+pub use common::initial_stack::AuxiliaryVectorType;
+
+// SAFETY: These are only written by `initialize`, before `main` is called.
+static mut ARGUMENT_COUNT: usize = 0;
+static mut ARGUMENTS: *const *const u8 = null();
+static mut ENVIRONMENT: *const *const u8 = null();
+static mut AUXILIARY_VECTOR: *const u64 = null();
+
+/// Find everything on the initial stack.
+///
+/// # Safety
+/// `stack` must be the stack pointer which the program started with, and nothing may have overwritten it.
+pub(crate) unsafe fn initialize(stack: *const u64) {
+    ARGUMENT_COUNT = *stack as usize;
+    ARGUMENTS = stack.add(1) as *const *const u8;
+    // Skip the arguments and the null pointer after them.
+    ENVIRONMENT = ARGUMENTS.add(ARGUMENT_COUNT + 1);
+    let mut environment_end = ENVIRONMENT;
+    while !(*environment_end).is_null() {
+        environment_end = environment_end.add(1);
+    }
+    AUXILIARY_VECTOR = environment_end.add(1) as *const u64;
+}
+
+/// # Safety
+/// `pointer` must point to a null-terminated string which lives forever.
+unsafe fn string_from_pointer(pointer: *const u8) -> &'static str {
+    CStr::from_ptr(pointer as *const _)
+        .to_str()
+        .expect("The kernel gave us a string which isn't UTF-8")
+}
+
+/// The arguments which the program was started with (the first of which is usually the path to the program).
+pub fn arguments() -> impl Iterator<Item = &'static str> {
+    // SAFETY: The kernel put `ARGUMENT_COUNT` valid strings here.
+    (0..unsafe { ARGUMENT_COUNT })
+        .map(|index| unsafe { string_from_pointer(*ARGUMENTS.add(index)) })
+}
+
+/// The environment variables which the program was started with, in the form `NAME=value`.
+pub fn environment() -> impl Iterator<Item = &'static str> {
+    let mut next = unsafe { ENVIRONMENT };
+    core::iter::from_fn(move || unsafe {
+        if next.is_null() || (*next).is_null() {
+            return None;
+        }
+        let variable = string_from_pointer(*next);
+        next = next.add(1);
+        Some(variable)
+    })
+}
+
+/// Look up an entry in the auxiliary vector.
+pub fn auxiliary_value(entry_type: AuxiliaryVectorType) -> Option<u64> {
+    let mut entry = unsafe { AUXILIARY_VECTOR };
+    if entry.is_null() {
+        return None;
+    }
+    loop {
+        // SAFETY: The kernel always ends the auxiliary vector with a null entry.
+        let (current_type, value) = unsafe { (*entry, *entry.add(1)) };
+        if current_type == entry_type as u64 {
+            return Some(value);
+        }
+        if current_type == AuxiliaryVectorType::Null as u64 {
+            return None;
+        }
+        entry = unsafe { entry.add(2) };
+    }
+}
@@ -0,0 +1,45 @@
+//! Futexes, for blocking until another thread (or process, with shared memory) changes a value.
+
+use core::{sync::atomic::AtomicU32, time::Duration};
+
+use crate::{
+    syscall::{syscall, SyscallError, SyscallNumber},
+    time::duration_to_nanoseconds,
+};
+
+/// Block until `wake` is called on `futex`, as long as it still contains `expected_value`.
+///
+/// Fails with `WouldBlock` if the value is different, or `TimedOut` if the timeout passes first.
+/// This can return early, so the value should be checked again afterwards.
+pub fn wait(
+    futex: &AtomicU32,
+    expected_value: u32,
+    timeout: Option<Duration>,
+) -> Result<(), SyscallError> {
+    let timeout = duration_to_nanoseconds(timeout);
+    unsafe {
+        syscall(
+            SyscallNumber::FutexWait,
+            [
+                futex.as_ptr() as u64,
+                expected_value as u64,
+                timeout,
+                0,
+                0,
+                0,
+            ],
+        )?
+    };
+    Ok(())
+}
+
+/// Wake up to `count` threads which are waiting on `futex`, returning how many were woken.
+pub fn wake(futex: &AtomicU32, count: usize) -> usize {
+    unsafe {
+        syscall(
+            SyscallNumber::FutexWake,
+            [futex.as_ptr() as u64, count as u64, 0, 0, 0, 0],
+        )
+        .unwrap_or(0) as usize
+    }
+}
@@ -0,0 +1,16 @@
+//! Handles to kernel objects, and the rights which they carry.
+
+use crate::syscall::{syscall, SyscallError, SyscallNumber};
+
+pub use common::syscall::{ALL_RIGHTS, RIGHT_DUPLICATE, RIGHT_READ, RIGHT_TRANSFER, RIGHT_WRITE};
+
+/// Make a new handle to the same object as `handle`, with the given rights (which it must already have).
+pub fn duplicate(handle: u64, rights: u64) -> Result<u64, SyscallError> {
+    unsafe { syscall(SyscallNumber::Duplicate, [handle, rights, 0, 0, 0, 0]) }
+}
+
+/// Remove a handle from this process.
+pub fn close(handle: u64) -> Result<(), SyscallError> {
+    unsafe { syscall(SyscallNumber::Close, [handle, 0, 0, 0, 0, 0])? };
+    Ok(())
+}
@@ -1,45 +1,48 @@
 #![no_std]
-#![feature(naked_functions, asm_const)]
+#![feature(naked_functions)]
 
 use core::arch::asm;
 
+pub mod channel;
+pub mod environment;
+pub mod futex;
+pub mod handle;
+pub mod memory;
+pub mod process;
+pub mod syscall;
+pub mod time;
+
 #[cfg_attr(not(test), panic_handler)]
 pub fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
-// The loader doesn't set up a stack for us, but it is simple enough to do that for ourselves:
-const STACK_SIZE: usize = 65536;
-static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
 extern "C" {
     fn main();
 }
 
+/// Called by `_start` with the stack pointer which the kernel gave us (see [`common::initial_stack`]).
+extern "C" fn start(stack: *const u64) -> ! {
+    unsafe {
+        environment::initialize(stack);
+        main();
+    }
+    process::exit(0);
+}
+
 #[naked]
 #[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn _start() -> ! {
     unsafe {
-        // Set up the stack pointer
+        // The kernel has already set up the stack, so all we have to do is tell `start` where it is.
         #[cfg(target_arch = "aarch64")]
-        asm!(
-            "adr x0, {} + {}",
-            "mov sp, x0",
-            "bl {}",
-            "b .",
-            sym STACK,
-            const STACK_SIZE,
-            sym main,
-            options(noreturn)
-        );
+        asm!("mov x0, sp", "bl {}", "b .", sym start, options(noreturn));
         #[cfg(target_arch = "x86_64")]
         asm!(
-            "lea {} + {}(%rip), %rsp",
+            "mov %rsp, %rdi",
             "call {}",
             "jmp .",
-            sym STACK,
-            const STACK_SIZE,
-            sym main,
+            sym start,
             options(noreturn, att_syntax)
         );
     }
@@ -0,0 +1,79 @@
+//! Memory objects, which can be mapped into more than one process to share memory.
+
+use crate::{
+    handle,
+    syscall::{syscall, SyscallError, SyscallNumber},
+};
+
+pub use common::syscall::{
+    MAP_EXECUTABLE, MAP_WRITABLE, MAX_MEMORY_OBJECT_SIZE, MEMORY_OBJECT_ALIGNMENT,
+};
+
+#[derive(Debug)]
+pub struct MemoryObject {
+    handle: u64,
+}
+
+impl MemoryObject {
+    /// Create a (zeroed) memory object. The size is rounded up to a multiple of `MEMORY_OBJECT_ALIGNMENT`.
+    pub fn create(size: usize) -> Result<Self, SyscallError> {
+        let handle = unsafe {
+            syscall(
+                SyscallNumber::CreateMemoryObject,
+                [size as u64, 0, 0, 0, 0, 0],
+            )?
+        };
+        Ok(Self { handle })
+    }
+
+    /// Take ownership of a memory object handle (for example one which was received in a message). It is closed when this is dropped.
+    ///
+    /// # Safety
+    /// The handle must refer to a memory object, and nothing else may be using it.
+    pub unsafe fn from_handle(handle: u64) -> Self {
+        Self { handle }
+    }
+
+    pub fn handle(&self) -> u64 {
+        self.handle
+    }
+
+    /// Give up ownership of the handle without closing it (for example to send it to another process).
+    pub fn into_handle(self) -> u64 {
+        let handle = self.handle;
+        core::mem::forget(self);
+        handle
+    }
+
+    /// Map the whole object at `address`, which must be a multiple of `MEMORY_OBJECT_ALIGNMENT` and not used for anything else.
+    ///
+    /// `flags` is a combination of `MAP_WRITABLE` and `MAP_EXECUTABLE`.
+    /// The mapping stays even if this is dropped, until `unmap` is called.
+    pub fn map(&self, address: usize, flags: u64) -> Result<*mut u8, SyscallError> {
+        unsafe {
+            syscall(
+                SyscallNumber::MapMemoryObject,
+                [self.handle, address as u64, flags, 0, 0, 0],
+            )?;
+        }
+        Ok(address as *mut u8)
+    }
+}
+
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        let _ = handle::close(self.handle);
+    }
+}
+
+/// Unmap the memory object which was mapped at `address`.
+///
+/// # Safety
+/// Nothing may use the memory afterwards.
+pub unsafe fn unmap(address: usize) -> Result<(), SyscallError> {
+    syscall(
+        SyscallNumber::UnmapMemoryObject,
+        [address as u64, 0, 0, 0, 0, 0],
+    )?;
+    Ok(())
+}
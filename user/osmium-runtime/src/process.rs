@@ -0,0 +1,88 @@
+//! Starting, stopping and waiting for processes.
+
+use core::time::Duration;
+
+use crate::{
+    handle,
+    syscall::{syscall, SyscallError, SyscallNumber},
+    time::deadline_after,
+};
+
+pub use common::syscall::KILLED_EXIT_STATUS;
+
+/// A process which was started by this one.
+#[derive(Debug)]
+pub struct Process {
+    handle: u64,
+}
+
+impl Process {
+    /// Block until the process exits, returning its exit status.
+    pub fn wait(&self) -> Result<u64, SyscallError> {
+        self.wait_with_optional_timeout(None)
+    }
+
+    /// Like `wait`, but fails with `TimedOut` if the process is still running after the timeout.
+    pub fn wait_with_timeout(&self, timeout: Duration) -> Result<u64, SyscallError> {
+        self.wait_with_optional_timeout(Some(timeout))
+    }
+
+    fn wait_with_optional_timeout(&self, timeout: Option<Duration>) -> Result<u64, SyscallError> {
+        unsafe {
+            syscall(
+                SyscallNumber::Wait,
+                [self.handle, deadline_after(timeout), 0, 0, 0, 0],
+            )
+        }
+    }
+
+    pub fn handle(&self) -> u64 {
+        self.handle
+    }
+
+    /// Give up ownership of the handle without closing it (for example to send it to another process).
+    pub fn into_handle(self) -> u64 {
+        let handle = self.handle;
+        core::mem::forget(self);
+        handle
+    }
+}
+
+/// Start the program at `path` in the initial ramdisk.
+pub fn spawn(path: &str) -> Result<Process, SyscallError> {
+    spawn_with_handles(path, &[])
+}
+
+/// Start the program at `path` in the initial ramdisk, moving `handles` to it.
+///
+/// The new process gets them as handles 1, 2, 3 and so on.
+pub fn spawn_with_handles(path: &str, handles: &[u64]) -> Result<Process, SyscallError> {
+    let handle = unsafe {
+        syscall(
+            SyscallNumber::Spawn,
+            [
+                path.as_ptr() as u64,
+                path.len() as u64,
+                handles.as_ptr() as u64,
+                handles.len() as u64,
+                0,
+                0,
+            ],
+        )?
+    };
+    Ok(Process { handle })
+}
+
+/// Stop the current process.
+pub fn exit(exit_status: u64) -> ! {
+    unsafe {
+        let _ = syscall(SyscallNumber::Exit, [exit_status, 0, 0, 0, 0, 0]);
+    }
+    unreachable!("The exit system call returned");
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = handle::close(self.handle);
+    }
+}
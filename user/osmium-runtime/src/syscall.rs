@@ -0,0 +1,49 @@
+use core::arch::asm;
+
+use common::syscall::decode_result;
+pub use common::syscall::{SyscallError, SyscallNumber, SyscallResult};
+
+/// Make a system call with the given number and arguments.
+///
+/// # Safety
+/// Some system calls take pointers, which must be valid for whatever the kernel will do with them.
+pub unsafe fn syscall(number: SyscallNumber, arguments: [u64; 6]) -> SyscallResult {
+    let value: u64;
+    let error_code: u64;
+    #[cfg(target_arch = "x86_64")]
+    asm!(
+        "syscall",
+        inlateout("rax") number as u64 => value,
+        in("rdi") arguments[0],
+        in("rsi") arguments[1],
+        inlateout("rdx") arguments[2] => error_code,
+        in("r10") arguments[3],
+        in("r8") arguments[4],
+        in("r9") arguments[5],
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    #[cfg(target_arch = "aarch64")]
+    asm!(
+        "svc 0",
+        in("x8") number as u64,
+        inlateout("x0") arguments[0] => value,
+        inlateout("x1") arguments[1] => error_code,
+        in("x2") arguments[2],
+        in("x3") arguments[3],
+        in("x4") arguments[4],
+        in("x5") arguments[5],
+        options(nostack)
+    );
+    decode_result(value, error_code)
+}
+
+pub fn debug_print(string: &str) -> SyscallResult {
+    unsafe {
+        syscall(
+            SyscallNumber::DebugPrint,
+            [string.as_ptr() as u64, string.len() as u64, 0, 0, 0, 0],
+        )
+    }
+}
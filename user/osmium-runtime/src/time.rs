@@ -0,0 +1,61 @@
+//! Reading the system's clocks, and sleeping.
+
+use core::time::Duration;
+
+use common::syscall::{CLOCK_MONOTONIC, CLOCK_REALTIME, NO_TIMEOUT};
+
+use crate::syscall::{syscall, SyscallError, SyscallNumber};
+
+/// The time since the system started, which never goes backwards.
+pub fn monotonic_time() -> Duration {
+    let nanoseconds = unsafe {
+        syscall(
+            SyscallNumber::ClockGetTime,
+            [CLOCK_MONOTONIC, 0, 0, 0, 0, 0],
+        )
+    }
+    .expect("The monotonic clock is always available");
+    Duration::from_nanos(nanoseconds)
+}
+
+/// The real (UTC) time since the Unix epoch.
+///
+/// Fails with `Unavailable` if there isn't a real time clock.
+pub fn wall_clock_time() -> Result<Duration, SyscallError> {
+    let nanoseconds =
+        unsafe { syscall(SyscallNumber::ClockGetTime, [CLOCK_REALTIME, 0, 0, 0, 0, 0])? };
+    Ok(Duration::from_nanos(nanoseconds))
+}
+
+/// Convert a length of time to nanoseconds for the kernel, where `None` means forever.
+pub(crate) fn duration_to_nanoseconds(duration: Option<Duration>) -> u64 {
+    // Anything which doesn't fit is so long that it may as well be forever.
+    duration.map_or(NO_TIMEOUT, |duration| {
+        duration.as_nanos().try_into().unwrap_or(NO_TIMEOUT)
+    })
+}
+
+/// The deadline (in nanoseconds on the monotonic clock) which is `timeout` from now.
+pub(crate) fn deadline_after(timeout: Option<Duration>) -> u64 {
+    duration_to_nanoseconds(timeout.and_then(|timeout| monotonic_time().checked_add(timeout)))
+}
+
+/// Block the current thread for (at least) `duration`.
+pub fn sleep(duration: Duration) {
+    unsafe {
+        let _ = syscall(
+            SyscallNumber::SleepFor,
+            [duration_to_nanoseconds(Some(duration)), 0, 0, 0, 0, 0],
+        );
+    }
+}
+
+/// Block the current thread until `monotonic_time()` reaches `deadline`.
+pub fn sleep_until(deadline: Duration) {
+    unsafe {
+        let _ = syscall(
+            SyscallNumber::SleepUntil,
+            [duration_to_nanoseconds(Some(deadline)), 0, 0, 0, 0, 0],
+        );
+    }
+}
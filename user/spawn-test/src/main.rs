@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+
+#[allow(unused_imports)]
+use osmium_runtime::panic as _;
+use osmium_runtime::{environment::arguments, process::exit};
+
+/// Where `build.sh` puts this program in the initial ramdisk.
+const PATH: &str = "tests/spawn-test";
+
+/// Started by the startup program, which checks the exit status.
+///
+/// The kernel gives a program its path as its only argument, so this fails if the path didn't make it across.
+#[no_mangle]
+extern "C" fn main() {
+    let mut arguments = arguments();
+    if arguments.next() != Some(PATH) || arguments.next().is_some() {
+        exit(1);
+    }
+}
@@ -1,18 +1,27 @@
 #![no_std]
 #![no_main]
 
+use core::time::Duration;
+
 #[allow(unused_imports)]
 use osmium_runtime::panic as _;
+use osmium_runtime::{environment::arguments, process::spawn, syscall::debug_print, time::sleep};
 
 #[no_mangle]
 extern "C" fn main() {
-    #[cfg(target_arch = "aarch64")]
-    unsafe {
-        core::arch::asm!("svc 0")
-    };
-    #[cfg(target_arch = "x86_64")]
-    unsafe {
-        core::arch::asm!("int 0x80")
+    debug_print("Hello from user mode!\n").unwrap();
+    for argument in arguments() {
+        debug_print("Argument: ").unwrap();
+        debug_print(argument).unwrap();
+        debug_print("\n").unwrap();
+    }
+    sleep(Duration::from_millis(100));
+    debug_print("Woke up after sleeping for 100ms\n").unwrap();
+
+    // The child checks that it was given its path, which the kernel has to copy out of this process.
+    let message = match spawn("tests/spawn-test").and_then(|child| child.wait()) {
+        Ok(0) => "Spawned a program by path\n",
+        _ => "Spawning a program by path failed\n",
     };
-    loop {}
+    debug_print(message).unwrap();
 }